@@ -0,0 +1,109 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::future::join_all;
+use spectacles_proxy::ratelimiter::{local::LocalRatelimiter, RatelimitInfo, Ratelimiter};
+use tokio::{runtime::Runtime, spawn};
+
+const CONCURRENCY: usize = 64;
+
+fn bench_ratelimiter<R>(c: &mut Criterion, name: &str, rt: &Runtime, ratelimiter: R)
+where
+	R: Ratelimiter + Clone + Send + Sync + 'static,
+{
+	let mut group = c.benchmark_group(name);
+
+	group.bench_function("uncontended_claim", |b| {
+		b.to_async(rt).iter(|| async {
+			ratelimiter.claim("uncontended".into()).await.unwrap();
+			ratelimiter
+				.release("uncontended".into(), RatelimitInfo::default())
+				.await
+				.unwrap();
+		})
+	});
+
+	group.bench_function("contended_claim", |b| {
+		b.to_async(rt).iter(|| {
+			join_all((0..CONCURRENCY).map(|_| {
+				let ratelimiter = ratelimiter.clone();
+				spawn(async move {
+					ratelimiter.claim("contended".into()).await.unwrap();
+					ratelimiter
+						.release("contended".into(), RatelimitInfo::default())
+						.await
+						.unwrap();
+				})
+			}))
+		})
+	});
+
+	group.bench_function("many_buckets", |b| {
+		b.to_async(rt).iter(|| {
+			join_all((0..CONCURRENCY).map(|i| {
+				let ratelimiter = ratelimiter.clone();
+				let bucket = format!("many/{}", i);
+				spawn(async move {
+					ratelimiter.claim(bucket.clone()).await.unwrap();
+					ratelimiter
+						.release(bucket, RatelimitInfo::default())
+						.await
+						.unwrap();
+				})
+			}))
+		})
+	});
+
+	group.bench_function("release_with_reset", |b| {
+		b.to_async(rt).iter(|| async {
+			ratelimiter.claim("reset".into()).await.unwrap();
+			ratelimiter
+				.release(
+					"reset".into(),
+					RatelimitInfo {
+						limit: Some(1),
+						resets_in: Some(0),
+						..Default::default()
+					},
+				)
+				.await
+				.unwrap();
+		})
+	});
+
+	group.finish();
+}
+
+fn local(c: &mut Criterion) {
+	let rt = Runtime::new().unwrap();
+	bench_ratelimiter(c, "local", &rt, LocalRatelimiter::default());
+}
+
+#[cfg(feature = "redis-ratelimiter")]
+fn redis(c: &mut Criterion) {
+	use redust::pool::{Manager, Pool};
+	use spectacles_proxy::ratelimiter::redis::RedisRatelimiter;
+	use std::{env, sync::Arc};
+	use tokio::time::{timeout, Duration};
+
+	let rt = Runtime::new().unwrap();
+	let url = env::var("REDIS_URL").unwrap_or_else(|_| "localhost:6379".to_string());
+	let pool = Pool::builder(Manager::new(url.clone()))
+		.max_size(CONCURRENCY)
+		.build()
+		.unwrap();
+
+	let available = rt
+		.block_on(async { matches!(timeout(Duration::from_secs(1), pool.get()).await, Ok(Ok(_))) });
+	if !available {
+		eprintln!("Skipping Redis benchmarks: unable to connect to {}", url);
+		return;
+	}
+
+	let ratelimiter = rt.block_on(async { Arc::new(RedisRatelimiter::new(pool)) });
+	bench_ratelimiter(c, "redis", &rt, ratelimiter);
+}
+
+#[cfg(not(feature = "redis-ratelimiter"))]
+fn redis(_c: &mut Criterion) {}
+
+criterion_group!(benches, local, redis);
+criterion_main!(benches);
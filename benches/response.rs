@@ -0,0 +1,94 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use http::{HeaderMap, HeaderValue};
+use serde::Serialize;
+use spectacles_proxy::models::{ResponseHeaders, SerializableHttpResponse};
+use std::collections::HashMap;
+
+/// Headers like those of a typical API response.
+fn discord_headers() -> HeaderMap {
+	let headers = [
+		("date", "Mon, 01 Jan 2024 00:00:00 GMT"),
+		("content-type", "application/json"),
+		("content-length", "1024"),
+		("connection", "keep-alive"),
+		("x-ratelimit-bucket", "80c17d2f203122d936070c88c8d10f33"),
+		("x-ratelimit-limit", "5"),
+		("x-ratelimit-remaining", "4"),
+		("x-ratelimit-reset", "1704067201.000"),
+		("x-ratelimit-reset-after", "1.000"),
+		("via", "1.1 google"),
+		("alt-svc", "h3=\":443\"; ma=86400"),
+		("cf-cache-status", "DYNAMIC"),
+		(
+			"strict-transport-security",
+			"max-age=31536000; includeSubDomains; preload",
+		),
+		("x-content-type-options", "nosniff"),
+		("server", "cloudflare"),
+		("cf-ray", "83e1f7b3cd5e1234-IAD"),
+	];
+
+	headers
+		.iter()
+		.map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+		.collect()
+}
+
+/// A response as it was built before headers were kept as received.
+#[derive(Serialize)]
+struct StringResponse {
+	status: u16,
+	headers: HashMap<String, String>,
+	url: String,
+	body: bytes::Bytes,
+}
+
+fn bench_response(c: &mut Criterion) {
+	let headers = discord_headers();
+	let body = bytes::Bytes::from(vec![b'a'; 1024]);
+	let url = "https://discord.com/api/v10/channels/1234/messages";
+
+	let mut group = c.benchmark_group("response");
+
+	group.bench_function("string_headers", |b| {
+		b.iter(|| {
+			let res = StringResponse {
+				status: 200,
+				headers: headers
+					.iter()
+					.map(|(name, value)| {
+						(
+							name.as_str().to_string(),
+							value.to_str().unwrap().to_string(),
+						)
+					})
+					.collect(),
+				url: url.to_string(),
+				body: body.clone(),
+			};
+			black_box(rmp_serde::to_vec(&res).unwrap())
+		})
+	});
+
+	group.bench_function("response_headers", |b| {
+		b.iter(|| {
+			let res = SerializableHttpResponse {
+				status: 200,
+				headers: ResponseHeaders(headers.clone()),
+				url: url.to_string(),
+				body: body.clone(),
+				bucket: None,
+				debug: None,
+				ttfb_ms: None,
+				elapsed_ms: None,
+				json: false,
+			};
+			black_box(rmp_serde::to_vec(&res).unwrap())
+		})
+	});
+
+	group.finish();
+}
+
+criterion_group!(benches, bench_response);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+use std::process::Command;
+
+fn main() {
+	// record the commit being built, for the debug API
+	let commit = Command::new("git")
+		.args(["rev-parse", "HEAD"])
+		.output()
+		.ok()
+		.filter(|output| output.status.success())
+		.and_then(|output| String::from_utf8(output.stdout).ok())
+		.map(|commit| commit.trim().to_string())
+		.unwrap_or_else(|| "unknown".to_string());
+	println!("cargo:rustc-env=PROXY_COMMIT={}", commit);
+	println!("cargo:rerun-if-changed=.git/HEAD");
+	println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -4,3 +4,5 @@ pub mod models;
 pub mod ratelimiter;
 pub mod route;
 pub mod runtime;
+#[cfg(feature = "test-util")]
+pub mod test_util;
@@ -1,68 +1,386 @@
 use anyhow::Result;
+use futures::{future::try_join_all, FutureExt};
 #[cfg(not(feature = "redis-ratelimiter"))]
 use spectacles_proxy::ratelimiter::local::LocalRatelimiter;
 #[cfg(feature = "redis-ratelimiter")]
 use spectacles_proxy::ratelimiter::redis::RedisRatelimiter;
 #[cfg(feature = "metrics")]
-use spectacles_proxy::runtime::metrics::start_server;
+use spectacles_proxy::runtime::metrics::{start_server, DebugInfo};
 use spectacles_proxy::{
-	ratelimiter::Ratelimiter,
-	runtime::{Client, Config},
+	ratelimiter::{Ratelimiter, WatchedBuckets},
+	route::{BucketHashes, BucketLimit, MAX_BUCKET_HASHES},
+	runtime::{
+		backoff::Backoff,
+		body::BodyStore,
+		bucket_map::BucketMap,
+		compression::Compression,
+		config::{UnrepliedPolicy, DEFAULT_MAX_CONCURRENCY},
+		dedup::Dedup,
+		error_log::ErrorLog,
+		quiesce::Quiesce,
+		rate_cap::RateCap,
+		reload::Reloadable,
+		requeue::Requeue,
+		self_test::SelfTest,
+		signing::Signer,
+		unreplied::Unreplied,
+		Client, Config,
+	},
 };
-use tokio::spawn;
-use tracing::info;
-use tracing_subscriber::EnvFilter;
-use uriparse::Scheme;
+use std::{io, sync::Arc};
+use tokio::{
+	spawn,
+	sync::{watch, Semaphore},
+};
+use tracing::{info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+const CONFIG_PATH: &str = "proxy.toml";
 
 #[tokio::main]
 async fn main() -> Result<()> {
-	tracing_subscriber::fmt()
-		.with_env_filter(EnvFilter::from_default_env())
-		.init();
+	// logged once logging is set up, which depends on the config
+	let (config, config_error) = match Config::from_toml_file(CONFIG_PATH) {
+		Ok(config) => (config.with_env(), None),
+		Err(e) => (Config::default().with_env(), Some(e)),
+	};
+
+	let subscriber = tracing_subscriber::registry()
+		.with(EnvFilter::from_default_env())
+		.with(tracing_subscriber::fmt::layer());
+	#[cfg(feature = "otel")]
+	let subscriber = subscriber.with(
+		config
+			.otel
+			.as_ref()
+			.map(spectacles_proxy::runtime::otel::layer)
+			.transpose()?,
+	);
+	subscriber.init();
+
+	// the config can be given entirely through the environment, so a missing file is expected
+	if let Some(e) = config_error.filter(|e| !is_not_found(e)) {
+		warn!(
+			"Unable to load \"{}\", using defaults: {:?}",
+			CONFIG_PATH, e
+		);
+	}
+
+	// shared by every profile, so the whole process is drained at once
+	let quiesce = Arc::new(Quiesce::default());
+	#[cfg(unix)]
+	spawn(Arc::clone(&quiesce).follow_signals());
+
+	#[cfg(feature = "metrics")]
+	if let Some(ref metrics) = config.metrics {
+		info!("Launching metrics server");
+		spawn(start_server(
+			metrics.path.clone(),
+			metrics.addr,
+			DebugInfo::new(&config),
+			Arc::clone(&quiesce),
+		));
+	}
+
+	if config.profiles.is_empty() {
+		run(config, None, quiesce).await?;
+	} else {
+		let profiles = config.profiles.into_iter();
+		try_join_all(
+			profiles.map(|profile| run(profile.config, Some(profile.name), Arc::clone(&quiesce))),
+		)
+		.await?;
+	}
+
+	#[cfg(feature = "otel")]
+	opentelemetry::global::shutdown_tracer_provider();
+
+	Ok(())
+}
+
+/// Whether the error is from the config file not existing.
+fn is_not_found(e: &anyhow::Error) -> bool {
+	e.downcast_ref::<io::Error>()
+		.map_or(false, |e| e.kind() == io::ErrorKind::NotFound)
+}
 
-	let config = Config::from_toml_file("proxy.toml")
-		.unwrap_or_default()
-		.with_env();
+/// Run a proxy with the config until its broker stops, as the named profile if it's one of many.
+async fn run(config: Config, name: Option<String>, quiesce: Arc<Quiesce>) -> Result<()> {
+	let (reload_tx, reload) = watch::channel(Reloadable::from(&config));
 
 	let broker = config.new_broker();
+	// control events are consumed in this proxy's own group, so that every proxy receives them
+	let control_broker = config.new_control_broker(name.as_deref());
 
-	let ratelimiter = get_ratelimiter(&config);
+	// profiles sharing Redis keep their buckets and stored data apart
+	let prefix = key_prefix(name.as_deref());
+	let ratelimiter = get_ratelimiter(&config, name.as_deref());
 	let client = Client {
-		http: reqwest::Client::new(),
+		profile: name.clone().unwrap_or_default(),
+		http: config.http.new_clients()?,
 		ratelimiter,
-		api_base: "discord.com".to_string(),
-		api_scheme: Scheme::HTTPS,
+		api_base: config.discord.api_base.clone(),
+		cdn_base: config.discord.cdn_base.clone(),
+		upload_hosts: config.discord.upload_hosts.clone().into(),
+		token: config.discord.token.clone(),
+		api_scheme: config.discord.api_scheme.into(),
 		api_version: config.discord.api_version,
 		timeout: config.timeout.map(|d| d.into()),
+		timeouts: Arc::new(config.timeouts.clone()),
+		requeue: config.requeue.as_ref().map(|requeue| Requeue {
+			broker: config.new_broker(),
+			event: config.broker.event.clone(),
+			delay: requeue.delay,
+			max_redeliveries: requeue.max_redeliveries,
+			poison_event: requeue.poison_event.clone(),
+		}),
+		routes: config.routes.clone().into(),
+		reserved_headers: config.headers.reserved_prefixes.clone().into(),
+		header_profiles: Arc::new(config.headers.profiles.clone()),
+		query_limits: config.query.clone(),
+		forwarded: config.headers.forwarded.clone(),
+		paths: config.paths.clone(),
+		validate_json: config.validate_json,
+		echo: config.echo,
+		check_response_json: config.check_response_json,
+		bucket_limit: config
+			.max_buckets
+			.map(|max| Arc::new(BucketLimit::new(max))),
+		bucket_hashes: config.bucket_hashes.then(|| {
+			Arc::new(BucketHashes::new(
+				config.max_buckets.unwrap_or(MAX_BUCKET_HASHES),
+			))
+		}),
+		path_buckets: config.path_buckets,
+		version_buckets: config.version_buckets,
+		spacing: Default::default(),
+		pause: Default::default(),
+		quiesce,
+		cancellations: Default::default(),
+		body_store: Some(BodyStore::new(redis_pool(&config)).with_prefix(prefix.clone())),
+		backoff: Some(Arc::new(
+			Backoff::new(config.backoff.initial, config.backoff.max)
+				.with_retries(config.backoff.retries),
+		)),
+		signer: config
+			.signing
+			.as_ref()
+			.map(|signing| Arc::new(Signer::new(signing.key.as_bytes(), signing.max_age))),
+		compression: config
+			.compression
+			.as_ref()
+			.map(|compression| Compression::new(compression.min_size)),
+		release_grace: config.release_grace,
+		shutdown_grace: config.shutdown_grace,
+		rate_cap: config.rate_cap.as_ref().map(|cap| {
+			Arc::new(RateCap::new(
+				cap.per_second,
+				cap.burst.unwrap_or(cap.per_second.ceil() as u32),
+				cap.fail_fast,
+			))
+		}),
+		lanes: Arc::new(
+			config
+				.broker
+				.lanes
+				.iter()
+				.map(|lane| (lane.event.clone(), lane.clone()))
+				.collect(),
+		),
+		dedup: config
+			.dedup
+			.as_ref()
+			.map(|dedup| Dedup::new(redis_pool(&config), dedup.ttl).with_prefix(prefix.clone())),
+		unreplied: match config.broker.unreplied {
+			UnrepliedPolicy::Drop => Unreplied::Drop,
+			UnrepliedPolicy::Warn => Unreplied::Warn,
+			UnrepliedPolicy::Publish => Unreplied::Publish {
+				broker: config.new_broker(),
+				event: config
+					.broker
+					.result_event
+					.clone()
+					.expect("result_event is required to publish unreplied responses"),
+			},
+		},
+		error_log: config
+			.error_log
+			.as_ref()
+			.map(|log| ErrorLog::new(log.window, log.threshold)),
+		concurrency: Some(Arc::new(Semaphore::new(
+			config.max_concurrency.unwrap_or(DEFAULT_MAX_CONCURRENCY),
+		))),
+		batch: config.batch.clone(),
+		reload: Some(reload),
+	};
+	spawn(Arc::clone(&client.pause).follow(reload_tx.subscribe()));
+	// hashes learned by earlier proxies are loaded before any requests are sent
+	let bucket_map = match (&client.bucket_hashes, config.bucket_map_interval) {
+		(Some(hashes), Some(interval)) => {
+			let bucket_map = BucketMap::new(redis_pool(&config)).with_prefix(prefix.clone());
+			match bucket_map.import_bucket_map(hashes).await {
+				Ok(count) => info!("Imported {} bucket hashes", count),
+				Err(e) => warn!("Unable to import bucket hashes: {:?}", e),
+			}
+			spawn(bucket_map.clone().run(Arc::clone(hashes), interval));
+			Some((bucket_map, Arc::clone(hashes)))
+		}
+		_ => None,
 	};
+	if let Some(self_test) = &config.self_test {
+		info!("Running a self-test every {:?}", self_test.interval);
+		spawn(SelfTest::new(self_test.interval, self_test.path.clone()).run(client.clone()));
+	}
 
-	#[cfg(feature = "metrics")]
-	if let Some(ref config) = config.metrics {
-		info!("Launching metrics server");
-		spawn(start_server(config.path.clone(), config.addr));
+	#[cfg(unix)]
+	{
+		use spectacles_proxy::runtime::reload::{reload_on_hangup, reload_profile_on_hangup};
+
+		info!("Reloading config on SIGHUP");
+		match name {
+			Some(name) => spawn(reload_profile_on_hangup(
+				CONFIG_PATH.to_string(),
+				name,
+				config.clone(),
+				reload_tx,
+			)),
+			None => spawn(reload_on_hangup(
+				CONFIG_PATH.to_string(),
+				config.clone(),
+				reload_tx,
+			)),
+		};
 	}
+	#[cfg(not(unix))]
+	drop(reload_tx);
 
-	let events = vec![config.broker.event.into()];
+	let events = std::iter::once(config.broker.event)
+		.chain(config.broker.lanes.into_iter().map(|lane| lane.event))
+		.map(Into::into)
+		.collect::<Vec<_>>();
 	broker.ensure_events(events.iter()).await?;
 
 	info!("Beginning normal message consumption");
-	client.consume_stream(broker.consume(events)).await?;
+	let mut consumers = vec![client
+		.consume_stream_until(broker.consume(events), shutdown_signal())
+		.boxed()];
+	if let Some(event) = config.batch.event {
+		let events = vec![event.into()];
+		broker.ensure_events(events.iter()).await?;
+		consumers.push(
+			client
+				.consume_batches_until(broker.consume(events), shutdown_signal())
+				.boxed(),
+		);
+	}
+	if let Some(event) = config.broker.cancel_event {
+		let events = vec![event.into()];
+		control_broker.ensure_events(events.iter()).await?;
+		consumers.push(
+			client
+				.consume_cancellations_until(control_broker.consume(events), shutdown_signal())
+				.boxed(),
+		);
+	}
+	if let Some(event) = config.broker.reset_event {
+		let events = vec![event.into()];
+		control_broker.ensure_events(events.iter()).await?;
+		consumers.push(
+			client
+				.consume_resets_until(control_broker.consume(events), shutdown_signal())
+				.boxed(),
+		);
+	}
+	if let Some(event) = config.broker.pause_event {
+		let events = vec![event.into()];
+		control_broker.ensure_events(events.iter()).await?;
+		consumers.push(
+			client
+				.consume_pauses_until(control_broker.consume(events), shutdown_signal())
+				.boxed(),
+		);
+	}
+	try_join_all(consumers).await?;
+
+	if let Some((bucket_map, hashes)) = bucket_map {
+		if let Err(e) = bucket_map.export_bucket_map(&hashes).await {
+			warn!("Unable to export bucket hashes: {:?}", e);
+		}
+	}
 
 	Ok(())
 }
 
-#[cfg(feature = "redis-ratelimiter")]
-fn get_ratelimiter(config: &Config) -> impl Ratelimiter + Clone {
-	let manager = redust::pool::Manager::new(config.redis.url.clone());
-	let pool = redust::pool::Pool::builder(manager)
+/// Resolves once the proxy is asked to stop, by ctrl-c or SIGTERM.
+async fn shutdown_signal() {
+	#[cfg(unix)]
+	let terminate = async {
+		use tokio::signal::unix::{signal, SignalKind};
+
+		match signal(SignalKind::terminate()) {
+			Ok(mut terminate) => {
+				terminate.recv().await;
+			}
+			Err(_) => futures::future::pending().await,
+		}
+	};
+	#[cfg(not(unix))]
+	let terminate = futures::future::pending::<()>();
+
+	tokio::select! {
+		_ = tokio::signal::ctrl_c() => {}
+		_ = terminate => {}
+	}
+}
+
+fn redis_pool(config: &Config) -> redust::pool::Pool<String> {
+	pool_for(config, config.redis.url.clone())
+}
+
+fn pool_for(config: &Config, url: String) -> redust::pool::Pool<String> {
+	redust::pool::Pool::builder(redust::pool::Manager::new(url))
 		.max_size(config.redis.pool_size)
 		.build()
-		.expect("Unable to connect to Redis");
+		.expect("Unable to connect to Redis")
+}
+
+/// The prefix of the profile's Redis keys. A lone proxy's keys aren't prefixed.
+fn key_prefix(profile: Option<&str>) -> String {
+	profile.map_or_else(String::new, |profile| format!("{}:", profile))
+}
 
-	RedisRatelimiter::new(pool.clone())
+#[cfg(feature = "redis-ratelimiter")]
+fn get_ratelimiter(config: &Config, profile: Option<&str>) -> impl Ratelimiter + Clone {
+	let ratelimiter = match config.redis.poll_interval {
+		Some(interval) => RedisRatelimiter::polling(redis_pool(config), interval),
+		None => RedisRatelimiter::new(redis_pool(config)),
+	}
+	.with_prefix(key_prefix(profile))
+	.with_watched(watched_buckets(config, profile))
+	.with_global_limit(config.discord.global_limit);
+	let ratelimiter = match config.redis.subscriber_lifetime {
+		Some(lifetime) => ratelimiter.with_subscriber_lifetime(lifetime),
+		None => ratelimiter,
+	};
+	match &config.redis.replica_url {
+		Some(url) => ratelimiter.with_replica(pool_for(config, url.clone())),
+		None => ratelimiter,
+	}
 }
 
 #[cfg(not(feature = "redis-ratelimiter"))]
-fn get_ratelimiter(_config: &Config) -> impl Ratelimiter + Clone {
+fn get_ratelimiter(config: &Config, profile: Option<&str>) -> impl Ratelimiter + Clone {
 	LocalRatelimiter::default()
+		.with_watched(watched_buckets(config, profile))
+		.with_global_limit(config.discord.global_limit)
+}
+
+fn watched_buckets(config: &Config, profile: Option<&str>) -> WatchedBuckets {
+	WatchedBuckets::new(
+		config
+			.metrics
+			.iter()
+			.flat_map(|metrics| metrics.watched_buckets.iter().cloned()),
+	)
+	.with_profile(profile.unwrap_or_default().to_string())
 }
@@ -1,29 +1,128 @@
 use lazy_static::lazy_static;
-use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use prometheus::{
+	register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+	GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec,
+};
 
 lazy_static! {
 	pub static ref REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
 		"proxy_requests_total",
 		"Number of HTTP requests made",
-		&["method", "path"]
+		&["profile", "method", "path"]
 	)
 	.unwrap();
 	pub static ref RESPONSES_TOTAL: IntCounterVec = register_int_counter_vec!(
 		"proxy_responses_total",
 		"Number of HTTP responses received",
-		&["method", "path", "status"]
+		&["profile", "method", "path", "status"]
+	)
+	.unwrap();
+	pub static ref RATELIMITED_TOTAL: IntCounterVec = register_int_counter_vec!(
+		"proxy_ratelimited_total",
+		"Number of HTTP responses which were ratelimited (429), by the scope of the limit",
+		&["profile", "method", "path", "scope"]
+	)
+	.unwrap();
+	pub static ref REJECTIONS_TOTAL: IntCounterVec = register_int_counter_vec!(
+		"proxy_rejections_total",
+		"Number of requests rejected before being processed",
+		&["profile", "status"]
 	)
 	.unwrap();
 	pub static ref REQUEST_LATENCY: HistogramVec = register_histogram_vec!(
 		"proxy_request_latency",
 		"Latency of HTTP requests (in seconds)",
-		&["method", "path"]
+		&["profile", "method", "path"]
+	)
+	.unwrap();
+	pub static ref RESPONSE_TTFB: HistogramVec = register_histogram_vec!(
+		"proxy_response_ttfb_seconds",
+		"Time from sending HTTP requests until their response headers were received (in seconds)",
+		&["profile", "method", "path"]
+	)
+	.unwrap();
+	pub static ref RESPONSE_ELAPSED: HistogramVec = register_histogram_vec!(
+		"proxy_response_elapsed_seconds",
+		"Time from sending HTTP requests until their response bodies were received (in seconds)",
+		&["profile", "method", "path"]
+	)
+	.unwrap();
+	pub static ref REQUESTS_IN_FLIGHT: IntGaugeVec = register_int_gauge_vec!(
+		"proxy_requests_in_flight",
+		"Number of requests being handled, from claiming their ratelimit bucket until their response is received",
+		&["profile"]
+	)
+	.unwrap();
+	pub static ref RATELIMIT_WAITING: IntGaugeVec = register_int_gauge_vec!(
+		"proxy_ratelimit_waiting",
+		"Number of requests waiting to claim their ratelimit bucket",
+		&["profile"]
+	)
+	.unwrap();
+	pub static ref HTTP_IN_FLIGHT: IntGaugeVec = register_int_gauge_vec!(
+		"proxy_http_in_flight",
+		"Number of HTTP requests which are sent and waiting for their response to be received",
+		&["profile"]
 	)
 	.unwrap();
 	pub static ref RATELIMIT_LATENCY: HistogramVec = register_histogram_vec!(
 		"proxy_ratelimit_latency",
 		"Latency of ratelimit checking, including wait time for any ratelimited requests.",
-		&["method", "path"]
+		&["profile", "method", "path"]
+	)
+	.unwrap();
+	pub static ref CONCURRENCY_WAIT: HistogramVec = register_histogram_vec!(
+		"proxy_concurrency_wait_seconds",
+		"Time spent waiting for a concurrency permit before handling a message (in seconds).",
+		&["profile"]
+	)
+	.unwrap();
+	pub static ref RATE_CAP_WAIT: HistogramVec = register_histogram_vec!(
+		"proxy_rate_cap_wait_seconds",
+		"Time spent waiting on the outbound rate cap (in seconds).",
+		&["profile"]
+	)
+	.unwrap();
+	pub static ref ACK_DELAY: HistogramVec = register_histogram_vec!(
+		"proxy_ack_delay_seconds",
+		"Time from receiving a message until it's acked (in seconds).",
+		&["profile"]
+	)
+	.unwrap();
+	pub static ref OLDEST_UNACKED_AGE: GaugeVec = register_gauge_vec!(
+		"proxy_oldest_unacked_age_seconds",
+		"Time the oldest message which is still being handled has been waiting (in seconds).",
+		&["profile"]
+	)
+	.unwrap();
+	pub static ref SELF_TEST_SUCCESS: IntGaugeVec = register_int_gauge_vec!(
+		"proxy_self_test_success",
+		"Whether the last self-test request succeeded (1) or not (0)",
+		&["profile"]
+	)
+	.unwrap();
+	pub static ref SELF_TEST_LAST_SUCCESS: GaugeVec = register_gauge_vec!(
+		"proxy_self_test_last_success_timestamp_seconds",
+		"When the last successful self-test request finished (in seconds since the Unix epoch)",
+		&["profile"]
+	)
+	.unwrap();
+	pub static ref BUCKET_REMAINING: IntGaugeVec = register_int_gauge_vec!(
+		"proxy_bucket_remaining",
+		"Requests remaining in watched ratelimit buckets",
+		&["profile", "bucket"]
+	)
+	.unwrap();
+	pub static ref BUCKET_THROTTLED: IntGaugeVec = register_int_gauge_vec!(
+		"proxy_bucket_throttled",
+		"Whether the last claim on watched ratelimit buckets had to wait (1) or not (0)",
+		&["profile", "bucket"]
+	)
+	.unwrap();
+	pub static ref BUCKET_RESET: GaugeVec = register_gauge_vec!(
+		"proxy_bucket_reset_seconds",
+		"Time until watched ratelimit buckets reset (in seconds)",
+		&["profile", "bucket"]
 	)
 	.unwrap();
 }
@@ -1,35 +1,473 @@
+use crate::ratelimiter::RatelimitInfo;
 use anyhow::Result;
 use bytes::Bytes;
-use serde::{Deserialize, Serialize};
+use http::HeaderMap;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{
+	de::{self, MapAccess, SeqAccess, Visitor},
+	ser::SerializeMap,
+	Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::Value;
 use serde_repr::*;
 use std::{
 	collections::HashMap,
 	fmt::{self, Display, Formatter},
+	ops::Deref,
+	time::SystemTime,
 };
 use tokio::time::{error::Elapsed, Duration};
 
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct SerializableHttpRequest {
 	pub method: String,
 	pub path: String,
 	pub query: Option<HashMap<String, String>>,
-	pub body: Option<Bytes>,
+	pub body: Option<RequestBody>,
+	/// The content type of a raw or streamed body, unless `headers` set one.
+	pub content_type: Option<String>,
+	/// A Redis list holding the body in chunks, to stream instead of `body`.
+	pub body_key: Option<String>,
+	/// Files to attach. When set, the request is sent as `multipart/form-data` with `body` as its
+	/// `payload_json` part, followed by the files.
+	pub files: Option<Vec<FilePart>>,
 	#[serde(default)]
 	pub headers: HashMap<String, String>,
+	/// The name of a configured set of headers to send, which `headers` take precedence over.
+	pub profile: Option<String>,
+	/// How long the request may take, in milliseconds.
+	#[serde(default, with = "timeout_ms")]
 	pub timeout: Option<Duration>,
+	/// When the request expires. Unlike `timeout`, this counts time spent waiting in the broker.
+	pub deadline: Option<SystemTime>,
+	/// How long each attempt to send the request may take once its bucket is claimed, in
+	/// milliseconds, instead of the HTTP client's timeout.
+	#[serde(default, with = "timeout_ms")]
+	pub http_timeout: Option<Duration>,
+	/// The number of times this request has been requeued after a transient failure.
+	#[serde(default)]
+	pub redeliveries: u32,
+	/// Skip replying with the response once the request completes.
+	#[serde(default)]
+	pub no_reply: bool,
+	/// Whether to prefix the path with `/api/v{version}`. Defaults to true.
+	pub prefix: Option<bool>,
+	/// The API version to send the request with, instead of the configured one.
+	pub api_version: Option<u8>,
+	/// Include the ratelimiting decisions made for this request in its response.
+	#[serde(default)]
+	pub debug: bool,
+	/// Include how long Discord took to respond in the response.
+	#[serde(default)]
+	pub timing: bool,
+	/// Reply with the response's status, headers, and final URL (after following redirects)
+	/// without downloading its body.
+	#[serde(default)]
+	pub resolve_only: bool,
+	/// Reply with this request as the proxy decoded it, instead of sending it. Only honored when
+	/// the proxy is configured to allow it.
+	#[serde(default)]
+	pub echo: bool,
+	/// Which Discord host to send the request to.
+	#[serde(default)]
+	pub mode: RequestMode,
+	/// An HMAC of the request, required when the proxy is configured with a signing key.
+	pub signature: Option<Bytes>,
+	/// When the request was signed, in seconds since the Unix epoch.
+	pub timestamp: Option<u64>,
+	/// An id to cancel the request by, while it's being handled.
+	pub cancel_id: Option<String>,
+}
+
+/// Requests sent in a single message, which is replied to with their responses in order.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct BatchRequest {
+	pub requests: Vec<SerializableHttpRequest>,
+	/// Stop sending requests once one fails or gets an error status, skipping the rest.
+	#[serde(default)]
+	pub stop_on_error: bool,
+	/// An id to cancel the batch by, while it's being handled.
+	pub cancel_id: Option<String>,
+}
+
+/// The body of a request. Bodies are raw bytes or strings, or maps with a single key naming how
+/// the value is encoded: `json`, `raw`, `multipart`, or `form`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RequestBody {
+	/// A JSON value, sent as `application/json`.
+	Json(Value),
+	/// Bytes sent as-is, with whatever content type the request's headers specify.
+	Raw(Bytes),
+	/// Parts sent as `multipart/form-data`.
+	Multipart(Vec<FilePart>),
+	/// Pairs sent as `application/x-www-form-urlencoded`.
+	Form(Vec<(String, String)>),
+}
+
+/// A part of a multipart body.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct FilePart {
+	/// The name of the form field.
+	pub name: String,
+	pub filename: Option<String>,
+	pub content_type: Option<String>,
+	pub data: Bytes,
+}
+
+impl RequestBody {
+	/// Encode the body to send, along with the content type it must be sent with, if any.
+	pub fn encode(&self) -> Result<(Option<String>, Bytes)> {
+		Ok(match self {
+			Self::Json(value) => (
+				Some("application/json".to_string()),
+				serde_json::to_vec(value)?.into(),
+			),
+			Self::Raw(bytes) => (None, bytes.clone()),
+			Self::Multipart(parts) => {
+				let mut boundary = [0; 16];
+				SystemRandom::new()
+					.fill(&mut boundary)
+					.map_err(|_| anyhow::anyhow!("Unable to generate multipart boundary"))?;
+				let boundary = boundary
+					.iter()
+					.map(|b| format!("{:02x}", b))
+					.collect::<String>();
+
+				(
+					Some(format!("multipart/form-data; boundary={}", boundary)),
+					encode_multipart(parts, &boundary),
+				)
+			}
+			Self::Form(pairs) => (
+				Some("application/x-www-form-urlencoded".to_string()),
+				serde_urlencoded::to_string(pairs)?.into(),
+			),
+		})
+	}
+
+	/// A multipart body of this body as the `payload_json` part, followed by the files.
+	pub fn with_files(body: Option<&Self>, files: &[FilePart]) -> Result<Self> {
+		let payload = match body {
+			Some(Self::Json(value)) => Some(serde_json::to_vec(value)?.into()),
+			Some(Self::Raw(bytes)) => Some(bytes.clone()),
+			Some(Self::Multipart(_) | Self::Form(_)) => {
+				return Err(Rejection::new(
+					ResponseStatus::InvalidRequestFormat,
+					"files can only be sent with a JSON or raw body",
+				)
+				.into())
+			}
+			None => None,
+		};
+
+		let payload = payload.map(|data| FilePart {
+			name: "payload_json".to_string(),
+			filename: None,
+			content_type: Some("application/json".to_string()),
+			data,
+		});
+		Ok(Self::Multipart(
+			payload.into_iter().chain(files.iter().cloned()).collect(),
+		))
+	}
+
+	/// The length of the body, before it's encoded.
+	pub fn len(&self) -> usize {
+		match self {
+			Self::Json(value) => value.to_string().len(),
+			Self::Raw(bytes) => bytes.len(),
+			Self::Multipart(parts) => parts.iter().map(|part| part.data.len()).sum(),
+			Self::Form(pairs) => pairs.iter().map(|(k, v)| k.len() + v.len()).sum(),
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+fn encode_multipart(parts: &[FilePart], boundary: &str) -> Bytes {
+	let mut body = Vec::new();
+	for part in parts {
+		body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+		body.extend_from_slice(
+			format!("Content-Disposition: form-data; name=\"{}\"", part.name).as_bytes(),
+		);
+		if let Some(filename) = &part.filename {
+			body.extend_from_slice(format!("; filename=\"{}\"", filename).as_bytes());
+		}
+		body.extend_from_slice(b"\r\n");
+		if let Some(content_type) = &part.content_type {
+			body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+		}
+		body.extend_from_slice(b"\r\n");
+		body.extend_from_slice(&part.data);
+		body.extend_from_slice(b"\r\n");
+	}
+	body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+	body.into()
+}
+
+impl From<Bytes> for RequestBody {
+	fn from(bytes: Bytes) -> Self {
+		Self::Raw(bytes)
+	}
+}
+
+impl From<&'static str> for RequestBody {
+	fn from(body: &'static str) -> Self {
+		Self::Raw(body.into())
+	}
+}
+
+impl Serialize for RequestBody {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		if let Self::Raw(bytes) = self {
+			return serializer.serialize_bytes(bytes);
+		}
+
+		let mut map = serializer.serialize_map(Some(1))?;
+		match self {
+			Self::Json(value) => map.serialize_entry("json", value)?,
+			Self::Multipart(parts) => map.serialize_entry("multipart", parts)?,
+			Self::Form(pairs) => map.serialize_entry("form", pairs)?,
+			Self::Raw(_) => unreachable!(),
+		}
+		map.end()
+	}
+}
+
+impl<'de> Deserialize<'de> for RequestBody {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		deserializer.deserialize_any(RequestBodyVisitor)
+	}
+}
+
+struct RequestBodyVisitor;
+
+impl<'de> Visitor<'de> for RequestBodyVisitor {
+	type Value = RequestBody;
+
+	fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+		f.write_str("bytes, a string, or a map with a single json, raw, multipart, or form key")
+	}
+
+	fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+		Ok(RequestBody::Raw(Bytes::copy_from_slice(v)))
+	}
+
+	fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+		Ok(RequestBody::Raw(v.into()))
+	}
+
+	fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+		self.visit_bytes(v.as_bytes())
+	}
+
+	fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+		Ok(RequestBody::Raw(v.into()))
+	}
+
+	/// Bytes serialized as JSON arrive as a sequence of numbers.
+	fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+		let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+		while let Some(b) = seq.next_element::<u8>()? {
+			bytes.push(b);
+		}
+		Ok(RequestBody::Raw(bytes.into()))
+	}
+
+	fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+		const VARIANTS: &[&str] = &["json", "raw", "multipart", "form"];
+
+		let key = map
+			.next_key::<String>()?
+			.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+		let body = match key.as_str() {
+			"json" => RequestBody::Json(map.next_value()?),
+			"raw" => RequestBody::Raw(map.next_value()?),
+			"multipart" => RequestBody::Multipart(map.next_value()?),
+			"form" => RequestBody::Form(map.next_value()?),
+			other => return Err(de::Error::unknown_variant(other, VARIANTS)),
+		};
+
+		if map.next_key::<de::IgnoredAny>()?.is_some() {
+			return Err(de::Error::invalid_length(2, &self));
+		}
+		Ok(body)
+	}
+}
+
+/// (De)serializes an optional duration as a number of milliseconds. Durations serialized as
+/// `{secs, nanos}` (serde's own representation) are still read, for older producers.
+mod timeout_ms {
+	use super::*;
+	use std::convert::TryFrom;
+
+	pub fn serialize<S: Serializer>(
+		timeout: &Option<Duration>,
+		serializer: S,
+	) -> Result<S::Ok, S::Error> {
+		match timeout {
+			Some(timeout) => {
+				let millis = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+				serializer.serialize_some(&millis)
+			}
+			None => serializer.serialize_none(),
+		}
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(
+		deserializer: D,
+	) -> Result<Option<Duration>, D::Error> {
+		deserializer.deserialize_option(OptionVisitor)
+	}
+
+	struct OptionVisitor;
+
+	impl<'de> Visitor<'de> for OptionVisitor {
+		type Value = Option<Duration>;
+
+		fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+			f.write_str("an optional number of milliseconds")
+		}
+
+		fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+			Ok(None)
+		}
+
+		fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+			Ok(None)
+		}
+
+		fn visit_some<D: Deserializer<'de>>(
+			self,
+			deserializer: D,
+		) -> Result<Self::Value, D::Error> {
+			deserializer.deserialize_any(MillisVisitor).map(Some)
+		}
+	}
+
+	struct MillisVisitor;
+
+	impl<'de> Visitor<'de> for MillisVisitor {
+		type Value = Duration;
+
+		fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+			f.write_str("a number of milliseconds, or a duration with secs and nanos")
+		}
+
+		fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+			Ok(Duration::from_millis(v))
+		}
+
+		fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+			u64::try_from(v)
+				.map(Duration::from_millis)
+				.map_err(|_| de::Error::invalid_value(de::Unexpected::Signed(v), &self))
+		}
+
+		fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+			if v.is_finite() && v >= 0. {
+				Ok(Duration::from_secs_f64(v / 1000.))
+			} else {
+				Err(de::Error::invalid_value(de::Unexpected::Float(v), &self))
+			}
+		}
+
+		fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+			Duration::deserialize(de::value::SeqAccessDeserializer::new(seq))
+		}
+
+		fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+			Duration::deserialize(de::value::MapAccessDeserializer::new(map))
+		}
+	}
+}
+
+/// The Discord host a request is sent to, each of which is sent with its own HTTP client.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestMode {
+	/// The API, with paths prefixed by the API version unless requested otherwise.
+	#[default]
+	Api,
+	/// The CDN, with paths sent as-is.
+	Cdn,
+	/// A pre-signed upload URL, given in full as the path, on one of the allowed upload hosts.
+	/// Uploads are sent with the CDN client and aren't ratelimited.
+	Upload,
+}
+
+impl SerializableHttpRequest {
+	/// Build a request, starting from its defaults.
+	pub fn builder() -> SerializableHttpRequestBuilder {
+		SerializableHttpRequestBuilder::default()
+	}
+}
+
+/// Builds a [`SerializableHttpRequest`] one part at a time.
+#[derive(Debug, Default, Clone)]
+pub struct SerializableHttpRequestBuilder {
+	data: SerializableHttpRequest,
+}
+
+impl SerializableHttpRequestBuilder {
+	pub fn method(mut self, method: impl Into<String>) -> Self {
+		self.data.method = method.into();
+		self
+	}
+
+	pub fn path(mut self, path: impl Into<String>) -> Self {
+		self.data.path = path.into();
+		self
+	}
+
+	/// Add a query parameter, replacing any earlier value for the key.
+	pub fn query(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+		self.data
+			.query
+			.get_or_insert_with(HashMap::new)
+			.insert(key.into(), value.into());
+		self
+	}
+
+	/// Add a header, replacing any earlier value for the name.
+	pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+		self.data.headers.insert(name.into(), value.into());
+		self
+	}
+
+	/// Send the body serialized as JSON, with a JSON content type.
+	pub fn json_body<T: Serialize + ?Sized>(self, body: &T) -> serde_json::Result<Self> {
+		let body = Bytes::from(serde_json::to_vec(body)?);
+		let mut builder = self.header("content-type", "application/json");
+		builder.data.body = Some(RequestBody::Raw(body));
+		Ok(builder)
+	}
+
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.data.timeout = Some(timeout);
+		self
+	}
+
+	pub fn build(self) -> SerializableHttpRequest {
+		self.data
+	}
 }
 
 impl Display for SerializableHttpRequest {
 	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
 		write!(
 			f,
-			"{} {} Query={:?} Headers={:?} BodyLen={:?} Timeout={:?}ms",
+			"{} {} Query={:?} Headers={:?} BodyLen={:?} Timeout={:?}ms Redeliveries={}",
 			self.method,
 			self.path,
 			self.query,
 			self.headers,
 			self.body.as_ref().map(|b| b.len()),
-			self.timeout.map(|d| d.as_millis())
+			self.timeout.map(|d| d.as_millis()),
+			self.redeliveries
 		)
 	}
 }
@@ -37,9 +475,27 @@ impl Display for SerializableHttpRequest {
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub struct SerializableHttpResponse {
 	pub status: u16,
-	pub headers: HashMap<String, String>,
+	pub headers: ResponseHeaders,
 	pub url: String,
 	pub body: Bytes,
+	/// The ratelimit bucket the request was grouped into.
+	#[serde(default)]
+	pub bucket: Option<String>,
+	/// The ratelimiting decisions made for the request, if it requested them.
+	#[serde(default)]
+	pub debug: Option<RatelimitDebug>,
+	/// Milliseconds from sending the request until the response's headers were received, if the
+	/// request asked for timing.
+	#[serde(default)]
+	pub ttfb_ms: Option<u64>,
+	/// Milliseconds from sending the request until the response's body was received, if the
+	/// request asked for timing.
+	#[serde(default)]
+	pub elapsed_ms: Option<u64>,
+	/// Whether the body was checked to be valid JSON. This is only checked for responses with a
+	/// JSON content type when the proxy is configured to.
+	#[serde(default)]
+	pub json: bool,
 }
 
 impl Display for SerializableHttpResponse {
@@ -55,8 +511,59 @@ impl Display for SerializableHttpResponse {
 	}
 }
 
+/// Response headers as received, serialized as a map of names to their last values, so building
+/// a response doesn't allocate a string for each of them. Values which aren't valid UTF-8 are
+/// skipped.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ResponseHeaders(pub HeaderMap);
+
+impl From<HeaderMap> for ResponseHeaders {
+	fn from(headers: HeaderMap) -> Self {
+		Self(headers)
+	}
+}
+
+impl Deref for ResponseHeaders {
+	type Target = HeaderMap;
+
+	fn deref(&self) -> &HeaderMap {
+		&self.0
+	}
+}
+
+impl Serialize for ResponseHeaders {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_map(self.0.keys().filter_map(|name| {
+			let value = self.0.get_all(name).iter().next_back()?;
+			Some((name.as_str(), std::str::from_utf8(value.as_bytes()).ok()?))
+		}))
+	}
+}
+
+impl<'de> Deserialize<'de> for ResponseHeaders {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let headers = HashMap::<String, String>::deserialize(deserializer)?;
+		let headers = headers
+			.iter()
+			.map(|(name, value)| Ok((name.parse()?, value.parse()?)))
+			.collect::<Result<HeaderMap>>()
+			.map_err(de::Error::custom)?;
+		Ok(Self(headers))
+	}
+}
+
+/// The ratelimiting decisions made for a single request.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct RatelimitDebug {
+	pub bucket: String,
+	/// How long the request waited to claim its bucket.
+	pub waited: Duration,
+	/// The ratelimit info the bucket was released with.
+	pub info: RatelimitInfo,
+}
+
 #[repr(u8)]
-#[derive(Debug, Serialize_repr, Deserialize_repr, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr, Eq, PartialEq)]
 pub enum ResponseStatus {
 	Success,
 	Unknown,
@@ -67,11 +574,25 @@ pub enum ResponseStatus {
 	InvalidHeaders,
 	RequestFailure,
 	RequestTimeout,
+	DnsFailure,
+	PoisonMessage,
+	Unauthorized,
+	RateCapped,
+	Expired,
+	RouteNotAllowed,
+	/// Not sent, because an earlier request in its batch failed.
+	Skipped,
+	/// Stopped, because its message was cancelled.
+	Cancelled,
+	/// Not sent, because its batch had too many requests.
+	BatchTooLarge,
+	/// Discord's Cloudflare banned the proxy for making too many invalid requests.
+	CloudflareBanned,
 }
 
 impl From<&(dyn std::error::Error + 'static)> for ResponseStatus {
 	fn from(e: &(dyn std::error::Error + 'static)) -> Self {
-		if e.is::<rmp_serde::decode::Error>() {
+		if e.is::<rmp_serde::decode::Error>() || e.is::<serde_json::Error>() {
 			ResponseStatus::InvalidRequestFormat
 		} else if e.is::<uriparse::PathError>() {
 			ResponseStatus::InvalidPath
@@ -82,21 +603,120 @@ impl From<&(dyn std::error::Error + 'static)> for ResponseStatus {
 		} else if e.is::<http::Error>() {
 			ResponseStatus::InvalidHeaders
 		} else if e.is::<reqwest::Error>() {
-			ResponseStatus::RequestFailure
+			if is_dns_error(e) {
+				ResponseStatus::DnsFailure
+			} else {
+				ResponseStatus::RequestFailure
+			}
 		} else if e.is::<Elapsed>() {
 			ResponseStatus::RequestTimeout
+		} else if e.is::<PoisonedError>() {
+			ResponseStatus::PoisonMessage
+		} else if let Some(rejection) = e.downcast_ref::<Rejection>() {
+			rejection.status
 		} else {
 			ResponseStatus::Unknown
 		}
 	}
 }
 
+/// Whether the error was caused by a failure to resolve the host.
+fn is_dns_error(e: &(dyn std::error::Error + 'static)) -> bool {
+	std::iter::successors(Some(e), |e| e.source()).any(|e| e.is::<DnsError>())
+}
+
+/// A failure to resolve the host a request was sent to.
+#[derive(Debug)]
+pub struct DnsError(pub std::io::Error);
+
+impl Display for DnsError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "unable to resolve host: {}", self.0)
+	}
+}
+
+impl std::error::Error for DnsError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		Some(&self.0)
+	}
+}
+
+/// The error returned for a request which kept failing after being requeued.
+#[derive(Debug)]
+pub struct PoisonedError {
+	pub redeliveries: u32,
+	pub cause: String,
+}
+
+impl Display for PoisonedError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"request failed after {} redeliveries: {}",
+			self.redeliveries, self.cause
+		)
+	}
+}
+
+impl std::error::Error for PoisonedError {}
+
+/// A request which was rejected before being processed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Rejection {
+	pub status: ResponseStatus,
+	pub message: String,
+}
+
+impl Rejection {
+	pub fn new(status: ResponseStatus, message: impl Into<String>) -> Self {
+		Self {
+			status,
+			message: message.into(),
+		}
+	}
+}
+
+impl Display for Rejection {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.message)
+	}
+}
+
+impl std::error::Error for Rejection {}
+
+impl From<anyhow::Error> for Rejection {
+	fn from(e: anyhow::Error) -> Self {
+		match e.downcast::<Rejection>() {
+			Ok(rejection) => rejection,
+			Err(e) => {
+				let e_ref: &dyn std::error::Error = e.as_ref();
+				Self::new(e_ref.into(), e.to_string())
+			}
+		}
+	}
+}
+
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub struct RequestResponse<T> {
 	pub status: ResponseStatus,
 	pub body: RequestResponseBody<T>,
 }
 
+/// A reply gzipped to save space in the broker, holding the MessagePack encoding of the reply.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct CompressedReply {
+	pub gzip: Bytes,
+}
+
+/// A response published to the result event, because the message it answers couldn't be
+/// replied to.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct UnrepliedResponse<T> {
+	/// The id of the message which was responded to.
+	pub id: String,
+	pub response: T,
+}
+
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
 #[serde(untagged)]
 pub enum RequestResponseBody<T> {
@@ -104,6 +724,15 @@ pub enum RequestResponseBody<T> {
 	Err(String),
 }
 
+impl<T> From<Rejection> for RequestResponse<T> {
+	fn from(rejection: Rejection) -> Self {
+		Self {
+			status: rejection.status,
+			body: RequestResponseBody::Err(rejection.message),
+		}
+	}
+}
+
 impl<T> From<Result<T>> for RequestResponse<T> {
 	fn from(res: Result<T>) -> Self {
 		match res {
@@ -121,3 +750,214 @@ impl<T> From<Result<T>> for RequestResponse<T> {
 		}
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::{
+		FilePart, RequestBody, RequestResponse, ResponseHeaders, ResponseStatus,
+		SerializableHttpRequest,
+	};
+	use bytes::Bytes;
+	use http::{HeaderMap, HeaderValue};
+	use serde_json::json;
+	use std::collections::HashMap;
+	use tokio::time::Duration;
+
+	fn bodies() -> Vec<RequestBody> {
+		vec![
+			RequestBody::Json(json!({"content": "hi", "tts": false})),
+			RequestBody::Raw(Bytes::from_static(b"\x00\xffraw")),
+			RequestBody::Multipart(vec![
+				FilePart {
+					name: "payload_json".to_string(),
+					filename: None,
+					content_type: Some("application/json".to_string()),
+					data: r#"{"content": "hi"}"#.into(),
+				},
+				FilePart {
+					name: "files[0]".to_string(),
+					filename: Some("hi.txt".to_string()),
+					content_type: None,
+					data: "hi".into(),
+				},
+			]),
+			RequestBody::Form(vec![
+				("grant_type".to_string(), "client_credentials".to_string()),
+				("scope".to_string(), "identify email".to_string()),
+			]),
+		]
+	}
+
+	#[test]
+	fn round_trips_msgpack_bodies() {
+		for body in bodies() {
+			let encoded = rmp_serde::to_vec(&body).unwrap();
+			assert_eq!(
+				rmp_serde::from_slice::<RequestBody>(&encoded).unwrap(),
+				body
+			);
+		}
+	}
+
+	#[test]
+	fn round_trips_json_bodies() {
+		for body in bodies() {
+			let encoded = serde_json::to_vec(&body).unwrap();
+			assert_eq!(
+				serde_json::from_slice::<RequestBody>(&encoded).unwrap(),
+				body
+			);
+		}
+	}
+
+	#[test]
+	fn reads_untyped_bodies_as_raw() {
+		#[derive(serde::Serialize)]
+		struct OldRequest {
+			method: &'static str,
+			path: &'static str,
+			body: Bytes,
+		}
+
+		let old = rmp_serde::to_vec_named(&OldRequest {
+			method: "POST",
+			path: "/channels/1/messages",
+			body: r#"{"content": "hi"}"#.into(),
+		})
+		.unwrap();
+		let data = rmp_serde::from_slice::<SerializableHttpRequest>(&old).unwrap();
+		assert_eq!(data.body, Some(r#"{"content": "hi"}"#.into()));
+
+		let data = serde_json::from_str::<SerializableHttpRequest>(
+			r#"{"method": "POST", "path": "/", "body": "hi"}"#,
+		)
+		.unwrap();
+		assert_eq!(data.body, Some("hi".into()));
+	}
+
+	#[test]
+	fn builds_request() {
+		let data = SerializableHttpRequest::builder()
+			.method("POST")
+			.path("/channels/1/messages")
+			.query("wait", "true")
+			.header("x-audit-log-reason", "testing")
+			.json_body(&json!({"content": "hi"}))
+			.unwrap()
+			.timeout(Duration::from_secs(5))
+			.build();
+
+		assert_eq!(
+			data,
+			SerializableHttpRequest {
+				method: "POST".into(),
+				path: "/channels/1/messages".into(),
+				query: Some(HashMap::from([("wait".into(), "true".into())])),
+				body: Some(RequestBody::Raw(Bytes::from(r#"{"content":"hi"}"#))),
+				headers: HashMap::from([
+					("x-audit-log-reason".into(), "testing".into()),
+					("content-type".into(), "application/json".into()),
+				]),
+				timeout: Some(Duration::from_secs(5)),
+				..Default::default()
+			}
+		);
+	}
+
+	#[test]
+	fn round_trips_timeout_as_millis() {
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			timeout: Some(Duration::from_millis(1500)),
+			..Default::default()
+		};
+
+		let json = serde_json::to_value(&data).unwrap();
+		assert_eq!(json["timeout"], json!(1500));
+		assert_eq!(
+			serde_json::from_value::<SerializableHttpRequest>(json).unwrap(),
+			data
+		);
+
+		let encoded = rmp_serde::to_vec_named(&data).unwrap();
+		let map = rmp_serde::from_slice::<HashMap<String, serde_json::Value>>(&encoded).unwrap();
+		assert_eq!(map["timeout"], json!(1500));
+		assert_eq!(
+			rmp_serde::from_slice::<SerializableHttpRequest>(&encoded).unwrap(),
+			data
+		);
+
+		let none = SerializableHttpRequest::default();
+		let encoded = rmp_serde::to_vec(&none).unwrap();
+		assert_eq!(
+			rmp_serde::from_slice::<SerializableHttpRequest>(&encoded).unwrap(),
+			none
+		);
+	}
+
+	#[test]
+	fn reads_legacy_timeouts() {
+		#[derive(serde::Serialize)]
+		struct OldRequest {
+			method: &'static str,
+			path: &'static str,
+			timeout: Option<Duration>,
+		}
+
+		let old = rmp_serde::to_vec_named(&OldRequest {
+			method: "GET",
+			path: "/gateway",
+			timeout: Some(Duration::from_millis(1500)),
+		})
+		.unwrap();
+		let data = rmp_serde::from_slice::<SerializableHttpRequest>(&old).unwrap();
+		assert_eq!(data.timeout, Some(Duration::from_millis(1500)));
+
+		let data = serde_json::from_str::<SerializableHttpRequest>(
+			r#"{"method": "GET", "path": "/", "timeout": {"secs": 1, "nanos": 500000000}}"#,
+		)
+		.unwrap();
+		assert_eq!(data.timeout, Some(Duration::from_millis(1500)));
+
+		let data =
+			serde_json::from_str::<SerializableHttpRequest>(r#"{"method": "GET", "path": "/"}"#)
+				.unwrap();
+		assert_eq!(data.timeout, None);
+	}
+
+	#[test]
+	fn serializes_response_headers_as_map() {
+		let mut headers = HeaderMap::new();
+		headers.append("x-ratelimit-bucket", HeaderValue::from_static("abcd"));
+		headers.append("set-cookie", HeaderValue::from_static("a=1"));
+		headers.append("set-cookie", HeaderValue::from_static("b=2"));
+		let headers = ResponseHeaders(headers);
+
+		let encoded = rmp_serde::to_vec(&headers).unwrap();
+		let map = rmp_serde::from_slice::<HashMap<String, String>>(&encoded).unwrap();
+		assert_eq!(map.len(), 2);
+		assert_eq!(map["x-ratelimit-bucket"], "abcd");
+		assert_eq!(map["set-cookie"], "b=2");
+
+		let decoded = rmp_serde::from_slice::<ResponseHeaders>(&encoded).unwrap();
+		assert_eq!(decoded["set-cookie"], "b=2");
+		assert_eq!(decoded.len(), 2);
+	}
+
+	#[test]
+	fn skips_non_utf8_response_headers() {
+		let mut headers = HeaderMap::new();
+		headers.append("x-odd", HeaderValue::from_bytes(b"\xff\xfe").unwrap());
+		headers.append(
+			"x-name",
+			HeaderValue::from_bytes("caf\u{e9}".as_bytes()).unwrap(),
+		);
+		let headers = ResponseHeaders(headers);
+
+		let encoded = rmp_serde::to_vec(&headers).unwrap();
+		let map = rmp_serde::from_slice::<HashMap<String, String>>(&encoded).unwrap();
+		assert_eq!(map.len(), 1);
+		assert_eq!(map["x-name"], "caf\u{e9}");
+	}
+}
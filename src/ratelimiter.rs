@@ -1,16 +1,117 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use reqwest::{header::HeaderMap, Response};
-use std::{ops::Deref, str::FromStr};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashSet,
+	fmt::{self, Display, Formatter},
+	future::Future,
+	ops::Deref,
+	str::FromStr,
+	sync::{Arc, Mutex},
+};
+use tokio::time::{timeout_at, Duration, Instant};
+use tracing::info;
+
+/// Requests allowed across every bucket per `GLOBAL_WINDOW`, by default.
+pub const GLOBAL_LIMIT: u32 = 50;
+pub const GLOBAL_WINDOW: Duration = Duration::from_secs(1);
 
 pub mod local;
 #[cfg(feature = "redis-ratelimiter")]
 pub mod redis;
 
+/// Returned by [`Ratelimiter::claim_all`] when it couldn't claim everything before its deadline.
+#[derive(Debug)]
+pub struct ClaimTimedOut(pub String);
+
+impl Display for ClaimTimedOut {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "Timed out claiming \"{}\"", self.0)
+	}
+}
+
+impl std::error::Error for ClaimTimedOut {}
+
+/// Wait for the future until the deadline, if there is one.
+async fn until<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+	match deadline {
+		Some(deadline) => timeout_at(deadline, future).await.ok(),
+		None => Some(future.await),
+	}
+}
+
 #[async_trait]
 pub trait Ratelimiter {
 	async fn claim(&self, bucket: String) -> Result<()>;
 	async fn release(&self, bucket: String, info: RatelimitInfo) -> Result<()>;
+
+	/// Give back a claim which wasn't used to send a request. Unlike a release, this doesn't
+	/// change how long the bucket is held closed for.
+	async fn unclaim(&self, bucket: String) -> Result<()>;
+
+	/// Claim several buckets in a canonical (sorted) order, followed by the global limit if
+	/// `global` is set, so that requests which need overlapping sets of buckets can't deadlock
+	/// each other. If everything can't be claimed before the deadline, the buckets which were
+	/// claimed are given back and [`ClaimTimedOut`] is returned.
+	async fn claim_all(
+		&self,
+		mut buckets: Vec<String>,
+		global: bool,
+		deadline: Option<Instant>,
+	) -> Result<()> {
+		buckets.sort();
+		buckets.dedup();
+
+		let mut claimed = Vec::with_capacity(buckets.len());
+		for bucket in buckets {
+			let err = match until(deadline, self.claim(bucket.clone())).await {
+				Some(Ok(())) => {
+					claimed.push(bucket);
+					continue;
+				}
+				Some(Err(e)) => e,
+				None => ClaimTimedOut(bucket).into(),
+			};
+
+			for bucket in claimed {
+				self.unclaim(bucket).await?;
+			}
+			return Err(err);
+		}
+
+		if global {
+			let err = match until(deadline, self.claim_global()).await {
+				Some(Ok(())) => return Ok(()),
+				Some(Err(e)) => e,
+				None => ClaimTimedOut("global".to_string()).into(),
+			};
+
+			for bucket in claimed {
+				self.unclaim(bucket).await?;
+			}
+			return Err(err);
+		}
+
+		Ok(())
+	}
+
+	/// Reset a bucket to its default state, as if it had never been claimed.
+	async fn reset_bucket(&self, _bucket: String) -> Result<()> {
+		Ok(())
+	}
+
+	/// Claim a request from the global limit, which every request counts against regardless of
+	/// its bucket.
+	async fn claim_global(&self) -> Result<()> {
+		Ok(())
+	}
+
+	/// Release the global limit after a request, holding it closed if Discord says the request
+	/// hit it.
+	async fn release_global(&self, _info: RatelimitInfo) -> Result<()> {
+		Ok(())
+	}
 }
 
 #[async_trait]
@@ -26,12 +127,124 @@ where
 	async fn release(&self, bucket: String, info: RatelimitInfo) -> Result<()> {
 		Ratelimiter::release(self.deref(), bucket, info).await
 	}
+
+	async fn unclaim(&self, bucket: String) -> Result<()> {
+		Ratelimiter::unclaim(self.deref(), bucket).await
+	}
+
+	async fn reset_bucket(&self, bucket: String) -> Result<()> {
+		Ratelimiter::reset_bucket(self.deref(), bucket).await
+	}
+
+	async fn claim_global(&self) -> Result<()> {
+		Ratelimiter::claim_global(self.deref()).await
+	}
+
+	async fn release_global(&self, info: RatelimitInfo) -> Result<()> {
+		Ratelimiter::release_global(self.deref(), info).await
+	}
+}
+
+/// Buckets whose state is exported as metrics. Only configured buckets are watched, to keep the
+/// metrics' cardinality bounded.
+#[derive(Debug, Default, Clone)]
+pub struct WatchedBuckets {
+	buckets: Arc<HashSet<String>>,
+	/// The profile the metrics are labeled with.
+	profile: String,
+}
+
+impl WatchedBuckets {
+	pub fn new(buckets: impl IntoIterator<Item = String>) -> Self {
+		Self {
+			buckets: Arc::new(buckets.into_iter().collect()),
+			profile: String::new(),
+		}
+	}
+
+	/// Label the metrics with the profile, keeping them apart from other profiles' buckets.
+	pub fn with_profile(mut self, profile: String) -> Self {
+		self.profile = profile;
+		self
+	}
+
+	pub fn contains(&self, bucket: &str) -> bool {
+		self.buckets.contains(bucket)
+	}
+
+	/// Record the state of a watched bucket.
+	#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+	pub fn record(&self, bucket: &str, remaining: i64, resets_in: Duration) {
+		#[cfg(feature = "metrics")]
+		{
+			use crate::metrics::{BUCKET_REMAINING, BUCKET_RESET};
+
+			BUCKET_REMAINING
+				.with_label_values(&[&self.profile, bucket])
+				.set(remaining);
+			BUCKET_RESET
+				.with_label_values(&[&self.profile, bucket])
+				.set(resets_in.as_secs_f64());
+		}
+	}
+
+	/// Record whether a watched bucket is throttled.
+	#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+	pub fn record_throttled(&self, bucket: &str, throttled: bool) {
+		#[cfg(feature = "metrics")]
+		if self.contains(bucket) {
+			crate::metrics::BUCKET_THROTTLED
+				.with_label_values(&[&self.profile, bucket])
+				.set(throttled as i64);
+		}
+	}
+}
+
+/// Buckets whose last claim had to wait, so that a bucket starting and stopping being throttled
+/// is reported once rather than for every claim.
+#[derive(Debug, Default, Clone)]
+pub struct Throttled(Arc<Mutex<HashSet<String>>>);
+
+impl Throttled {
+	/// Record whether a claim on the bucket had to wait, returning whether that changed whether
+	/// the bucket is throttled.
+	pub fn record(&self, bucket: &str, waited: bool) -> bool {
+		let mut throttled = self.0.lock().unwrap();
+		let changed = match waited {
+			true => throttled.insert(bucket.to_string()),
+			false => throttled.remove(bucket),
+		};
+		drop(throttled);
+
+		match (changed, waited) {
+			(true, true) => info!("\"{}\" is throttled", bucket),
+			(true, false) => info!("\"{}\" is no longer throttled", bucket),
+			_ => {}
+		}
+		changed
+	}
 }
 
-#[derive(Debug, Default, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct RatelimitInfo {
 	pub limit: Option<usize>,
 	pub resets_in: Option<u64>,
+	/// Requests remaining before the bucket resets. Once this reaches 0, the bucket is held
+	/// closed until it resets.
+	#[serde(default)]
+	pub remaining: Option<usize>,
+	/// Whether Discord's global limit was hit, in which case `resets_in` applies to the global
+	/// bucket rather than the request's bucket.
+	#[serde(default)]
+	pub global: bool,
+	/// The hash Discord identifies the bucket by, which routes it groups together share.
+	#[serde(default)]
+	pub bucket: Option<String>,
+	/// How long Discord said to wait before retrying, after a 429. The bucket is held closed at
+	/// least this long, even if it would otherwise reset sooner. Not set when the global limit was
+	/// hit, since the wait applies to the global bucket instead.
+	#[serde(default)]
+	pub retry_after: Option<u64>,
 }
 
 fn get_header<T: FromStr>(headers: &HeaderMap, key: &str) -> Option<T> {
@@ -45,9 +258,18 @@ impl<'a, E> From<std::result::Result<&'a Response, E>> for RatelimitInfo {
 		match r {
 			Ok(r) => {
 				let headers = r.headers();
+				let global = get_header(headers, "x-ratelimit-global").unwrap_or(false);
 				Self {
 					limit: get_header(headers, "x-ratelimit-limit"),
+					// global limits don't send the reset for any bucket, only how long to wait
 					resets_in: get_header(headers, "x-ratelimit-reset-after")
+						.or_else(|| get_header(headers, "retry-after"))
+						.map(|r: f64| (r * 1000.) as u64),
+					remaining: get_header(headers, "x-ratelimit-remaining"),
+					global,
+					bucket: get_header(headers, "x-ratelimit-bucket"),
+					retry_after: get_header(headers, "retry-after")
+						.filter(|_| !global)
 						.map(|r: f64| (r * 1000.) as u64),
 				}
 			}
@@ -58,7 +280,7 @@ impl<'a, E> From<std::result::Result<&'a Response, E>> for RatelimitInfo {
 
 #[cfg(test)]
 mod test {
-	use super::{RatelimitInfo, Ratelimiter};
+	use super::{ClaimTimedOut, RatelimitInfo, Ratelimiter, Throttled, GLOBAL_WINDOW};
 	use anyhow::{anyhow, Result};
 	use futures::TryFutureExt;
 	use std::{
@@ -69,7 +291,7 @@ mod test {
 		time::{Duration, SystemTime},
 	};
 	use tokio::{
-		time::{sleep, timeout},
+		time::{sleep, timeout, Instant},
 		try_join,
 	};
 
@@ -131,8 +353,8 @@ mod test {
 			.release(
 				"foo2".into(),
 				RatelimitInfo {
-					limit: None,
 					resets_in: Some(5000),
+					..Default::default()
 				},
 			)
 			.await?;
@@ -145,6 +367,40 @@ mod test {
 		Ok(())
 	}
 
+	pub async fn reset_bucket(client: Arc<impl Ratelimiter + Sync>) -> Result<()> {
+		claim_timeout(client.clone(), "baz1", 0, 50).await?;
+		assert!(
+			timeout(Duration::from_millis(100), client.claim("baz1".into()))
+				.await
+				.is_err()
+		);
+
+		client.reset_bucket("baz1".into()).await?;
+		claim_timeout(client, "baz1", 0, 50).await
+	}
+
+	pub async fn reset_ignores_stale_release(client: Arc<impl Ratelimiter + Sync>) -> Result<()> {
+		client.claim("baz2".into()).await?;
+		client.reset_bucket("baz2".into()).await?;
+		client.claim("baz2".into()).await?;
+
+		// the claim from before the reset doesn't free up the new bucket
+		client
+			.release("baz2".into(), RatelimitInfo::default())
+			.await?;
+		assert!(
+			timeout(Duration::from_millis(100), client.claim("baz2".into()))
+				.await
+				.is_err()
+		);
+
+		client
+			.release("baz2".into(), RatelimitInfo::default())
+			.await?;
+		timeout(Duration::from_millis(50), client.claim("baz2".into())).await??;
+		Ok(())
+	}
+
 	pub async fn claim_3x(client: Arc<impl Ratelimiter>) -> Result<()> {
 		let claims = claim_timeout(client.clone(), "foo3", 0, 50)
 			.and_then(|_| claim_timeout(client.clone(), "foo3", 5000, 5050))
@@ -155,13 +411,7 @@ mod test {
 				sleep(Duration::from_secs(5)).await;
 				client
 					.clone()
-					.release(
-						"foo3".into(),
-						RatelimitInfo {
-							limit: None,
-							resets_in: None,
-						},
-					)
+					.release("foo3".into(), RatelimitInfo::default())
 					.await?;
 			}
 
@@ -179,7 +429,7 @@ mod test {
 				"foo4".into(),
 				RatelimitInfo {
 					limit: Some(2),
-					resets_in: None,
+					..Default::default()
 				},
 			)
 			.await?;
@@ -205,7 +455,7 @@ mod test {
 						"foo4".into(),
 						RatelimitInfo {
 							limit: Some(2),
-							resets_in: None,
+							..Default::default()
 						},
 					)
 					.await?;
@@ -228,6 +478,7 @@ mod test {
 				RatelimitInfo {
 					limit: Some(2),
 					resets_in: Some(5000),
+					..Default::default()
 				},
 			)
 			.await?;
@@ -255,6 +506,7 @@ mod test {
 				RatelimitInfo {
 					limit: Some(2),
 					resets_in: Some(5000),
+					..Default::default()
 				},
 			)
 			.await?;
@@ -271,6 +523,7 @@ mod test {
 				RatelimitInfo {
 					limit: Some(2),
 					resets_in: Some(4000),
+					..Default::default()
 				},
 			)
 			.await?;
@@ -282,4 +535,252 @@ mod test {
 
 		Ok(())
 	}
+
+	pub async fn claim_remaining_zero(client: Arc<impl Ratelimiter>) -> Result<()> {
+		claim_timeout(client.clone(), "foo7", 0, 50).await?;
+
+		let start = SystemTime::now();
+		client
+			.clone()
+			.release(
+				"foo7".into(),
+				RatelimitInfo {
+					limit: Some(5),
+					resets_in: Some(1000),
+					remaining: Some(0),
+					..Default::default()
+				},
+			)
+			.await?;
+
+		let min = Duration::from_secs(1) - SystemTime::now().duration_since(start)?;
+		let min = min.as_millis() as u64;
+		claim_timeout(client.clone(), "foo7", min, min + 50).await?;
+		claim_timeout(client, "foo7", 0, 50).await
+	}
+
+	pub async fn claim_retry_after(client: Arc<impl Ratelimiter>) -> Result<()> {
+		claim_timeout(client.clone(), "foo8", 0, 50).await?;
+
+		let start = SystemTime::now();
+		client
+			.clone()
+			.release(
+				"foo8".into(),
+				RatelimitInfo {
+					limit: Some(5),
+					resets_in: Some(100),
+					remaining: Some(4),
+					retry_after: Some(1000),
+					..Default::default()
+				},
+			)
+			.await?;
+
+		let min = Duration::from_secs(1) - SystemTime::now().duration_since(start)?;
+		let min = min.as_millis() as u64;
+		claim_timeout(client.clone(), "foo8", min, min + 50).await?;
+		claim_timeout(client, "foo8", 0, 50).await
+	}
+
+	pub async fn claim_all_overlapping(client: Arc<impl Ratelimiter + Sync>) -> Result<()> {
+		let deadline = Instant::now() + Duration::from_secs(1);
+
+		try_join!(
+			async {
+				client
+					.claim_all(vec!["bar1".into(), "bar2".into()], false, Some(deadline))
+					.await?;
+				sleep(Duration::from_millis(50)).await;
+				client
+					.release("bar1".into(), RatelimitInfo::default())
+					.await?;
+				client
+					.release("bar2".into(), RatelimitInfo::default())
+					.await
+			},
+			async {
+				client
+					.claim_all(vec!["bar2".into(), "bar1".into()], false, Some(deadline))
+					.await?;
+				client
+					.release("bar2".into(), RatelimitInfo::default())
+					.await?;
+				client
+					.release("bar1".into(), RatelimitInfo::default())
+					.await
+			},
+		)?;
+
+		Ok(())
+	}
+
+	pub async fn claim_all_timeout_release(client: Arc<impl Ratelimiter + Sync>) -> Result<()> {
+		claim_timeout(client.clone(), "bar4", 0, 50).await?;
+
+		let deadline = Instant::now() + Duration::from_millis(100);
+		let res = client
+			.claim_all(vec!["bar3".into(), "bar4".into()], false, Some(deadline))
+			.await;
+		assert!(res.unwrap_err().is::<ClaimTimedOut>());
+
+		claim_timeout(client, "bar3", 0, 50).await
+	}
+
+	pub async fn unclaim_keeps_timeout(client: Arc<impl Ratelimiter + Sync>) -> Result<()> {
+		claim_timeout(client.clone(), "bar5", 0, 50).await?;
+		client
+			.release(
+				"bar5".into(),
+				RatelimitInfo {
+					limit: Some(2),
+					resets_in: Some(1000),
+					remaining: Some(1),
+					..Default::default()
+				},
+			)
+			.await?;
+
+		// one claim goes unused while the other's response exhausts the bucket
+		claim_timeout(client.clone(), "bar5", 0, 50).await?;
+		claim_timeout(client.clone(), "bar5", 0, 50).await?;
+		let start = Instant::now();
+		client
+			.release(
+				"bar5".into(),
+				RatelimitInfo {
+					resets_in: Some(1000),
+					remaining: Some(0),
+					..Default::default()
+				},
+			)
+			.await?;
+		client.unclaim("bar5".into()).await?;
+
+		let min = Duration::from_millis(900).saturating_sub(start.elapsed());
+		claim_timeout(client, "bar5", min.as_millis() as u64, 1100).await
+	}
+
+	/// Expects the client to have a global limit of 5.
+	pub async fn claim_global_limit(client: Arc<impl Ratelimiter + Sync>) -> Result<()> {
+		let start = Instant::now();
+		for _ in 0..5 {
+			timeout(Duration::from_millis(50), client.claim_global()).await??;
+		}
+
+		timeout(Duration::from_millis(1050), client.claim_global()).await??;
+		assert!(start.elapsed() >= GLOBAL_WINDOW);
+		Ok(())
+	}
+
+	pub async fn release_global(client: Arc<impl Ratelimiter + Sync>) -> Result<()> {
+		client.claim_global().await?;
+		client.release_global(RatelimitInfo::default()).await?;
+		timeout(Duration::from_millis(50), client.claim_global()).await??;
+
+		let start = Instant::now();
+		client
+			.release_global(RatelimitInfo {
+				resets_in: Some(1500),
+				global: true,
+				..Default::default()
+			})
+			.await?;
+		timeout(Duration::from_millis(1550), client.claim_global()).await??;
+		assert!(start.elapsed() >= Duration::from_millis(1500));
+		Ok(())
+	}
+
+	#[test]
+	fn records_throttle_transitions() {
+		let throttled = Throttled::default();
+		let transitions = [
+			("a", false),
+			("a", true),
+			("a", true),
+			("b", true),
+			("a", false),
+			("a", false),
+			("b", false),
+		]
+		.iter()
+		.map(|(bucket, waited)| throttled.record(bucket, *waited))
+		.collect::<Vec<_>>();
+
+		assert_eq!(transitions, [false, true, false, true, true, false, true]);
+	}
+
+	#[test]
+	fn ignores_retry_after_for_global_limit() {
+		let info = |global: &str| {
+			let res = http::Response::builder()
+				.status(429)
+				.header("retry-after", "1.5")
+				.header("x-ratelimit-global", global)
+				.body("")
+				.unwrap();
+			RatelimitInfo::from(Ok::<_, ()>(&reqwest::Response::from(res)))
+		};
+
+		let bucket = info("false");
+		assert_eq!(bucket.retry_after, Some(1500));
+		assert_eq!(bucket.resets_in, Some(1500));
+
+		let global = info("true");
+		assert!(global.global);
+		assert_eq!(global.retry_after, None);
+		assert_eq!(global.resets_in, Some(1500));
+	}
+
+	#[cfg(feature = "metrics")]
+	pub async fn watched_bucket_throttled(
+		client: Arc<impl Ratelimiter + Sync>,
+		bucket: &str,
+	) -> Result<()> {
+		use crate::metrics::BUCKET_THROTTLED;
+
+		claim_timeout(client.clone(), bucket, 0, 50).await?;
+		assert_eq!(BUCKET_THROTTLED.with_label_values(&["", bucket]).get(), 0);
+
+		// the next claim waits for the release
+		try_join!(claim_timeout(client.clone(), bucket, 100, 200), async {
+			sleep(Duration::from_millis(100)).await;
+			client
+				.release(bucket.into(), RatelimitInfo::default())
+				.await
+		})?;
+		assert_eq!(BUCKET_THROTTLED.with_label_values(&["", bucket]).get(), 1);
+
+		client
+			.release(bucket.into(), RatelimitInfo::default())
+			.await?;
+		claim_timeout(client.clone(), bucket, 0, 50).await?;
+		assert_eq!(BUCKET_THROTTLED.with_label_values(&["", bucket]).get(), 0);
+
+		Ok(())
+	}
+
+	#[cfg(feature = "metrics")]
+	pub async fn watched_bucket_metrics(client: Arc<impl Ratelimiter>, bucket: &str) -> Result<()> {
+		use crate::metrics::{BUCKET_REMAINING, BUCKET_RESET};
+
+		claim_timeout(client.clone(), bucket, 0, 50).await?;
+		assert_eq!(BUCKET_REMAINING.with_label_values(&["", bucket]).get(), 0);
+
+		client
+			.clone()
+			.release(
+				bucket.into(),
+				RatelimitInfo {
+					resets_in: Some(5000),
+					..Default::default()
+				},
+			)
+			.await?;
+		assert_eq!(BUCKET_REMAINING.with_label_values(&["", bucket]).get(), 1);
+		let reset = BUCKET_RESET.with_label_values(&["", bucket]).get();
+		assert!(reset > 4. && reset <= 5., "reset in {}s", reset);
+
+		Ok(())
+	}
 }
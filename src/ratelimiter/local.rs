@@ -1,4 +1,4 @@
-use super::{RatelimitInfo, Ratelimiter};
+use super::{RatelimitInfo, Ratelimiter, Throttled, WatchedBuckets, GLOBAL_LIMIT, GLOBAL_WINDOW};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use std::{
@@ -11,10 +11,7 @@ use std::{
 };
 use tokio::{
 	select, spawn,
-	sync::{
-		mpsc::{self, Sender},
-		Mutex, RwLock, Semaphore,
-	},
+	sync::{watch, Mutex, RwLock, Semaphore, TryAcquireError},
 	time::{sleep, sleep_until, Duration, Instant},
 };
 use tracing::{debug, instrument};
@@ -22,8 +19,13 @@ use tracing::{debug, instrument};
 #[derive(Debug)]
 struct Bucket {
 	ready: Semaphore,
-	new_timeout: Mutex<Option<Sender<Instant>>>,
+	new_timeout: Mutex<Option<watch::Sender<Instant>>>,
 	size: AtomicUsize,
+	/// Claims which haven't been released yet.
+	claimed: AtomicUsize,
+	/// Claims of the bucket this one replaced when it was reset, which haven't been released yet.
+	/// Their permits came from the old bucket, so they aren't returned to this one.
+	stale: AtomicUsize,
 }
 
 impl Default for Bucket {
@@ -32,13 +34,96 @@ impl Default for Bucket {
 			ready: Semaphore::new(1),
 			new_timeout: Default::default(),
 			size: AtomicUsize::new(1),
+			claimed: AtomicUsize::new(0),
+			stale: AtomicUsize::new(0),
+		}
+	}
+}
+
+impl Bucket {
+	/// Record that a claim is being released, returning whether it was a claim of a bucket this
+	/// one replaced.
+	fn release_claim(&self) -> bool {
+		let decrement = |count: usize| count.checked_sub(1);
+		if self
+			.stale
+			.fetch_update(Ordering::SeqCst, Ordering::SeqCst, decrement)
+			.is_ok()
+		{
+			return true;
+		}
+
+		let _ = self
+			.claimed
+			.fetch_update(Ordering::SeqCst, Ordering::SeqCst, decrement);
+		false
+	}
+
+	/// Record the state of the bucket, if it's watched.
+	async fn record(&self, name: &str, watched: &WatchedBuckets) {
+		if !watched.contains(name) {
+			return;
+		}
+
+		let resets_in = match &*self.new_timeout.lock().await {
+			Some(sender) => sender.borrow().saturating_duration_since(Instant::now()),
+			None => Duration::ZERO,
+		};
+		watched.record(name, self.ready.available_permits() as i64, resets_in);
+	}
+}
+
+/// A fixed window of requests across every bucket.
+#[derive(Debug)]
+struct Global {
+	limit: u32,
+	state: Mutex<GlobalState>,
+}
+
+#[derive(Debug)]
+struct GlobalState {
+	used: u32,
+	resets_at: Instant,
+}
+
+impl Global {
+	fn new(limit: u32) -> Self {
+		Self {
+			limit,
+			state: Mutex::new(GlobalState {
+				used: 0,
+				resets_at: Instant::now(),
+			}),
 		}
 	}
 }
 
+impl Default for Global {
+	fn default() -> Self {
+		Self::new(GLOBAL_LIMIT)
+	}
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct LocalRatelimiter {
 	buckets: Arc<RwLock<HashMap<String, Arc<Bucket>>>>,
+	global: Arc<Global>,
+	watched: WatchedBuckets,
+	throttled: Throttled,
+}
+
+impl LocalRatelimiter {
+	/// Export the state of the given buckets as metrics.
+	pub fn with_watched(mut self, watched: WatchedBuckets) -> Self {
+		self.watched = watched;
+		self
+	}
+
+	/// Allow this many requests per second across every bucket.
+	pub fn with_global_limit(mut self, limit: u32) -> Self {
+		self.global = Arc::new(Global::new(limit));
+		self
+	}
 }
 
 #[async_trait]
@@ -46,11 +131,32 @@ impl Ratelimiter for LocalRatelimiter {
 	#[instrument(level = "debug")]
 	async fn claim(&self, bucket_name: String) -> Result<()> {
 		let buckets = Arc::clone(&self.buckets);
-		let mut claim = buckets.write().await;
-		let bucket = Arc::clone(claim.entry(bucket_name.clone()).or_default());
-		drop(claim);
+		let mut waited = false;
+		loop {
+			let mut claim = buckets.write().await;
+			let bucket = Arc::clone(claim.entry(bucket_name.clone()).or_default());
+			drop(claim);
+
+			// the semaphore is only closed when the bucket is reset, so claim the new bucket instead
+			let acquired = match bucket.ready.try_acquire() {
+				Ok(permit) => Some(permit),
+				Err(TryAcquireError::NoPermits) => {
+					waited = true;
+					bucket.ready.acquire().await.ok()
+				}
+				Err(TryAcquireError::Closed) => None,
+			};
+			if let Some(permit) = acquired {
+				permit.forget();
+				bucket.claimed.fetch_add(1, Ordering::SeqCst);
+				bucket.record(&bucket_name, &self.watched).await;
+				break;
+			}
+		}
 
-		bucket.ready.acquire().await?.forget();
+		if self.throttled.record(&bucket_name, waited) {
+			self.watched.record_throttled(&bucket_name, waited);
+		}
 
 		debug!("Acquired lock for \"{}\"", &bucket_name);
 		Ok(())
@@ -73,12 +179,18 @@ impl Ratelimiter for LocalRatelimiter {
 
 		let mut maybe_sender = bucket.new_timeout.lock().await;
 
-		if let None = &*maybe_sender {
+		if bucket.release_claim() {
+			debug!(
+				"\"{}\" was reset since it was claimed: not releasing",
+				&bucket_name
+			);
+		} else if let None = &*maybe_sender {
 			debug!("No timeout: releasing \"{}\" immediately", &bucket_name);
 			bucket.ready.add_permits(1);
 		}
 
-		if let Some(resets_in) = info.resets_in {
+		// a 429 holds the bucket closed for as long as Discord said to wait, even past its reset
+		if let Some(resets_in) = info.resets_in.max(info.retry_after) {
 			let duration = Duration::from_millis(resets_in);
 
 			debug!(
@@ -89,26 +201,34 @@ impl Ratelimiter for LocalRatelimiter {
 			match &mut *maybe_sender {
 				Some(sender) => {
 					debug!("Resetting expiration for \"{}\"", &bucket_name);
-					sender.send(now + duration).await?;
+					let _ = sender.send(now + duration);
 				}
 				None => {
 					debug!("Creating new expiration for \"{}\"", &bucket_name);
 					let mut delay = sleep(duration);
-					let (sender, mut receiver) = mpsc::channel(1);
+					let (sender, mut receiver) = watch::channel(now + duration);
 					let timeout_bucket = Arc::clone(&bucket);
 					let bucket_name = bucket_name.clone();
 					spawn(async move {
 						loop {
 							select! {
-								Some(new_instant) = receiver.recv() => {
+								Ok(()) = receiver.changed() => {
+									let new_instant = *receiver.borrow();
 									debug!("Updating timeout for \"{}\" to {:?}", &bucket_name, new_instant);
 									delay = sleep_until(new_instant);
 								},
 								_ = delay => {
+									// a release may have reset the expiration while this was waiting for the lock
+									let mut new_timeout = timeout_bucket.new_timeout.lock().await;
+									if receiver.has_changed().unwrap_or(false) {
+										delay = sleep_until(*receiver.borrow_and_update());
+										continue;
+									}
+
 									debug!("Releasing \"{}\" after timeout", &bucket_name);
 									let size = timeout_bucket.size.load(Ordering::SeqCst);
 									timeout_bucket.ready.add_permits(size);
-									*timeout_bucket.new_timeout.lock().await = None;
+									*new_timeout = None;
 									break;
 								}
 							}
@@ -121,14 +241,109 @@ impl Ratelimiter for LocalRatelimiter {
 
 		if let Some(size) = info.limit {
 			let old_size = bucket.size.swap(size, Ordering::SeqCst);
-			let diff = size - old_size;
 			debug!(
-				"New bucket size for \"{}\": {} (changing permits by {})",
-				&bucket_name, size, diff
+				"New bucket size for \"{}\": {} (was {})",
+				&bucket_name, size, old_size
 			);
-			bucket.ready.add_permits(diff);
+			match size.checked_sub(old_size) {
+				Some(grown) => bucket.ready.add_permits(grown),
+				// take back as many of the permits the bucket no longer has as aren't claimed
+				None => {
+					let shrunk = old_size - size;
+					let taken = shrunk.min(bucket.ready.available_permits()) as u32;
+					if let Ok(permits) = bucket.ready.try_acquire_many(taken) {
+						permits.forget();
+					}
+				}
+			}
+		}
+
+		// Discord says the bucket is exhausted, so hold it closed until the pending reset
+		if (info.remaining == Some(0) || info.retry_after.is_some()) && maybe_sender.is_some() {
+			let available = bucket.ready.available_permits() as u32;
+			if let Ok(permits) = bucket.ready.try_acquire_many(available) {
+				debug!("\"{}\" is exhausted: closing until reset", &bucket_name);
+				permits.forget();
+			}
+		}
+
+		drop(maybe_sender);
+		bucket.record(&bucket_name, &self.watched).await;
+		Ok(())
+	}
+
+	#[instrument(level = "debug")]
+	async fn unclaim(&self, bucket_name: String) -> Result<()> {
+		let bucket = Arc::clone(
+			self.buckets
+				.read()
+				.await
+				.get(&bucket_name)
+				.ok_or(anyhow!("Attempted to unclaim before claim"))?,
+		);
+
+		// a bucket which is held closed gets every claim back once it resets
+		let new_timeout = bucket.new_timeout.lock().await;
+		if !bucket.release_claim() && new_timeout.is_none() {
+			debug!("Giving back unused claim of \"{}\"", &bucket_name);
+			bucket.ready.add_permits(1);
+		}
+
+		drop(new_timeout);
+		bucket.record(&bucket_name, &self.watched).await;
+		Ok(())
+	}
+
+	#[instrument(level = "debug")]
+	async fn reset_bucket(&self, bucket_name: String) -> Result<()> {
+		debug!("Resetting \"{}\"", &bucket_name);
+
+		let mut buckets = self.buckets.write().await;
+		let bucket = Bucket::default();
+		if let Some(old) = buckets.get(&bucket_name) {
+			old.ready.close();
+			let stale = old.claimed.load(Ordering::SeqCst) + old.stale.load(Ordering::SeqCst);
+			bucket.stale.store(stale, Ordering::SeqCst);
+		}
+		buckets.insert(bucket_name, Arc::new(bucket));
+
+		Ok(())
+	}
+
+	#[instrument(level = "debug")]
+	async fn claim_global(&self) -> Result<()> {
+		loop {
+			let resets_at = {
+				let mut state = self.global.state.lock().await;
+				let now = Instant::now();
+				if now >= state.resets_at {
+					state.used = 0;
+					state.resets_at = now + GLOBAL_WINDOW;
+				}
+
+				if state.used < self.global.limit {
+					state.used += 1;
+					return Ok(());
+				}
+				state.resets_at
+			};
+
+			debug!("Global limit reached: waiting until {:?}", resets_at);
+			sleep_until(resets_at).await;
 		}
+	}
+
+	#[instrument(level = "debug")]
+	async fn release_global(&self, info: RatelimitInfo) -> Result<()> {
+		let resets_in = match info.resets_in.filter(|_| info.global) {
+			Some(resets_in) => Duration::from_millis(resets_in),
+			None => return Ok(()),
+		};
 
+		debug!("Global limit hit: closing for {:?}", resets_in);
+		let mut state = self.global.state.lock().await;
+		state.used = self.global.limit;
+		state.resets_at = state.resets_at.max(Instant::now() + resets_in);
 		Ok(())
 	}
 }
@@ -139,8 +354,12 @@ mod test {
 
 	use anyhow::Result;
 	use test_log::test;
+	use tokio::time::{timeout, Duration};
 
-	use super::{super::test, LocalRatelimiter};
+	use super::{
+		super::{test, RatelimitInfo, Ratelimiter},
+		LocalRatelimiter,
+	};
 
 	fn get_client() -> Arc<LocalRatelimiter> {
 		Default::default()
@@ -175,4 +394,91 @@ mod test {
 	async fn claim_limit_release_timeout() -> Result<()> {
 		test::claim_limit_release_timeout(get_client()).await
 	}
+
+	#[test(tokio::test)]
+	async fn claim_all_overlapping() -> Result<()> {
+		test::claim_all_overlapping(get_client()).await
+	}
+
+	#[test(tokio::test)]
+	async fn claim_all_timeout_release() -> Result<()> {
+		test::claim_all_timeout_release(get_client()).await
+	}
+
+	#[test(tokio::test)]
+	async fn shrinks_bucket() -> Result<()> {
+		let client = get_client();
+		client.claim("baz3".into()).await?;
+		let grown = RatelimitInfo {
+			limit: Some(3),
+			..Default::default()
+		};
+		client.release("baz3".into(), grown).await?;
+		client.claim("baz3".into()).await?;
+
+		let shrunk = RatelimitInfo {
+			limit: Some(1),
+			..Default::default()
+		};
+		client.release("baz3".into(), shrunk).await?;
+		timeout(Duration::from_millis(50), client.claim("baz3".into())).await??;
+		assert!(
+			timeout(Duration::from_millis(100), client.claim("baz3".into()))
+				.await
+				.is_err()
+		);
+		Ok(())
+	}
+
+	#[test(tokio::test)]
+	async fn unclaim_keeps_timeout() -> Result<()> {
+		test::unclaim_keeps_timeout(get_client()).await
+	}
+
+	#[test(tokio::test)]
+	async fn reset_bucket() -> Result<()> {
+		test::reset_bucket(get_client()).await
+	}
+
+	#[test(tokio::test)]
+	async fn reset_ignores_stale_release() -> Result<()> {
+		test::reset_ignores_stale_release(get_client()).await
+	}
+
+	#[test(tokio::test)]
+	async fn claim_remaining_zero() -> Result<()> {
+		test::claim_remaining_zero(get_client()).await
+	}
+
+	#[test(tokio::test)]
+	async fn claim_retry_after() -> Result<()> {
+		test::claim_retry_after(get_client()).await
+	}
+
+	#[test(tokio::test)]
+	async fn claim_global_limit() -> Result<()> {
+		let client = LocalRatelimiter::default().with_global_limit(5);
+		test::claim_global_limit(Arc::new(client)).await
+	}
+
+	#[test(tokio::test)]
+	async fn release_global() -> Result<()> {
+		test::release_global(get_client()).await
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test(tokio::test)]
+	async fn watched_bucket_metrics() -> Result<()> {
+		let client = LocalRatelimiter::default()
+			.with_watched(super::WatchedBuckets::new(vec!["local_qux1".to_string()]));
+		test::watched_bucket_metrics(Arc::new(client), "local_qux1").await
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test(tokio::test)]
+	async fn watched_bucket_throttled() -> Result<()> {
+		let client = LocalRatelimiter::default()
+			.with_watched(super::WatchedBuckets::new(vec!["local_qux2".to_string()]));
+		test::watched_bucket_throttled(Arc::new(client), "local_qux2").await
+	}
 }
@@ -1,18 +1,242 @@
-use super::{RatelimitInfo, Ratelimiter};
-use anyhow::Result;
+use super::{RatelimitInfo, Ratelimiter, Throttled, WatchedBuckets, GLOBAL_LIMIT, GLOBAL_WINDOW};
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use futures::TryStreamExt;
 use lazy_static::lazy_static;
-use redust::{model::pubsub, pool::Pool, resp::from_data, script::Script};
-use std::{fmt::Debug, str::from_utf8, time::Duration};
-use tokio::{net::ToSocketAddrs, time::sleep};
-use tracing::{debug, instrument};
+use redust::{
+	model::pubsub,
+	pool::{deadpool::managed::Object, Manager, Pool},
+	resp::from_data,
+	script::Script,
+};
+use std::{
+	collections::{HashMap, VecDeque},
+	fmt::Debug,
+	future::pending,
+	mem::drop,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+use tokio::{
+	net::ToSocketAddrs,
+	select, spawn,
+	sync::{
+		broadcast::{self, error::RecvError},
+		oneshot,
+	},
+	task::JoinHandle,
+	time::{sleep, sleep_until, timeout_at, Instant},
+};
+use tracing::{debug, instrument, warn};
 
 static NOTIFY_KEY: &'static str = "rest_ready";
+static GLOBAL_KEY: &str = "global";
+/// Sent to waiting claims when notifications might have been missed, so that they check their
+/// bucket again.
+static MISSED: &str = "";
+/// Claims waiting on a release check their bucket again this often, in case its notification
+/// was lost.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 lazy_static! {
-	static ref CLAIM_SCRIPT: Script<2> = Script::new(include_bytes!("./scripts/claim.lua"));
-	static ref RELEASE_SCRIPT: Script<3> = Script::new(include_bytes!("./scripts/release.lua"));
+	static ref CLAIM_SCRIPT: Script<3> = Script::new(include_bytes!("./scripts/claim.lua"));
+	static ref RELEASE_SCRIPT: Script<4> = Script::new(include_bytes!("./scripts/release.lua"));
+	static ref UNCLAIM_SCRIPT: Script<3> = Script::new(include_bytes!("./scripts/unclaim.lua"));
+	static ref RESET_SCRIPT: Script<4> = Script::new(include_bytes!("./scripts/reset.lua"));
+	static ref CLAIM_GLOBAL_SCRIPT: Script<1> =
+		Script::new(include_bytes!("./scripts/claim_global.lua"));
+	static ref RELEASE_GLOBAL_SCRIPT: Script<1> =
+		Script::new(include_bytes!("./scripts/release_global.lua"));
+}
+
+/// Handle to the background task which listens for bucket releases and wakes up pending claims.
+/// The task unsubscribes and returns its connection to the pool once this is dropped.
+#[derive(Debug)]
+struct Subscriber {
+	/// The channel releases are published to.
+	channel: String,
+	lifetime: Option<Duration>,
+	ready: broadcast::Sender<String>,
+	shutdown: Mutex<Option<oneshot::Sender<()>>>,
+	task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Subscriber {
+	fn new<A>(pool: Pool<A>, channel: String, lifetime: Option<Duration>) -> Self
+	where
+		A: ToSocketAddrs + Clone + Send + Sync + Debug + 'static,
+	{
+		let (ready, _) = broadcast::channel(1024);
+		let (shutdown, shutdown_rx) = oneshot::channel();
+		let task = spawn(subscribe(
+			pool,
+			channel.clone(),
+			ready.clone(),
+			shutdown_rx,
+			lifetime,
+		));
+
+		Self {
+			channel,
+			lifetime,
+			ready,
+			shutdown: Mutex::new(Some(shutdown)),
+			task: Mutex::new(Some(task)),
+		}
+	}
+
+	fn stop(&self) -> Option<JoinHandle<()>> {
+		drop(self.shutdown.lock().unwrap().take());
+		self.task.lock().unwrap().take()
+	}
+}
+
+impl Drop for Subscriber {
+	fn drop(&mut self) {
+		// the task is signalled rather than aborted so that it can unsubscribe before its
+		// connection goes back into the pool
+		self.stop();
+	}
+}
+
+/// Get a connection from the pool and subscribe it to bucket releases.
+async fn connect<A>(pool: &Pool<A>, channel: &str) -> Result<Object<Manager<A>>>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug,
+{
+	let mut conn = pool
+		.get()
+		.await
+		.map_err(|e| anyhow!("Unable to get subscriber connection: {:?}", e))?;
+	conn.cmd(["SUBSCRIBE", channel]).await?;
+	Ok(conn)
+}
+
+async fn unsubscribe<A>(conn: &mut Object<Manager<A>>, channel: &str)
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug,
+{
+	if let Err(e) = conn.cmd(["UNSUBSCRIBE", channel]).await {
+		warn!("Unable to unsubscribe from \"{}\": {:?}", channel, e);
+	}
+}
+
+/// Wait until the subscriber's connection should be rotated, if it ever should.
+async fn rotation(at: Option<Instant>) {
+	match at {
+		Some(at) => sleep_until(at).await,
+		None => pending().await,
+	}
+}
+
+async fn subscribe<A>(
+	pool: Pool<A>,
+	channel: String,
+	ready: broadcast::Sender<String>,
+	mut shutdown: oneshot::Receiver<()>,
+	lifetime: Option<Duration>,
+) where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug,
+{
+	let mut resubscribed = false;
+	loop {
+		let mut conn = select! {
+			conn = connect(&pool, &channel) => match conn {
+				Ok(conn) => conn,
+				Err(e) => {
+					warn!("Unable to subscribe to \"{}\": {:?}", channel, e);
+					sleep(Duration::from_secs(1)).await;
+					continue;
+				}
+			},
+			_ = &mut shutdown => return,
+		};
+
+		// releases while there was no subscription weren't notified
+		if resubscribed {
+			let _ = ready.send(MISSED.to_string());
+		}
+		resubscribed = true;
+
+		let mut rotate_at = lifetime.map(|lifetime| Instant::now() + lifetime);
+		loop {
+			select! {
+				data = conn.try_next() => match data {
+					Ok(Some(data)) => match from_data::<pubsub::Response>(data) {
+						Ok(pubsub::Response::Message(msg)) => {
+							let bucket = String::from_utf8_lossy(&msg.data).into_owned();
+							debug!("Received ready notification for \"{}\"", bucket);
+							let _ = ready.send(bucket);
+						}
+						Ok(_) => {}
+						Err(e) => warn!("Unable to parse subscriber message: {:?}", e),
+					},
+					Ok(None) => break,
+					Err(e) => {
+						warn!("Subscriber connection failed: {:?}", e);
+						break;
+					}
+				},
+				_ = rotation(rotate_at) => {
+					// the new connection subscribes before the old one unsubscribes, so releases
+					// are notified on at least one of them
+					match connect(&pool, &channel).await {
+						Ok(new_conn) => {
+							unsubscribe(&mut conn, &channel).await;
+							conn = new_conn;
+							debug!("Rotated subscriber connection");
+
+							// notifications still buffered on the old connection are dropped with it
+							let _ = ready.send(MISSED.to_string());
+						}
+						Err(e) => warn!("Unable to rotate subscriber connection: {:?}", e),
+					}
+					rotate_at = lifetime.map(|lifetime| Instant::now() + lifetime);
+				}
+				_ = &mut shutdown => {
+					unsubscribe(&mut conn, &channel).await;
+					return;
+				}
+			}
+		}
+	}
+}
+
+/// The key counting how many times the bucket has been reset.
+fn generation_key(bucket: &str) -> String {
+	bucket.to_string() + "_generation"
+}
+
+/// The generations of the buckets claimed through this ratelimiter, oldest first, so that their
+/// releases can be ignored if the bucket was reset since.
+#[derive(Clone, Debug, Default)]
+struct Claims(Arc<Mutex<HashMap<String, VecDeque<i64>>>>);
+
+impl Claims {
+	fn push(&self, bucket: &str, generation: i64) {
+		self.0
+			.lock()
+			.unwrap()
+			.entry(bucket.to_string())
+			.or_default()
+			.push_back(generation);
+	}
+
+	/// Take the generation of the oldest claim of the bucket, or an empty string if there isn't
+	/// one, in which case the release applies to the current bucket.
+	fn pop(&self, bucket: &str) -> String {
+		let mut claims = self.0.lock().unwrap();
+		let generations = match claims.get_mut(bucket) {
+			Some(generations) => generations,
+			None => return String::new(),
+		};
+
+		let generation = generations.pop_front();
+		if generations.is_empty() {
+			claims.remove(bucket);
+		}
+		generation.map_or_else(String::new, |generation| generation.to_string())
+	}
 }
 
 #[derive(Clone, Debug)]
@@ -21,6 +245,21 @@ where
 	A: ToSocketAddrs + Clone + Send + Sync + Debug,
 {
 	redis: Pool<A>,
+	/// A read replica of `redis`, which read-only checks are sent to instead. Mutations always go
+	/// to `redis`.
+	replica: Option<Pool<A>>,
+	/// Wakes claims once the bucket they're waiting on is released. Claims poll instead if this
+	/// isn't set.
+	subscriber: Option<Arc<Subscriber>>,
+	poll_interval: Duration,
+	/// Requests allowed per `GLOBAL_WINDOW` across every bucket and every instance sharing
+	/// `redis`.
+	global_limit: u32,
+	watched: WatchedBuckets,
+	throttled: Throttled,
+	claims: Claims,
+	/// Prepended to every key, so that ratelimiters sharing `redis` can keep separate buckets.
+	prefix: String,
 }
 
 impl<A> RedisRatelimiter<A>
@@ -28,68 +267,445 @@ where
 	A: ToSocketAddrs + Clone + Send + Sync + Debug + 'static,
 {
 	pub fn new(pool: Pool<A>) -> Self {
-		Self { redis: pool }
+		Self {
+			subscriber: Some(Arc::new(Subscriber::new(
+				pool.clone(),
+				NOTIFY_KEY.to_string(),
+				None,
+			))),
+			redis: pool,
+			replica: None,
+			poll_interval: Duration::ZERO,
+			global_limit: GLOBAL_LIMIT,
+			watched: WatchedBuckets::default(),
+			throttled: Throttled::default(),
+			claims: Claims::default(),
+			prefix: String::new(),
+		}
+	}
+
+	/// Create a ratelimiter which doesn't use pub/sub, for where it's unreliable or disabled.
+	/// Claims waiting on a release poll the bucket on the interval instead of being notified.
+	pub fn polling(pool: Pool<A>, interval: Duration) -> Self {
+		Self {
+			subscriber: None,
+			redis: pool,
+			replica: None,
+			poll_interval: interval,
+			global_limit: GLOBAL_LIMIT,
+			watched: WatchedBuckets::default(),
+			throttled: Throttled::default(),
+			claims: Claims::default(),
+			prefix: String::new(),
+		}
+	}
+
+	/// Send read-only checks to a read replica, reducing the load on the primary.
+	pub fn with_replica(mut self, replica: Pool<A>) -> Self {
+		self.replica = Some(replica);
+		self
+	}
+
+	/// Replace the subscriber's connection with a new one this often, for servers or networks
+	/// which don't cope with long-lived connections. Ratelimiters which poll have no subscriber.
+	pub fn with_subscriber_lifetime(mut self, lifetime: Duration) -> Self {
+		if let Some(subscriber) = &self.subscriber {
+			self.subscriber = Some(Arc::new(Subscriber::new(
+				self.redis.clone(),
+				subscriber.channel.clone(),
+				Some(lifetime),
+			)));
+		}
+		self
+	}
+
+	/// Prepend the prefix to every key, keeping these buckets and global limit apart from those
+	/// of other ratelimiters sharing Redis.
+	pub fn with_prefix(mut self, prefix: String) -> Self {
+		if let Some(subscriber) = &self.subscriber {
+			self.subscriber = Some(Arc::new(Subscriber::new(
+				self.redis.clone(),
+				prefix.clone() + NOTIFY_KEY,
+				subscriber.lifetime,
+			)));
+		}
+		self.prefix = prefix;
+		self
+	}
+
+	/// Export the state of the given buckets as metrics.
+	pub fn with_watched(mut self, watched: WatchedBuckets) -> Self {
+		self.watched = watched;
+		self
+	}
+
+	/// Allow this many requests per second across every bucket.
+	pub fn with_global_limit(mut self, limit: u32) -> Self {
+		self.global_limit = limit;
+		self
+	}
+
+	/// Stop listening for bucket releases and wait for the subscriber connection to be returned
+	/// to the pool. Claims waiting on a release are no longer woken once this is called, so it
+	/// should only be used when the ratelimiter is done being used.
+	pub async fn shutdown(&self) {
+		if let Some(task) = self
+			.subscriber
+			.as_ref()
+			.and_then(|subscriber| subscriber.stop())
+		{
+			if let Err(e) = task.await {
+				warn!("Subscriber task failed: {:?}", e);
+			}
+		}
+	}
+}
+
+impl<A> RedisRatelimiter<A>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug,
+{
+	/// The pool to send read-only commands to.
+	fn reader(&self) -> &Pool<A> {
+		self.replica.as_ref().unwrap_or(&self.redis)
+	}
+
+	/// The key with this ratelimiter's prefix.
+	fn key(&self, name: &str) -> String {
+		self.prefix.clone() + name
+	}
+
+	/// How long the bucket is closed for according to the replica, if there is one, so claims can
+	/// wait out closed buckets without running the claim script on the primary.
+	async fn closed_for(&self, bucket: &str) -> Result<Option<Duration>> {
+		let replica = match &self.replica {
+			Some(replica) => replica,
+			None => return Ok(None),
+		};
+
+		let key = self.key(bucket);
+		let mut conn = replica.get().await?;
+		let remaining = from_data::<Option<String>>(conn.cmd(["GET", &key]).await?)?
+			.and_then(|remaining| remaining.parse::<i64>().ok());
+		if !matches!(remaining, Some(remaining) if remaining <= 0) {
+			return Ok(None);
+		}
+
+		// buckets which are closed until a release, rather than a reset, have no TTL; claims on
+		// them are left to the primary so they don't miss the release notification
+		let ttl = from_data::<i64>(conn.cmd(["PTTL", &key]).await?)?;
+		Ok((ttl > 0).then(|| Duration::from_millis(ttl as u64)))
+	}
+
+	/// Record the state of the bucket, if it's watched.
+	async fn record(&self, bucket: &str) -> Result<()> {
+		if !self.watched.contains(bucket) {
+			return Ok(());
+		}
+
+		let key = self.key(bucket);
+		let mut conn = self.reader().get().await?;
+		let remaining = from_data::<Option<String>>(conn.cmd(["GET", &key]).await?)?
+			.map(|remaining| remaining.parse::<i64>())
+			.transpose()?
+			.unwrap_or(1);
+		let ttl = from_data::<i64>(conn.cmd(["PTTL", &key]).await?)?;
+		self.watched
+			.record(bucket, remaining, Duration::from_millis(ttl.max(0) as u64));
+
+		Ok(())
+	}
+}
+
+/// Run the claim script, returning how long the bucket is closed for (0 if it was claimed, or
+/// negative if it's closed until a release) and the bucket's generation.
+async fn run_claim<A>(pool: &Pool<A>, bucket: &str) -> Result<(i64, i64)>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug,
+{
+	let mut conn = pool.get().await?;
+	let claimed = CLAIM_SCRIPT
+		.exec(&mut conn)
+		.keys([
+			bucket,
+			&(bucket.to_string() + "_size"),
+			&generation_key(bucket),
+		])
+		.invoke()
+		.await?;
+	Ok(from_data::<(i64, i64)>(claimed)?)
+}
+
+async fn run_release<A>(
+	pool: &Pool<A>,
+	bucket: &str,
+	notify: &str,
+	generation: String,
+	info: &RatelimitInfo,
+) -> Result<()>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug,
+{
+	let mut conn = pool.get().await?;
+	RELEASE_SCRIPT
+		.exec(&mut conn)
+		.keys([
+			bucket,
+			&(bucket.to_string() + "_size"),
+			notify,
+			&generation_key(bucket),
+		])
+		.args(&[
+			info.limit.unwrap_or(0).to_string(),
+			info.resets_in.unwrap_or(0).to_string(),
+			info.remaining
+				.map_or_else(|| "-1".to_string(), |remaining| remaining.to_string()),
+			info.retry_after.unwrap_or(0).to_string(),
+			generation,
+		])
+		.invoke()
+		.await?;
+	Ok(())
+}
+
+async fn run_unclaim<A>(
+	pool: &Pool<A>,
+	bucket: &str,
+	notify: &str,
+	generation: String,
+) -> Result<()>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug,
+{
+	let mut conn = pool.get().await?;
+	UNCLAIM_SCRIPT
+		.exec(&mut conn)
+		.keys([bucket, notify, &generation_key(bucket)])
+		.args([generation])
+		.invoke()
+		.await?;
+	Ok(())
+}
+
+/// A bucket claimed by the claim script, which is given back if this is dropped before the claim
+/// completes, such as when it's cancelled by a timeout.
+struct ClaimedBucket<A>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug + 'static,
+{
+	/// The pool, the bucket's key, and the channel to notify it's given back on.
+	release: Option<(Pool<A>, String, String)>,
+	generation: i64,
+}
+
+impl<A> ClaimedBucket<A>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug + 'static,
+{
+	/// Hold onto the bucket, now that the claim is complete, until it's released with its
+	/// generation.
+	fn keep(mut self, claims: &Claims) {
+		if let Some((_, bucket, _)) = self.release.take() {
+			claims.push(&bucket, self.generation);
+		}
+	}
+}
+
+impl<A> Drop for ClaimedBucket<A>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug + 'static,
+{
+	fn drop(&mut self) {
+		if let Some((pool, bucket, notify)) = self.release.take() {
+			debug!("Claim of \"{}\" was cancelled: giving it back", bucket);
+			let generation = self.generation.to_string();
+			spawn(async move {
+				if let Err(e) = run_unclaim(&pool, &bucket, &notify, generation).await {
+					warn!(
+						"Unable to give back cancelled claim of \"{}\": {:?}",
+						bucket, e
+					);
+				}
+			});
+		}
+	}
+}
+
+impl<A> RedisRatelimiter<A>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug + 'static,
+{
+	/// Run the claim script in its own task, so that it isn't interrupted if the claim is
+	/// cancelled while it runs. The bucket is returned with its expiration if it was claimed, and
+	/// is released again if the claim was cancelled meanwhile.
+	async fn try_claim(&self, bucket: &str) -> Result<(i64, Option<ClaimedBucket<A>>)> {
+		let (tx, rx) = oneshot::channel();
+		let pool = self.redis.clone();
+		let bucket = self.key(bucket);
+		let notify = self.key(NOTIFY_KEY);
+		spawn(async move {
+			let claimed = run_claim(&pool, &bucket)
+				.await
+				.map(|(expiration, generation)| {
+					// constructing the bucket arms its release, so it's only done if it was claimed
+					let claimed = match expiration {
+						0 => Some(ClaimedBucket {
+							release: Some((pool, bucket, notify)),
+							generation,
+						}),
+						_ => None,
+					};
+					(expiration, claimed)
+				});
+			// if the claim was cancelled, the bucket is released as this is dropped
+			let _ = tx.send(claimed);
+		});
+
+		rx.await?
 	}
 }
 
 #[async_trait]
 impl<A> Ratelimiter for RedisRatelimiter<A>
 where
-	A: ToSocketAddrs + Clone + Send + Sync + Debug,
+	A: ToSocketAddrs + Clone + Send + Sync + Debug + 'static,
 {
 	#[instrument(level = "debug")]
 	async fn claim(&self, bucket: String) -> Result<()> {
-		loop {
-			let mut conn = self.redis.get().await?;
-			let expiration = CLAIM_SCRIPT
-				.exec(&mut conn)
-				.keys([&bucket, &(bucket.to_string() + "_size")])
-				.invoke()
-				.await?;
-			let expiration = from_data::<i64>(expiration)?;
+		let key = self.key(&bucket);
+		let mut ready = self
+			.subscriber
+			.as_ref()
+			.map(|subscriber| subscriber.ready.subscribe());
+
+		let mut waited = false;
+		let claimed = loop {
+			if let Some(closed_for) = self.closed_for(&bucket).await? {
+				debug!("Replica has \"{}\" closed for {:?}", bucket, closed_for);
+				waited = true;
+				sleep(closed_for).await;
+				continue;
+			}
 
+			let (expiration, claimed) = self.try_claim(&bucket).await?;
 			debug!("Received expiration of {}ms for \"{}\"", expiration, bucket);
 
+			if expiration == 0 {
+				self.record(&bucket).await?;
+				break claimed;
+			}
+
+			waited = true;
 			if expiration.is_positive() {
 				sleep(Duration::from_millis(expiration as u64)).await;
 				continue;
 			}
 
-			if expiration == 0 {
-				break;
-			}
+			let ready = match &mut ready {
+				Some(ready) => ready,
+				None => {
+					sleep(self.poll_interval).await;
+					continue;
+				}
+			};
 
-			conn.cmd(["SUBSCRIBE", NOTIFY_KEY]).await?;
-			while let Some(data) = conn.try_next().await? {
-				let res = from_data::<pubsub::Response>(data)?;
-				match res {
-					pubsub::Response::Message(msg) if from_utf8(&msg.data) == Ok(&bucket) => {
-						break;
+			let recheck_at = Instant::now() + RECHECK_INTERVAL;
+			loop {
+				match timeout_at(recheck_at, ready.recv()).await {
+					Ok(Ok(released)) if released == key || released == MISSED => break,
+					Ok(Ok(_)) => {}
+					Ok(Err(RecvError::Lagged(_))) | Err(_) => break,
+					Ok(Err(RecvError::Closed)) => {
+						return Err(anyhow!("Ratelimiter has been shut down"))
 					}
-					_ => {}
 				}
 			}
-			conn.cmd(["UNSUBSCRIBE", NOTIFY_KEY]).await?;
-		}
+		};
 
+		if self.throttled.record(&bucket, waited) {
+			self.watched.record_throttled(&bucket, waited);
+		}
+		if let Some(claimed) = claimed {
+			claimed.keep(&self.claims);
+		}
 		Ok(())
 	}
 
 	#[instrument(level = "debug")]
 	async fn release(&self, bucket: String, info: RatelimitInfo) -> Result<()> {
+		let key = self.key(&bucket);
+		let notify = self.key(NOTIFY_KEY);
+		run_release(&self.redis, &key, &notify, self.claims.pop(&key), &info).await?;
+		self.record(&bucket).await
+	}
+
+	#[instrument(level = "debug")]
+	async fn unclaim(&self, bucket: String) -> Result<()> {
+		let key = self.key(&bucket);
+		let notify = self.key(NOTIFY_KEY);
+		run_unclaim(&self.redis, &key, &notify, self.claims.pop(&key)).await?;
+		self.record(&bucket).await
+	}
+
+	#[instrument(level = "debug")]
+	async fn reset_bucket(&self, bucket: String) -> Result<()> {
+		let key = self.key(&bucket);
 		let mut conn = self.redis.get().await?;
-		RELEASE_SCRIPT
+		RESET_SCRIPT
 			.exec(&mut conn)
-			.keys([bucket.as_str(), &(bucket.to_string() + "_size"), NOTIFY_KEY])
-			.args(&[
-				info.limit.unwrap_or(0).to_string(),
-				info.resets_in.unwrap_or(0).to_string(),
+			.keys([
+				key.as_str(),
+				&(key.clone() + "_size"),
+				&generation_key(&key),
+				&self.key(NOTIFY_KEY),
 			])
 			.invoke()
 			.await?;
 
 		Ok(())
 	}
+
+	#[instrument(level = "debug")]
+	async fn claim_global(&self) -> Result<()> {
+		let limit = self.global_limit.to_string();
+		let window = GLOBAL_WINDOW.as_millis().to_string();
+
+		loop {
+			let mut conn = self.redis.get().await?;
+			let expiration = CLAIM_GLOBAL_SCRIPT
+				.exec(&mut conn)
+				.keys([self.key(GLOBAL_KEY)])
+				.args([&limit, &window])
+				.invoke()
+				.await?;
+			let expiration = from_data::<i64>(expiration)?;
+			drop(conn);
+
+			if expiration <= 0 {
+				return Ok(());
+			}
+
+			debug!("Global limit reached: waiting {}ms", expiration);
+			sleep(Duration::from_millis(expiration as u64)).await;
+		}
+	}
+
+	#[instrument(level = "debug")]
+	async fn release_global(&self, info: RatelimitInfo) -> Result<()> {
+		let resets_in = match info.resets_in.filter(|_| info.global) {
+			Some(resets_in) => resets_in,
+			None => return Ok(()),
+		};
+
+		let mut conn = self.redis.get().await?;
+		RELEASE_GLOBAL_SCRIPT
+			.exec(&mut conn)
+			.keys([self.key(GLOBAL_KEY)])
+			.args(&[self.global_limit.to_string(), resets_in.to_string()])
+			.invoke()
+			.await?;
+		Ok(())
+	}
 }
 
 #[cfg(test)]
@@ -100,19 +716,33 @@ mod test {
 	};
 
 	use anyhow::Result;
-	use redust::pool::{deadpool::managed::HookFuture, Hook, Manager, Pool};
+	use redust::{
+		pool::{deadpool::managed::HookFuture, Hook, Manager, Pool},
+		resp::from_data,
+	};
 	use test_log::test;
+	use tokio::{
+		sync::broadcast::error::RecvError,
+		time::{sleep, timeout, Duration, Instant},
+	};
 
-	use super::{super::test, RedisRatelimiter};
+	use super::{
+		super::test, RatelimitInfo, Ratelimiter, RedisRatelimiter, MISSED, RECHECK_INTERVAL,
+	};
 
 	static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
 
 	async fn get_client() -> Result<Arc<RedisRatelimiter<&'static str>>> {
+		Ok(Arc::new(RedisRatelimiter::new(get_pool()?)))
+	}
+
+	/// Get a pool of connections to a new, empty database.
+	fn get_pool() -> Result<Pool<&'static str>> {
 		let db = NEXT_DB.fetch_add(1, Ordering::Relaxed);
 		dbg!(db);
 
 		let manager = Manager::new("localhost:6379");
-		let pool = Pool::builder(manager)
+		Ok(Pool::builder(manager)
 			.post_create(Hook::async_fn(
 				move |conn, _metrics| -> HookFuture<redust::Error> {
 					Box::pin(async move {
@@ -122,9 +752,7 @@ mod test {
 					})
 				},
 			))
-			.build()?;
-
-		Ok(Arc::new(RedisRatelimiter::new(pool)))
+			.build()?)
 	}
 
 	#[test(tokio::test)]
@@ -162,4 +790,252 @@ mod test {
 		let client = get_client().await?;
 		test::claim_limit_release_timeout(client).await
 	}
+
+	#[test(tokio::test)]
+	async fn claim_all_overlapping() -> Result<()> {
+		let client = get_client().await?;
+		test::claim_all_overlapping(client).await
+	}
+
+	#[test(tokio::test)]
+	async fn claim_all_timeout_release() -> Result<()> {
+		let client = get_client().await?;
+		test::claim_all_timeout_release(client).await
+	}
+
+	#[test(tokio::test)]
+	async fn cancelled_claim_releases() -> Result<()> {
+		let client = get_client().await?;
+
+		// each claim is cancelled as soon as it starts, while its script may still be running
+		for _ in 0..10 {
+			let _ = timeout(Duration::ZERO, client.claim("cancelled1".into())).await;
+		}
+		sleep(Duration::from_millis(100)).await;
+
+		timeout(Duration::from_secs(1), client.claim("cancelled1".into())).await??;
+		Ok(())
+	}
+
+	#[test(tokio::test)]
+	async fn drop_returns_connections() -> Result<()> {
+		let manager = Manager::new("localhost:6379");
+		let pool = Pool::builder(manager).max_size(4).build()?;
+
+		for _ in 0..16 {
+			let client = RedisRatelimiter::new(pool.clone());
+			client.shutdown().await;
+		}
+
+		for _ in 0..16 {
+			drop(RedisRatelimiter::new(pool.clone()));
+		}
+
+		let mut conns = Vec::new();
+		for _ in 0..4 {
+			conns.push(timeout(Duration::from_secs(1), pool.get()).await??);
+		}
+
+		Ok(())
+	}
+
+	#[test(tokio::test)]
+	async fn rotates_subscriber() -> Result<()> {
+		let client =
+			RedisRatelimiter::new(get_pool()?).with_subscriber_lifetime(Duration::from_millis(100));
+		let mut ready = client.subscriber.as_ref().unwrap().ready.subscribe();
+
+		for bucket in ["rotate1", "rotate2", "rotate3"] {
+			// each release is after the connection has been rotated again
+			sleep(Duration::from_millis(150)).await;
+			client.claim(bucket.into()).await?;
+			client
+				.release(bucket.into(), RatelimitInfo::default())
+				.await?;
+
+			let released = timeout(Duration::from_secs(1), async {
+				loop {
+					match ready.recv().await? {
+						released if released == MISSED => continue,
+						released => return Ok::<_, RecvError>(released),
+					}
+				}
+			})
+			.await??;
+			assert_eq!(released, bucket);
+		}
+
+		client.shutdown().await;
+		Ok(())
+	}
+
+	#[test(tokio::test)]
+	async fn rechecks_unnotified_release() -> Result<()> {
+		let pool = get_pool()?;
+		let client = Arc::new(RedisRatelimiter::new(pool.clone()));
+		client.claim("unnotified1".into()).await?;
+
+		// the bucket is opened without publishing, as if the notification were lost
+		tokio::spawn(async move {
+			sleep(Duration::from_millis(100)).await;
+			let mut conn = pool.get().await.unwrap();
+			conn.cmd(["INCR", "unnotified1"]).await.unwrap();
+		});
+
+		let start = Instant::now();
+		timeout(
+			RECHECK_INTERVAL + Duration::from_secs(1),
+			client.claim("unnotified1".into()),
+		)
+		.await??;
+		assert!(start.elapsed() >= Duration::from_millis(100));
+
+		Ok(())
+	}
+
+	#[test(tokio::test)]
+	async fn reads_from_replica() -> Result<()> {
+		// separate databases stand in for the primary and its replica
+		let primary = get_pool()?;
+		let replica = get_pool()?;
+		let client = RedisRatelimiter::new(primary.clone()).with_replica(replica.clone());
+
+		// only the replica has the bucket closed, so the claim must have read it from there
+		replica
+			.get()
+			.await?
+			.cmd(["SET", "replica1", "0", "PX", "200"])
+			.await?;
+		let start = Instant::now();
+		client.claim("replica1".into()).await?;
+		assert!(start.elapsed() >= Duration::from_millis(200));
+
+		client
+			.release(
+				"replica1".into(),
+				RatelimitInfo {
+					limit: Some(5),
+					..Default::default()
+				},
+			)
+			.await?;
+
+		let exists = |pool: Pool<&'static str>| async move {
+			let mut conn = pool.get().await?;
+			Ok::<_, anyhow::Error>(from_data::<i64>(
+				conn.cmd(["EXISTS", "replica1_size"]).await?,
+			)?)
+		};
+		assert_eq!(exists(primary).await?, 1);
+		assert_eq!(exists(replica).await?, 0);
+
+		Ok(())
+	}
+
+	#[test(tokio::test)]
+	async fn claims_by_polling() -> Result<()> {
+		let client = Arc::new(RedisRatelimiter::polling(
+			get_pool()?,
+			Duration::from_millis(20),
+		));
+		client.claim("polled1".into()).await?;
+
+		let releaser = Arc::clone(&client);
+		tokio::spawn(async move {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+			releaser
+				.release("polled1".into(), Default::default())
+				.await
+				.unwrap();
+		});
+
+		// nothing is published to wake the claim, so it succeeds by polling after the release
+		let start = Instant::now();
+		timeout(Duration::from_secs(1), client.claim("polled1".into())).await??;
+		assert!(start.elapsed() >= Duration::from_millis(100));
+
+		Ok(())
+	}
+
+	#[test(tokio::test)]
+	async fn unclaim_keeps_timeout() -> Result<()> {
+		let client = get_client().await?;
+		test::unclaim_keeps_timeout(client).await
+	}
+
+	#[test(tokio::test)]
+	async fn reset_bucket() -> Result<()> {
+		let client = get_client().await?;
+		test::reset_bucket(client).await
+	}
+
+	#[test(tokio::test)]
+	async fn reset_ignores_stale_release() -> Result<()> {
+		let client = get_client().await?;
+		test::reset_ignores_stale_release(client).await
+	}
+
+	#[test(tokio::test)]
+	async fn keeps_prefixes_apart() -> Result<()> {
+		let pool = get_pool()?;
+		let a = RedisRatelimiter::new(pool.clone()).with_prefix("a:".into());
+		let b = RedisRatelimiter::new(pool).with_prefix("b:".into());
+
+		// each prefix has its own bucket, so a full bucket under one doesn't hold up the other
+		a.claim("prefixed1".into()).await?;
+		timeout(Duration::from_millis(50), b.claim("prefixed1".into())).await??;
+		assert!(
+			timeout(Duration::from_millis(100), a.claim("prefixed1".into()))
+				.await
+				.is_err()
+		);
+
+		// the bucket is freed by a release under its own prefix
+		a.release("prefixed1".into(), RatelimitInfo::default())
+			.await?;
+		timeout(Duration::from_millis(50), a.claim("prefixed1".into())).await??;
+		Ok(())
+	}
+
+	#[test(tokio::test)]
+	async fn claim_remaining_zero() -> Result<()> {
+		let client = get_client().await?;
+		test::claim_remaining_zero(client).await
+	}
+
+	#[test(tokio::test)]
+	async fn claim_retry_after() -> Result<()> {
+		let client = get_client().await?;
+		test::claim_retry_after(client).await
+	}
+
+	#[test(tokio::test)]
+	async fn claim_global_limit() -> Result<()> {
+		let client = RedisRatelimiter::new(get_pool()?).with_global_limit(5);
+		test::claim_global_limit(Arc::new(client)).await
+	}
+
+	#[test(tokio::test)]
+	async fn release_global() -> Result<()> {
+		let client = get_client().await?;
+		test::release_global(client).await
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test(tokio::test)]
+	async fn watched_bucket_metrics() -> Result<()> {
+		let client = (*get_client().await?)
+			.clone()
+			.with_watched(super::WatchedBuckets::new(vec!["redis_qux1".to_string()]));
+		test::watched_bucket_metrics(Arc::new(client), "redis_qux1").await
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test(tokio::test)]
+	async fn watched_bucket_throttled() -> Result<()> {
+		let client = (*get_client().await?)
+			.clone()
+			.with_watched(super::WatchedBuckets::new(vec!["redis_qux2".to_string()]));
+		test::watched_bucket_throttled(Arc::new(client), "redis_qux2").await
+	}
 }
@@ -1,4 +1,6 @@
 local remaining = tonumber(redis.call("GET", KEYS[1]))
+-- returned with the claim so that its release can be ignored if the bucket is reset meanwhile
+local generation = tonumber(redis.call("GET", KEYS[3])) or 0
 
 if remaining == nil then
 	local bucket_size = tonumber(redis.call("GET", KEYS[2]))
@@ -13,9 +15,9 @@ end
 
 if remaining <= 0 then
 	local ttl = redis.call("PTTL", KEYS[1])
-	if ttl == nil then return -1 end
-	return ttl
+	if ttl == nil then return { -1, generation } end
+	return { ttl, generation }
 end
 
 redis.call("DECR", KEYS[1])
-return 0
+return { 0, generation }
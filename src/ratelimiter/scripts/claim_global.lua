@@ -0,0 +1,24 @@
+local global_key = KEYS[1]
+
+local limit = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+
+local used = tonumber(redis.call("GET", global_key))
+
+if used == nil then
+	-- the first request of a new window
+	redis.call("SET", global_key, 1, "PX", window)
+	return 0
+end
+
+if used >= limit then
+	local ttl = redis.call("PTTL", global_key)
+	if ttl > 0 then return ttl end
+
+	-- the window should always expire, but don't hold the limit closed forever if it doesn't
+	redis.call("SET", global_key, 1, "PX", window)
+	return 0
+end
+
+redis.call("INCR", global_key)
+return 0
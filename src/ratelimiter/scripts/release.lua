@@ -1,9 +1,20 @@
 local bucket_key = KEYS[1]
 local bucket_size_key = KEYS[2]
 local notify_key = KEYS[3]
+local generation_key = KEYS[4]
 
 local new_bucket_size = tonumber(ARGV[1])
 local expires_in = tonumber(ARGV[2])
+local remaining = tonumber(ARGV[3])
+local retry_after = tonumber(ARGV[4])
+-- the generation of the bucket when it was claimed, or empty if it isn't known
+local generation = ARGV[5]
+
+-- a 429 holds the bucket closed for as long as Discord said to wait, even past its reset
+if retry_after > 0 then
+	expires_in = math.max(expires_in, retry_after)
+	remaining = 0
+end
 
 if new_bucket_size > 0 then
 	local original_bucket_size = tonumber(redis.call("GET", bucket_size_key))
@@ -20,8 +31,19 @@ if new_bucket_size > 0 then
 	redis.call("SET", bucket_size_key, new_bucket_size)
 end
 
+if remaining == 0 and expires_in > 0 then
+	-- the bucket is exhausted, so hold it closed until it resets; waiting claims are notified so
+	-- they wait for the reset instead
+	redis.call("SET", bucket_key, 0, "PX", expires_in)
+	redis.call("PUBLISH", notify_key, bucket_key)
+	return
+end
+
+-- a claim from before the bucket was reset doesn't hold one of the new bucket's requests
+local stale = generation ~= "" and generation ~= (redis.call("GET", generation_key) or "0")
+
 local ttl = redis.call("TTL", bucket_key)
-if ttl < 0 then -- key has no expire or doesn't exist
+if ttl < 0 and not stale then -- key has no expire or doesn't exist
 	redis.call("INCR", bucket_key)
 	redis.call("PUBLISH", notify_key, bucket_key)
 end
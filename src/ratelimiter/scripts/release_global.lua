@@ -0,0 +1,9 @@
+local global_key = KEYS[1]
+
+local limit = tonumber(ARGV[1])
+local expires_in = tonumber(ARGV[2])
+
+-- hold the limit closed until Discord says it resets, unless it's already closed for longer
+if redis.call("PTTL", global_key) < expires_in then
+	redis.call("SET", global_key, limit, "PX", expires_in)
+end
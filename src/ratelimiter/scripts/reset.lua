@@ -0,0 +1,10 @@
+local bucket_key = KEYS[1]
+local bucket_size_key = KEYS[2]
+local generation_key = KEYS[3]
+local notify_key = KEYS[4]
+
+redis.call("DEL", bucket_key, bucket_size_key)
+-- claims from before the reset are released without freeing up the new bucket
+redis.call("INCR", generation_key)
+-- wake up any claims waiting on the bucket so they see the reset
+redis.call("PUBLISH", notify_key, bucket_key)
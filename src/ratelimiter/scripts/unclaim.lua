@@ -0,0 +1,16 @@
+local bucket_key = KEYS[1]
+local notify_key = KEYS[2]
+local generation_key = KEYS[3]
+
+local generation = ARGV[1]
+if generation ~= "" and generation ~= (redis.call("GET", generation_key) or "0") then
+	-- the bucket was reset since the claim, which doesn't hold one of the new bucket's requests
+	return
+end
+
+-- a bucket which is held closed gets every claim back once it resets, so only give the claim back
+-- to a bucket which isn't
+if redis.call("PTTL", bucket_key) == -1 then
+	redis.call("INCR", bucket_key)
+	redis.call("PUBLISH", notify_key, bucket_key)
+end
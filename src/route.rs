@@ -1,8 +1,88 @@
 use anyhow::{anyhow, Result};
-use std::convert::TryFrom;
+use http::Method;
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	convert::TryFrom,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
 use uriparse::path::{Path, Segment};
 
-pub fn make_route(path: &str) -> Result<String> {
+/// Additional normalization for routes beyond the major parameter.
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
+pub struct RouteRule {
+	/// Leading segments the route must have for this rule to apply, after the major parameter has
+	/// been normalized (e.g. `/channels/:id/messages`).
+	pub prefix: String,
+	/// Indices of segments to replace with `:id`.
+	#[serde(default)]
+	pub segments: Vec<usize>,
+	/// The minimum time between requests to the same bucket.
+	#[serde(default, with = "humantime_serde")]
+	pub min_spacing: Option<Duration>,
+}
+
+impl RouteRule {
+	fn matches(&self, segments: &[Segment<'_>]) -> bool {
+		let prefix: Vec<&str> = self.prefix.trim_start_matches('/').split('/').collect();
+		prefix.len() <= segments.len()
+			&& prefix
+				.iter()
+				.zip(segments)
+				.all(|(expected, segment)| segment.as_str() == *expected)
+	}
+}
+
+/// The minimum time between requests to the (normalized) route, from the first rule matching it
+/// which sets one.
+pub fn min_spacing(route: &str, rules: &[RouteRule]) -> Option<Duration> {
+	// routes bucketed by method have the method appended after the path
+	let path = route.split('?').next().unwrap_or(route);
+	let path = Path::try_from(path).ok()?;
+	rules
+		.iter()
+		.filter(|rule| rule.matches(path.segments()))
+		.find_map(|rule| rule.min_spacing)
+}
+
+/// Whether the route starts with the pattern's segments, where `*` matches any one segment (e.g.
+/// `/channels/*/messages` matches `/channels/:id/messages/1234`).
+pub fn matches_pattern(pattern: &str, route: &str) -> bool {
+	let route = route.split('?').next().unwrap_or(route);
+	let pattern = pattern.trim_end_matches('/').split('/').collect::<Vec<_>>();
+	let route = route.split('/').collect::<Vec<_>>();
+	pattern.len() <= route.len()
+		&& pattern
+			.iter()
+			.zip(route)
+			.all(|(expected, segment)| *expected == "*" || *expected == segment)
+}
+
+/// Split the `/api/v{version}` prefix from the path of an API request, if it has one.
+pub fn split_api_version(path: &str) -> (Option<u8>, &str) {
+	let versioned = match path.strip_prefix("/api/v") {
+		Some(versioned) => versioned,
+		None => return (None, path),
+	};
+	let end = versioned.find('/').unwrap_or(versioned.len());
+	match versioned[..end].parse() {
+		Ok(version) => (Some(version), &versioned[end..]),
+		Err(_) => (None, path),
+	}
+}
+
+/// The bucket for a request, by its method and path.
+pub fn make_route(method: &Method, path: &str) -> Result<String> {
+	make_route_with_method(method, path, &[])
+}
+
+/// The bucket for a path, regardless of the method of requests to it.
+pub fn make_path_route(path: &str) -> Result<String> {
+	make_route_with_rules(path, &[])
+}
+
+pub fn make_route_with_rules(path: &str, rules: &[RouteRule]) -> Result<String> {
 	let mut path = Path::try_from(path)?;
 	if !path.is_absolute() {
 		return Err(anyhow!("path is not absolute"));
@@ -12,22 +92,561 @@ pub fn make_route(path: &str) -> Result<String> {
 	match segments[0].as_str() {
 		"guilds" | "channels" | "webhooks" if segments.len() > 1 => {
 			segments[1] = Segment::try_from(":id").unwrap();
-			Ok(path.into())
 		}
-		_ => Ok(path.into()),
+		_ => {}
+	}
+	// a webhook's token is a major parameter along with its id
+	let has_token = segments[0].as_str() == "webhooks"
+		&& matches!(segments.get(2), Some(token) if !token.as_str().is_empty());
+	if has_token {
+		segments[2] = Segment::try_from(":token").unwrap();
+	}
+	normalize_threads(segments);
+	normalize_reactions(segments);
+
+	for rule in rules {
+		if !rule.matches(segments) {
+			continue;
+		}
+
+		for &index in &rule.segments {
+			if let Some(segment) = segments.get_mut(index) {
+				*segment = Segment::try_from(":id").unwrap();
+			}
+		}
+	}
+
+	Ok(path.into())
+}
+
+/// Make the route for a request with the given method. Deleting a message is limited separately
+/// from the message's other routes, and regardless of which message is deleted, so it's bucketed
+/// as `/channels/:id/messages/:id?delete`.
+pub fn make_route_with_method(method: &Method, path: &str, rules: &[RouteRule]) -> Result<String> {
+	let route = make_route_with_rules(path, rules)?;
+	let segments = route.split('/').collect::<Vec<_>>();
+	let route = match (method, segments.as_slice()) {
+		(&Method::DELETE, ["", "channels", ":id", "messages", _]) => {
+			"/channels/:id/messages/:id".to_string()
+		}
+		_ => route,
+	};
+	Ok(format!(
+		"{}?{}",
+		route,
+		method.as_str().to_ascii_lowercase()
+	))
+}
+
+/// Threads are channels in their own right, so routes under a thread's id are bucketed like any
+/// other channel's. Creating threads (and forum posts, which are threads in a forum channel) is
+/// limited per parent channel, so threads started from different messages share a bucket
+/// (`/channels/:id/messages/:id/threads`), separate from `/channels/:id/threads`.
+fn normalize_threads(segments: &mut [Segment<'_>]) {
+	if segments.len() < 4 || segments[0].as_str() != "channels" {
+		return;
+	}
+
+	match (segments[2].as_str(), segments.get(4).map(Segment::as_str)) {
+		("messages", Some("threads")) if segments.len() == 5 => {
+			segments[3] = Segment::try_from(":id").unwrap();
+		}
+		("thread-members", None) if segments[3].as_str() != "@me" => {
+			segments[3] = Segment::try_from(":id").unwrap();
+		}
+		_ => {}
+	}
+}
+
+/// Reactions are limited per channel regardless of the emoji, so the emoji following `reactions`
+/// is normalized (`/channels/:id/messages/1234/reactions/:emoji/@me`).
+fn normalize_reactions(segments: &mut [Segment<'_>]) {
+	if segments[0].as_str() != "channels" {
+		return;
+	}
+
+	let emoji = segments
+		.iter()
+		.position(|segment| segment.as_str() == "reactions")
+		.map(|index| index + 1)
+		.filter(|&index| index < segments.len());
+	if let Some(index) = emoji {
+		segments[index] = Segment::try_from(":emoji").unwrap();
+	}
+}
+
+/// The bucket shared by every route beyond the bucket limit.
+pub const OVERFLOW_BUCKET: &str = "overflow";
+
+/// How long a bucket can go unused before another route can take its place under the cap.
+pub const BUCKET_IDLE: Duration = Duration::from_secs(60 * 60);
+
+/// Entries which are forgotten once they've gone unused for long enough, making room for new
+/// ones under the cap.
+#[derive(Debug)]
+struct Known<V> {
+	max: usize,
+	idle: Duration,
+	/// Each entry, with when it was last used.
+	entries: HashMap<String, (V, Instant)>,
+	/// When idle entries were last forgotten, so they're only looked for once per idle period.
+	swept_at: Instant,
+}
+
+impl<V> Known<V> {
+	fn new(max: usize) -> Self {
+		Self {
+			max,
+			idle: BUCKET_IDLE,
+			entries: HashMap::new(),
+			swept_at: Instant::now(),
+		}
+	}
+
+	/// Get the entry for the key, marking it as used.
+	fn get(&mut self, key: &str) -> Option<&mut V> {
+		let (value, used) = self.entries.get_mut(key)?;
+		*used = Instant::now();
+		Some(value)
+	}
+
+	/// Add an entry, if there's room for it once idle entries are forgotten. Returns whether it
+	/// was added.
+	fn insert(&mut self, key: String, value: V) -> bool {
+		let now = Instant::now();
+		if self.entries.len() >= self.max && now.duration_since(self.swept_at) >= self.idle {
+			let idle = self.idle;
+			self.entries
+				.retain(|_, (_, used)| now.duration_since(*used) < idle);
+			self.swept_at = now;
+		}
+
+		if self.entries.len() < self.max {
+			self.entries.insert(key, (value, now));
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// Caps the number of distinct buckets. Once the cap is reached, routes that haven't been seen
+/// before are grouped into [`OVERFLOW_BUCKET`], until buckets which have gone unused for long
+/// enough are forgotten to make room.
+#[derive(Debug)]
+pub struct BucketLimit {
+	known: Mutex<Known<()>>,
+}
+
+impl BucketLimit {
+	pub fn new(max: usize) -> Self {
+		Self {
+			known: Mutex::new(Known::new(max)),
+		}
+	}
+
+	/// Forget buckets once they've gone unused for this long, instead of [`BUCKET_IDLE`].
+	pub fn with_idle(mut self, idle: Duration) -> Self {
+		self.known.get_mut().unwrap().idle = idle;
+		self
+	}
+
+	/// The bucket to use for the route.
+	pub fn bucket(&self, route: String) -> String {
+		let mut known = self.known.lock().unwrap();
+		if known.get(&route).is_some() || known.insert(route.clone(), ()) {
+			route
+		} else {
+			OVERFLOW_BUCKET.to_string()
+		}
+	}
+}
+
+/// How many route hashes are remembered by default.
+pub const MAX_BUCKET_HASHES: usize = 10000;
+
+/// The route with its minor ids, which Discord doesn't bucket by, replaced with `:id`, so that
+/// every request to the route shares what's known about it.
+pub fn normalize_minor_ids(route: &str) -> String {
+	let (path, method) = match route.split_once('?') {
+		Some((path, method)) => (path, Some(method)),
+		None => (route, None),
+	};
+	let path = path
+		.split('/')
+		.map(|segment| {
+			if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+				":id"
+			} else {
+				segment
+			}
+		})
+		.collect::<Vec<_>>()
+		.join("/");
+
+	match method {
+		Some(method) => format!("{}?{}", path, method),
+		None => path,
+	}
+}
+
+/// Remembers the bucket hash Discord reports for each route. Routes are bucketed by their path
+/// until a response reports their hash, and by the hash afterwards, so routes which Discord
+/// groups together share a bucket. Like [`BucketLimit`], hashes are capped, and routes unused for
+/// long enough are forgotten to make room.
+#[derive(Debug)]
+pub struct BucketHashes {
+	hashes: Mutex<Known<String>>,
+}
+
+impl Default for BucketHashes {
+	fn default() -> Self {
+		Self::new(MAX_BUCKET_HASHES)
+	}
+}
+
+impl BucketHashes {
+	pub fn new(max: usize) -> Self {
+		Self {
+			hashes: Mutex::new(Known::new(max)),
+		}
+	}
+
+	/// Forget routes once they've gone unused for this long, instead of [`BUCKET_IDLE`].
+	pub fn with_idle(mut self, idle: Duration) -> Self {
+		self.hashes.get_mut().unwrap().idle = idle;
+		self
+	}
+
+	/// The bucket to use for the route.
+	pub fn bucket(&self, route: String) -> String {
+		match self
+			.hashes
+			.lock()
+			.unwrap()
+			.get(&normalize_minor_ids(&route))
+		{
+			Some(hash) => format!("hash:{}", hash),
+			None => route,
+		}
+	}
+
+	/// The hashes currently known, by normalized route.
+	pub fn export(&self) -> HashMap<String, String> {
+		self.hashes
+			.lock()
+			.unwrap()
+			.entries
+			.iter()
+			.map(|(route, (hash, _))| (route.clone(), hash.clone()))
+			.collect()
+	}
+
+	/// Remember each of the hashes, as if they were reported by Discord.
+	pub fn import(&self, map: HashMap<String, String>) {
+		for (route, hash) in map {
+			self.learn(&route, &hash);
+		}
+	}
+
+	/// Remember the hash Discord reported for the route.
+	pub fn learn(&self, route: &str, hash: &str) {
+		let route = normalize_minor_ids(route);
+		let mut hashes = self.hashes.lock().unwrap();
+		match hashes.get(&route) {
+			Some(known) if known == hash => {}
+			Some(known) => *known = hash.to_string(),
+			None => {
+				hashes.insert(route, hash.to_string());
+			}
+		}
 	}
 }
 
 #[cfg(test)]
 mod test {
-	use super::make_route;
+	use super::{
+		make_path_route, make_route, make_route_with_method, make_route_with_rules,
+		matches_pattern, min_spacing, normalize_minor_ids, split_api_version, BucketHashes,
+		BucketLimit, RouteRule, OVERFLOW_BUCKET,
+	};
+	use http::Method;
+	use std::{thread::sleep, time::Duration};
 
 	#[test]
 	fn makes_route() {
-		assert_eq!(make_route("/foo/bar").unwrap(), "/foo/bar".to_string());
+		assert_eq!(make_path_route("/foo/bar").unwrap(), "/foo/bar".to_string());
 		assert_eq!(
-			make_route("/guilds/1234/roles").unwrap(),
+			make_path_route("/guilds/1234/roles").unwrap(),
 			"/guilds/:id/roles".to_string()
 		);
 	}
+
+	#[test]
+	fn splits_api_version() {
+		assert_eq!(
+			split_api_version("/api/v10/guilds/1234"),
+			(Some(10), "/guilds/1234")
+		);
+		assert_eq!(split_api_version("/api/v9"), (Some(9), ""));
+		assert_eq!(split_api_version("/guilds/1234"), (None, "/guilds/1234"));
+		assert_eq!(
+			split_api_version("/api/vx/guilds"),
+			(None, "/api/vx/guilds")
+		);
+	}
+
+	#[test]
+	fn makes_webhook_routes() {
+		assert_eq!(make_path_route("/webhooks/123").unwrap(), "/webhooks/:id");
+		assert_eq!(
+			make_path_route("/webhooks/123/abc").unwrap(),
+			"/webhooks/:id/:token"
+		);
+		assert_eq!(
+			make_path_route("/webhooks/123/abc/messages/456").unwrap(),
+			"/webhooks/:id/:token/messages/456"
+		);
+	}
+
+	#[test]
+	fn makes_reaction_routes() {
+		assert_eq!(
+			make_path_route("/channels/1234/messages/5678/reactions/%F0%9F%91%8D/@me").unwrap(),
+			"/channels/:id/messages/5678/reactions/:emoji/@me"
+		);
+		assert_eq!(
+			make_path_route("/channels/1234/messages/5678/reactions/name:9012/3456").unwrap(),
+			"/channels/:id/messages/5678/reactions/:emoji/3456"
+		);
+		assert_eq!(
+			make_path_route("/channels/1234/messages/5678/reactions").unwrap(),
+			"/channels/:id/messages/5678/reactions"
+		);
+	}
+
+	#[test]
+	fn makes_message_delete_route() {
+		let path = "/channels/1234/messages/5678";
+		assert_eq!(
+			make_route_with_method(&Method::GET, path, &[]).unwrap(),
+			"/channels/:id/messages/5678?get"
+		);
+		assert_eq!(
+			make_route_with_method(&Method::DELETE, path, &[]).unwrap(),
+			"/channels/:id/messages/:id?delete"
+		);
+		assert_eq!(
+			make_route_with_method(
+				&Method::DELETE,
+				"/channels/1234/messages/5678/reactions",
+				&[]
+			)
+			.unwrap(),
+			"/channels/:id/messages/5678/reactions?delete"
+		);
+	}
+
+	#[test]
+	fn makes_method_routes() {
+		let path = "/guilds/1234/roles";
+		assert_eq!(
+			make_route(&Method::GET, path).unwrap(),
+			"/guilds/:id/roles?get"
+		);
+		assert_eq!(
+			make_route(&Method::POST, path).unwrap(),
+			"/guilds/:id/roles?post"
+		);
+		assert_eq!(make_path_route(path).unwrap(), "/guilds/:id/roles");
+	}
+
+	#[test]
+	fn makes_thread_routes() {
+		// sending to a thread is sending to a channel
+		assert_eq!(
+			make_path_route("/channels/1234/messages").unwrap(),
+			"/channels/:id/messages"
+		);
+
+		// creating a thread from a message shares a bucket with threads from other messages
+		assert_eq!(
+			make_path_route("/channels/1234/messages/5678/threads").unwrap(),
+			"/channels/:id/messages/:id/threads"
+		);
+		assert_eq!(
+			make_path_route("/channels/1234/messages/5678").unwrap(),
+			"/channels/:id/messages/5678"
+		);
+
+		// creating a thread without a message, or a forum post, has its own bucket
+		assert_eq!(
+			make_path_route("/channels/1234/threads").unwrap(),
+			"/channels/:id/threads"
+		);
+		assert_eq!(
+			make_path_route("/channels/1234/threads/archived/public").unwrap(),
+			"/channels/:id/threads/archived/public"
+		);
+
+		assert_eq!(
+			make_path_route("/channels/1234/thread-members/5678").unwrap(),
+			"/channels/:id/thread-members/:id"
+		);
+		assert_eq!(
+			make_path_route("/channels/1234/thread-members/@me").unwrap(),
+			"/channels/:id/thread-members/@me"
+		);
+	}
+
+	#[test]
+	fn makes_route_with_rules() {
+		let rules = [RouteRule {
+			prefix: "/guilds/:id/members".to_string(),
+			segments: vec![3, 5],
+			min_spacing: None,
+		}];
+
+		assert_eq!(
+			make_route_with_rules("/guilds/1/members/2/roles/3", &rules).unwrap(),
+			"/guilds/:id/members/:id/roles/:id".to_string()
+		);
+		assert_eq!(
+			make_route_with_rules("/guilds/1/members", &rules).unwrap(),
+			"/guilds/:id/members".to_string()
+		);
+		assert_eq!(
+			make_route_with_rules("/guilds/1/bans/2", &rules).unwrap(),
+			"/guilds/:id/bans/2".to_string()
+		);
+	}
+
+	#[test]
+	fn finds_min_spacing() {
+		let rules = [
+			RouteRule {
+				prefix: "/guilds/:id/members".to_string(),
+				segments: vec![3],
+				min_spacing: None,
+			},
+			RouteRule {
+				prefix: "/guilds/:id".to_string(),
+				segments: vec![],
+				min_spacing: Some(Duration::from_millis(50)),
+			},
+		];
+
+		assert_eq!(
+			min_spacing("/guilds/:id/members/:id", &rules),
+			Some(Duration::from_millis(50))
+		);
+		assert_eq!(min_spacing("/channels/:id/messages", &rules), None);
+	}
+
+	#[test]
+	fn matches_patterns() {
+		assert!(matches_pattern(
+			"/channels/*/messages",
+			"/channels/:id/messages"
+		));
+		assert!(matches_pattern(
+			"/channels/*/messages",
+			"/channels/:id/messages/:id?delete"
+		));
+		assert!(matches_pattern("/channels/", "/channels/:id/pins"));
+		assert!(!matches_pattern(
+			"/channels/*/messages",
+			"/channels/:id/pins"
+		));
+		assert!(!matches_pattern("/channels/*/messages", "/channels/:id"));
+	}
+
+	#[test]
+	fn limits_buckets() {
+		let limit = BucketLimit::new(2);
+		assert_eq!(limit.bucket("/webhooks/:id/a".into()), "/webhooks/:id/a");
+		assert_eq!(limit.bucket("/webhooks/:id/b".into()), "/webhooks/:id/b");
+		assert_eq!(limit.bucket("/webhooks/:id/c".into()), OVERFLOW_BUCKET);
+		assert_eq!(limit.bucket("/webhooks/:id/d".into()), OVERFLOW_BUCKET);
+		assert_eq!(limit.bucket("/webhooks/:id/a".into()), "/webhooks/:id/a");
+		assert_eq!(limit.bucket("/webhooks/:id/b".into()), "/webhooks/:id/b");
+	}
+
+	#[test]
+	fn forgets_idle_buckets() {
+		let limit = BucketLimit::new(2).with_idle(Duration::from_millis(100));
+		assert_eq!(limit.bucket("/webhooks/:id/a".into()), "/webhooks/:id/a");
+		assert_eq!(limit.bucket("/webhooks/:id/b".into()), "/webhooks/:id/b");
+		assert_eq!(limit.bucket("/webhooks/:id/c".into()), OVERFLOW_BUCKET);
+
+		// b stays in use while a goes idle, so c takes a's place
+		sleep(Duration::from_millis(60));
+		assert_eq!(limit.bucket("/webhooks/:id/b".into()), "/webhooks/:id/b");
+		sleep(Duration::from_millis(60));
+		assert_eq!(limit.bucket("/webhooks/:id/c".into()), "/webhooks/:id/c");
+		assert_eq!(limit.bucket("/webhooks/:id/b".into()), "/webhooks/:id/b");
+		assert_eq!(limit.bucket("/webhooks/:id/a".into()), OVERFLOW_BUCKET);
+	}
+
+	#[test]
+	fn buckets_by_hash() {
+		let hashes = BucketHashes::default();
+		assert_eq!(
+			hashes.bucket("/channels/:id/messages/1".into()),
+			"/channels/:id/messages/1"
+		);
+
+		// the hash is learned for the route regardless of the message it was learned from
+		hashes.learn("/channels/:id/messages/1", "abc");
+		assert_eq!(hashes.bucket("/channels/:id/messages/1".into()), "hash:abc");
+		assert_eq!(hashes.bucket("/channels/:id/messages/2".into()), "hash:abc");
+		assert_eq!(
+			hashes.bucket("/channels/:id/pins".into()),
+			"/channels/:id/pins"
+		);
+	}
+
+	#[test]
+	fn limits_bucket_hashes() {
+		let hashes = BucketHashes::new(1).with_idle(Duration::from_millis(100));
+		hashes.learn("/channels/:id/messages/1?get", "abc");
+		hashes.learn("/channels/:id/pins?get", "def");
+		assert_eq!(
+			hashes.bucket("/channels/:id/pins?get".into()),
+			"/channels/:id/pins?get"
+		);
+
+		// once the first route goes idle, the second takes its place
+		sleep(Duration::from_millis(120));
+		hashes.learn("/channels/:id/pins?get", "def");
+		assert_eq!(hashes.bucket("/channels/:id/pins?get".into()), "hash:def");
+		assert_eq!(
+			hashes.bucket("/channels/:id/messages/1?get".into()),
+			"/channels/:id/messages/1?get"
+		);
+	}
+
+	#[test]
+	fn imports_bucket_hashes() {
+		let hashes = BucketHashes::default();
+		hashes.learn("/channels/:id/messages/1?get", "abc");
+		hashes.learn("/channels/:id/pins?get", "def");
+
+		let imported = BucketHashes::default();
+		imported.import(hashes.export());
+		assert_eq!(
+			imported.bucket("/channels/:id/messages/2?get".into()),
+			"hash:abc"
+		);
+		assert_eq!(imported.bucket("/channels/:id/pins?get".into()), "hash:def");
+	}
+
+	#[test]
+	fn normalizes_minor_ids() {
+		assert_eq!(
+			normalize_minor_ids("/channels/:id/messages/1234/reactions/:emoji/@me?put"),
+			"/channels/:id/messages/:id/reactions/:emoji/@me?put"
+		);
+		assert_eq!(normalize_minor_ids("/invites/abc123"), "/invites/abc123");
+	}
 }
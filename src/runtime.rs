@@ -1,7 +1,26 @@
+pub mod backoff;
+pub mod body;
+pub mod bucket_map;
+pub mod cancel;
 pub mod client;
+pub mod compression;
 pub mod config;
+pub mod dedup;
+pub mod error_log;
+pub mod http;
 #[cfg(feature = "metrics")]
 pub mod metrics;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod pause;
+pub mod quiesce;
+pub mod rate_cap;
+pub mod reload;
+pub mod requeue;
+pub mod self_test;
+pub mod signing;
+pub mod spacing;
+pub mod unreplied;
 
 pub use client::Client;
 pub use config::Config;
@@ -0,0 +1,72 @@
+use crate::ratelimiter::RatelimitInfo;
+use http::StatusCode;
+use reqwest::Response;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// Waits before reusing buckets which were ratelimited without being told for how long, doubling
+/// the wait for each consecutive such response.
+#[derive(Debug)]
+pub struct Backoff {
+	initial: Duration,
+	max: Duration,
+	retries: u32,
+	attempts: Mutex<HashMap<String, u32>>,
+}
+
+impl Backoff {
+	pub fn new(initial: Duration, max: Duration) -> Self {
+		Self {
+			initial,
+			max,
+			retries: 0,
+			attempts: Mutex::default(),
+		}
+	}
+
+	/// Send requests again up to this many times after a 429, once their bucket reopens.
+	pub fn with_retries(mut self, retries: u32) -> Self {
+		self.retries = retries;
+		self
+	}
+
+	/// How many times a request is sent again after a 429.
+	pub fn retries(&self) -> u32 {
+		self.retries
+	}
+
+	/// Fill in when the bucket resets if the response was a 429 without any reset info, using its
+	/// `Retry-After` header if it has one and backing off otherwise.
+	pub fn apply<E>(&self, bucket: &str, res: Result<&Response, E>, info: &mut RatelimitInfo) {
+		let mut attempts = self.attempts.lock().unwrap();
+		let res = match res {
+			Ok(res)
+				if res.status() == StatusCode::TOO_MANY_REQUESTS && info.resets_in.is_none() =>
+			{
+				res
+			}
+			_ => {
+				attempts.remove(bucket);
+				return;
+			}
+		};
+
+		let retry_after = res
+			.headers()
+			.get("retry-after")
+			.and_then(|value| value.to_str().ok()?.parse::<f64>().ok());
+		if let Some(retry_after) = retry_after {
+			attempts.remove(bucket);
+			info.resets_in = Some((retry_after * 1000.) as u64);
+			return;
+		}
+
+		let attempt = attempts.entry(bucket.to_string()).or_default();
+		let wait = self
+			.initial
+			.checked_mul(2u32.saturating_pow(*attempt))
+			.map_or(self.max, |wait| wait.min(self.max));
+		*attempt += 1;
+
+		info.resets_in = Some(wait.as_millis() as u64);
+	}
+}
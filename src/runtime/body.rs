@@ -0,0 +1,66 @@
+use anyhow::Result;
+use bytes::Bytes;
+use redust::{pool::Pool, resp::from_data};
+use std::fmt::{self, Debug, Formatter};
+use tokio::{spawn, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Reads request bodies which producers have pushed to a Redis list in chunks, so they can be
+/// sent to Discord without being buffered in full.
+#[derive(Clone)]
+pub struct BodyStore {
+	pool: Pool<String>,
+	/// Prepended to every key, so that proxies sharing Redis read bodies from separate keys.
+	prefix: String,
+}
+
+impl Debug for BodyStore {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.debug_struct("BodyStore").finish()
+	}
+}
+
+impl BodyStore {
+	pub fn new(pool: Pool<String>) -> Self {
+		Self {
+			pool,
+			prefix: String::new(),
+		}
+	}
+
+	/// Prepend the prefix to every key.
+	pub fn with_prefix(mut self, prefix: String) -> Self {
+		self.prefix = prefix;
+		self
+	}
+
+	/// Stream the chunks of the list at `key` (after the prefix) in order, reading ahead by at
+	/// most one chunk. The list is left in place so the request can be redelivered; producers
+	/// should set it to expire.
+	pub fn stream(&self, key: String) -> ReceiverStream<Result<Bytes>> {
+		let key = self.prefix.clone() + &key;
+		let (sender, receiver) = mpsc::channel(1);
+		let store = self.clone();
+		spawn(async move {
+			for index in 0.. {
+				let chunk = match store.chunk(&key, index).await.transpose() {
+					Some(chunk) => chunk,
+					None => break,
+				};
+
+				let failed = chunk.is_err();
+				if sender.send(chunk).await.is_err() || failed {
+					break;
+				}
+			}
+		});
+
+		ReceiverStream::new(receiver)
+	}
+
+	async fn chunk(&self, key: &str, index: usize) -> Result<Option<Bytes>> {
+		let mut conn = self.pool.get().await?;
+		let chunk = conn.cmd(["LINDEX", key, &index.to_string()]).await?;
+		Ok(from_data(chunk)?)
+	}
+}
@@ -0,0 +1,95 @@
+use crate::route::BucketHashes;
+use anyhow::Result;
+use redust::{pool::Pool, resp::from_data};
+use std::{
+	collections::HashMap,
+	fmt::{self, Debug, Formatter},
+	sync::Arc,
+	time::Duration,
+};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{debug, warn};
+
+const KEY: &str = "proxy_bucket_map";
+
+/// Keeps the bucket hashes learned from Discord in Redis, so proxies start out knowing them
+/// instead of relearning them from scratch.
+#[derive(Clone)]
+pub struct BucketMap {
+	pool: Pool<String>,
+	/// The key of the hash the map is stored in.
+	key: String,
+}
+
+impl Debug for BucketMap {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.debug_struct("BucketMap").finish()
+	}
+}
+
+impl BucketMap {
+	pub fn new(pool: Pool<String>) -> Self {
+		Self {
+			pool,
+			key: KEY.to_string(),
+		}
+	}
+
+	/// Prepend the prefix to the key the map is stored in.
+	pub fn with_prefix(mut self, prefix: String) -> Self {
+		self.key = prefix + KEY;
+		self
+	}
+
+	/// Replace the stored map with the hashes known to `hashes`. Nothing is stored if no hashes
+	/// are known, so a proxy which hasn't learned any yet doesn't erase what others have.
+	pub async fn export_bucket_map(&self, hashes: &BucketHashes) -> Result<()> {
+		let map = hashes.export();
+		if map.is_empty() {
+			return Ok(());
+		}
+
+		let mut hset = vec!["HSET".to_string(), self.key.clone()];
+		let count = map.len();
+		for (route, hash) in map {
+			hset.push(route);
+			hset.push(hash);
+		}
+
+		let mut conn = self.pool.get().await?;
+		conn.cmd(["MULTI"]).await?;
+		conn.cmd(["DEL", self.key.as_str()]).await?;
+		conn.cmd(hset).await?;
+		conn.cmd(["EXEC"]).await?;
+		debug!("Exported {} bucket hashes", count);
+		Ok(())
+	}
+
+	/// Teach `hashes` the stored map, returning how many hashes it had.
+	pub async fn import_bucket_map(&self, hashes: &BucketHashes) -> Result<usize> {
+		let mut conn = self.pool.get().await?;
+		let fields = from_data::<Vec<String>>(conn.cmd(["HGETALL", self.key.as_str()]).await?)?;
+		let map = fields
+			.chunks_exact(2)
+			.map(|pair| (pair[0].clone(), pair[1].clone()))
+			.collect::<HashMap<_, _>>();
+
+		let count = map.len();
+		hashes.import(map);
+		Ok(count)
+	}
+
+	/// Export the hashes every interval, forever.
+	pub async fn run(self, hashes: Arc<BucketHashes>, every: Duration) {
+		let mut ticks = interval(every);
+		ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		// the first tick completes immediately, when there's nothing new to export
+		ticks.tick().await;
+		loop {
+			ticks.tick().await;
+			if let Err(e) = self.export_bucket_map(&hashes).await {
+				warn!("Unable to export bucket hashes: {:?}", e);
+			}
+		}
+	}
+}
@@ -0,0 +1,106 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+/// Messages which can be cancelled while they're being handled, by the ids their producers gave
+/// them.
+#[derive(Debug, Default)]
+pub struct Cancellations {
+	cancelled: Mutex<HashMap<String, watch::Sender<bool>>>,
+}
+
+impl Cancellations {
+	/// Make the message with the id cancellable until the returned handle is dropped.
+	pub fn register(self: &Arc<Self>, id: &str) -> Cancellable {
+		let mut cancelled = self.cancelled.lock().unwrap();
+		let receiver = match cancelled.get(id) {
+			Some(sender) => sender.subscribe(),
+			None => {
+				let (sender, receiver) = watch::channel(false);
+				cancelled.insert(id.to_string(), sender);
+				receiver
+			}
+		};
+
+		Cancellable {
+			cancellations: Arc::clone(self),
+			id: id.to_string(),
+			cancelled: receiver,
+		}
+	}
+
+	/// Cancel the message with the id, returning whether it was being handled.
+	pub fn cancel(&self, id: &str) -> bool {
+		match self.cancelled.lock().unwrap().get(id) {
+			Some(sender) => {
+				sender.send_replace(true);
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+/// A message which is cancellable while it's being handled.
+#[derive(Debug)]
+pub struct Cancellable {
+	cancellations: Arc<Cancellations>,
+	id: String,
+	cancelled: watch::Receiver<bool>,
+}
+
+impl Cancellable {
+	pub fn is_cancelled(&self) -> bool {
+		*self.cancelled.borrow()
+	}
+
+	/// Wait until the message is cancelled.
+	pub async fn cancelled(&self) {
+		let mut cancelled = self.cancelled.clone();
+		// the sender isn't dropped while this is registered
+		let _ = cancelled.wait_for(|cancelled| *cancelled).await;
+	}
+}
+
+impl Drop for Cancellable {
+	fn drop(&mut self) {
+		let mut cancelled = self.cancellations.cancelled.lock().unwrap();
+		// this handle's receiver is the last one when no others share the id
+		if matches!(cancelled.get(&self.id), Some(sender) if sender.receiver_count() <= 1) {
+			cancelled.remove(&self.id);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::Cancellations;
+	use std::sync::Arc;
+	use tokio::time::{timeout, Duration};
+
+	#[tokio::test]
+	async fn cancels_registered_messages() {
+		let cancellations = Arc::new(Cancellations::default());
+		assert!(!cancellations.cancel("unknown"));
+
+		let cancellable = cancellations.register("abc");
+		{
+			let cancelled = cancellable.cancelled();
+			tokio::pin!(cancelled);
+			assert!(timeout(Duration::from_millis(50), &mut cancelled)
+				.await
+				.is_err());
+
+			assert!(cancellations.cancel("abc"));
+			timeout(Duration::from_millis(50), cancelled)
+				.await
+				.expect("wasn't cancelled");
+		}
+		assert!(cancellable.is_cancelled());
+
+		drop(cancellable);
+		assert!(!cancellations.cancel("abc"));
+	}
+}
@@ -1,35 +1,351 @@
 #[cfg(feature = "metrics")]
-use crate::metrics::{RATELIMIT_LATENCY, REQUESTS_TOTAL, REQUEST_LATENCY, RESPONSES_TOTAL};
+use crate::metrics::{
+	ACK_DELAY, CONCURRENCY_WAIT, HTTP_IN_FLIGHT, OLDEST_UNACKED_AGE, RATELIMITED_TOTAL,
+	RATELIMIT_LATENCY, RATELIMIT_WAITING, REJECTIONS_TOTAL, REQUESTS_IN_FLIGHT, REQUESTS_TOTAL,
+	REQUEST_LATENCY, RESPONSES_TOTAL, RESPONSE_ELAPSED, RESPONSE_TTFB,
+};
 use crate::{
-	models::{RequestResponse, SerializableHttpRequest, SerializableHttpResponse},
-	ratelimiter::Ratelimiter,
-	route::make_route,
+	models::{
+		BatchRequest, CompressedReply, PoisonedError, RatelimitDebug, Rejection, RequestBody,
+		RequestMode, RequestResponse, RequestResponseBody, ResponseStatus, SerializableHttpRequest,
+		SerializableHttpResponse,
+	},
+	ratelimiter::{ClaimTimedOut, RatelimitInfo, Ratelimiter},
+	route::{
+		make_route_with_method, make_route_with_rules, min_spacing, split_api_version,
+		BucketHashes, BucketLimit, RouteRule, OVERFLOW_BUCKET,
+	},
 };
 use anyhow::{Context, Result};
-use futures::{TryStream, TryStreamExt};
-use http::Method;
-use reqwest::Request;
-use rustacles_brokers::{common::Message, redis::message};
-use std::{convert::TryInto, fmt::Debug, str::FromStr, time::SystemTime};
+use bytes::Bytes;
+use futures::{
+	future::{self, BoxFuture},
+	stream, Future, FutureExt, StreamExt, TryStream, TryStreamExt,
+};
+use http::{
+	header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER, VIA},
+	HeaderMap, HeaderValue, Method,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Body, Request};
+use rustacles_brokers::{
+	common::Message,
+	redis::{message, RedisBroker},
+};
+use serde::{de::IgnoredAny, Serialize};
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+	convert::TryInto,
+	fmt::{self, Debug, Formatter},
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::SystemTime,
+};
 use tokio::{
 	net::ToSocketAddrs,
 	spawn,
+	sync::{watch, OwnedSemaphorePermit, Semaphore},
 	time::{self, timeout_at, Duration, Instant},
 };
-use tracing::{info, instrument, warn};
+use tracing::{debug, info, instrument, warn};
 use uriparse::{Path, Query, Scheme, URIBuilder};
 
 #[cfg(feature = "metrics")]
-use super::metrics::LatencyTracker;
+use super::metrics::{Backlog, GaugeGuard, LatencyTracker};
+use super::{
+	backoff::Backoff,
+	body::BodyStore,
+	cancel::{Cancellable, Cancellations},
+	compression::Compression,
+	config::{
+		BatchConfig, ForwardedConfig, LaneConfig, PathsConfig, QueryConfig, RatelimitStrategy,
+	},
+	dedup::Dedup,
+	error_log::ErrorLog,
+	http::HttpClients,
+	pause::{Pause, PauseControl},
+	quiesce::Quiesce,
+	rate_cap::RateCap,
+	reload::Reloadable,
+	requeue::Requeue,
+	signing::Signer,
+	spacing::Spacing,
+	unreplied::Unreplied,
+};
+
+/// The methods requests can be sent with. Others can't succeed against Discord.
+const ALLOWED_METHODS: [Method; 5] = [
+	Method::GET,
+	Method::POST,
+	Method::PUT,
+	Method::PATCH,
+	Method::DELETE,
+];
+
+/// The bucket reported for uploads, which aren't ratelimited.
+const UPLOAD_BUCKET: &str = "upload";
+
+/// How often to update the age of the oldest message still being handled.
+#[cfg(feature = "metrics")]
+const BACKLOG_UPDATE_PERIOD: Duration = Duration::from_secs(1);
+
+/// A request which has claimed its ratelimit bucket.
+#[derive(Debug)]
+struct Claimed {
+	req: Request,
+	/// The route the request was made to, as bucketed by its path.
+	route: String,
+	bucket: String,
+	waited: Duration,
+	/// Whether the request holds its bucket, and so must release it.
+	holds_bucket: bool,
+	/// Whether the request counts against the global limit.
+	global: bool,
+	guard: Option<ReleaseGuard>,
+}
+
+/// Releases a claimed bucket if the request is dropped before releasing it, such as when it times
+/// out, so that the bucket isn't left claimed.
+struct ReleaseGuard {
+	bucket: String,
+	grace: Duration,
+	release: Option<BoxFuture<'static, Result<()>>>,
+}
+
+impl Debug for ReleaseGuard {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ReleaseGuard")
+			.field("bucket", &self.bucket)
+			.field("grace", &self.grace)
+			.field("armed", &self.release.is_some())
+			.finish()
+	}
+}
+
+impl ReleaseGuard {
+	/// Stop the guard from releasing the bucket, since the request is releasing it itself.
+	fn disarm(&mut self) {
+		self.release = None;
+	}
+}
+
+impl Drop for ReleaseGuard {
+	fn drop(&mut self) {
+		let release = match self.release.take() {
+			Some(release) => release,
+			None => return,
+		};
+
+		let bucket = self.bucket.clone();
+		let grace = self.grace;
+		spawn(async move {
+			warn!("Releasing \"{}\" after the request was dropped", bucket);
+			match time::timeout(grace, release).await {
+				Ok(Ok(())) => {}
+				Ok(Err(e)) => warn!("Unable to release \"{}\": {:?}", bucket, e),
+				Err(_) => warn!("Timed out releasing \"{}\"", bucket),
+			}
+		});
+	}
+}
+
+/// How long cancelled requests have to release their bucket, when no release grace is configured.
+const CANCELLED_RELEASE_GRACE: Duration = Duration::from_secs(5);
+
+/// How long to stop sending requests after being banned by Cloudflare, when it doesn't say.
+const CLOUDFLARE_BAN_WAIT: Duration = Duration::from_secs(60 * 60);
+
+/// The state shared by the requests of a batch as it's handled.
+struct BatchState<'a> {
+	lane: Option<&'a LaneConfig>,
+	cancel: Option<&'a Cancellable>,
+	stop_on_error: bool,
+	/// Whether any request has failed so far.
+	failed: AtomicBool,
+}
+
+/// The payload of a message which the client consumes.
+trait Payload: Clone + Serialize + Send + Sync + 'static {
+	/// Handle the message, which was received at `received`.
+	fn handle<'a, R, A>(
+		client: &'a Client<R>,
+		message: message::Message<A, Self>,
+		received: Instant,
+	) -> BoxFuture<'a, Result<()>>
+	where
+		R: Ratelimiter + Clone + Sync + Send + 'static,
+		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug;
+
+	/// Count the payload as redelivered, before it's published again.
+	fn redeliver(&mut self);
+}
+
+impl Payload for SerializableHttpRequest {
+	fn handle<'a, R, A>(
+		client: &'a Client<R>,
+		message: message::Message<A, Self>,
+		received: Instant,
+	) -> BoxFuture<'a, Result<()>>
+	where
+		R: Ratelimiter + Clone + Sync + Send + 'static,
+		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		client.handle_message_received(message, received).boxed()
+	}
+
+	fn redeliver(&mut self) {
+		self.redeliveries += 1;
+	}
+}
+
+impl Payload for BatchRequest {
+	fn handle<'a, R, A>(
+		client: &'a Client<R>,
+		message: message::Message<A, Self>,
+		received: Instant,
+	) -> BoxFuture<'a, Result<()>>
+	where
+		R: Ratelimiter + Clone + Sync + Send + 'static,
+		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		client.handle_batch_received(message, received).boxed()
+	}
+
+	fn redeliver(&mut self) {
+		for request in &mut self.requests {
+			request.redeliveries += 1;
+		}
+	}
+}
+
+/// A copy of a message being handled, to publish again if it's stopped before it's finished.
+struct Unfinished<A, V>
+where
+	A: ToSocketAddrs + Clone + Send + Sync + Debug,
+{
+	id: String,
+	event: Bytes,
+	data: Option<V>,
+	broker: RedisBroker<A>,
+}
+
+impl<A, V> Unfinished<A, V>
+where
+	A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
+	V: Payload,
+{
+	fn new(message: &message::Message<A, V>) -> Self {
+		Self {
+			id: message.id.clone(),
+			event: message.event.clone(),
+			data: message.data.clone(),
+			broker: message.broker.clone(),
+		}
+	}
+
+	/// Publish the message again, counting it as redelivered.
+	async fn requeue(self) {
+		let mut data = match self.data {
+			Some(data) => data,
+			None => return,
+		};
+		data.redeliver();
+
+		info!("~~> REQUEUE({}): unfinished at shutdown", self.id);
+		if let Err(e) = self.broker.publish(&self.event, &data).await {
+			warn!("Unable to requeue unfinished message {}: {:?}", self.id, e);
+		}
+	}
+}
 
 #[derive(Debug, Clone)]
 pub struct Client<R> {
-	pub http: reqwest::Client,
+	/// The name of the profile the client runs, which its metrics are labeled with. Empty when the
+	/// proxy runs a single config.
+	pub profile: String,
+	pub http: HttpClients,
 	pub ratelimiter: R,
 	pub api_scheme: Scheme<'static>,
 	pub api_version: u8,
 	pub api_base: String,
+	/// The host to send CDN requests to, with the API scheme.
+	pub cdn_base: String,
+	/// The hosts (with their ports, if not the default) which uploads can be sent to.
+	pub upload_hosts: Arc<[String]>,
+	/// The bot token sent with API requests which don't set their own authorization.
+	pub token: Option<String>,
 	pub timeout: Option<Duration>,
+	/// Timeouts which replace `timeout` for requests to their routes.
+	pub timeouts: Arc<HashMap<String, Duration>>,
+	pub requeue: Option<Requeue>,
+	pub routes: Arc<[RouteRule]>,
+	/// Prefixes of headers which producers may not set, since they're reserved for the proxy.
+	pub reserved_headers: Arc<[String]>,
+	/// Named sets of headers which requests can select with their `profile`.
+	pub header_profiles: Arc<HashMap<String, HashMap<String, String>>>,
+	/// Limits on the size of request queries, which larger queries are rejected for exceeding, and
+	/// the parameters added to them by default.
+	pub query_limits: QueryConfig,
+	/// How headers describing the chain of proxies a request passed through are handled.
+	pub forwarded: ForwardedConfig,
+	/// Which routes requests from the broker can be made to.
+	pub paths: PathsConfig,
+	/// Whether to reject JSON request bodies which aren't well-formed before sending them.
+	pub validate_json: bool,
+	/// Whether to honor requests asking to be echoed back instead of sent.
+	pub echo: bool,
+	/// Whether to check that JSON responses are valid.
+	pub check_response_json: bool,
+	/// Caps the number of distinct buckets requests are grouped into.
+	pub bucket_limit: Option<Arc<BucketLimit>>,
+	/// The bucket hashes Discord has reported for routes, if routes are bucketed by them.
+	pub bucket_hashes: Option<Arc<BucketHashes>>,
+	/// Whether routes are bucketed by their path alone, rather than by method and path.
+	pub path_buckets: bool,
+	/// Whether to bucket requests separately for each API version they're sent with.
+	pub version_buckets: bool,
+	/// When requests were last sent to each bucket, for routes with a minimum spacing.
+	pub spacing: Arc<Spacing>,
+	/// Buckets which are paused, whose requests wait until they're resumed.
+	pub pause: Arc<Pause>,
+	/// Stops messages from being taken from the broker while the proxy is quiesced.
+	pub quiesce: Arc<Quiesce>,
+	/// Messages being handled which can be cancelled.
+	pub cancellations: Arc<Cancellations>,
+	/// Where to read streamed request bodies from.
+	pub body_store: Option<BodyStore>,
+	/// How long to wait before reusing buckets which were ratelimited without any reset info.
+	pub backoff: Option<Arc<Backoff>>,
+	/// Verifies that requests are signed, if they must be.
+	pub signer: Option<Arc<Signer>>,
+	/// Gzips replies which are large, if they should be.
+	pub compression: Option<Compression>,
+	/// How long requests which are dropped before releasing their bucket, such as by timing out,
+	/// have to release it anyways. Dropped requests don't release their bucket if this isn't set.
+	pub release_grace: Option<Duration>,
+	/// How long messages still being handled when consumption is shut down have to finish before
+	/// they're requeued. They're requeued right away if this isn't set.
+	pub shutdown_grace: Option<Duration>,
+	/// Caps the rate of all outgoing requests.
+	pub rate_cap: Option<Arc<RateCap>>,
+	/// How requests received from each additional event are ratelimited. Requests from other
+	/// events are fully ratelimited.
+	pub lanes: Arc<HashMap<String, LaneConfig>>,
+	/// Skips messages which the broker redelivers after they were processed, replaying the first
+	/// reply instead.
+	pub dedup: Option<Dedup>,
+	/// Handles the responses to messages which can't be replied to.
+	pub unreplied: Unreplied,
+	/// Collapses floods of identical request errors in the log.
+	pub error_log: Option<ErrorLog>,
+	pub concurrency: Option<Arc<Semaphore>>,
+	/// How batches of requests are handled.
+	pub batch: BatchConfig,
+	pub reload: Option<watch::Receiver<Reloadable>>,
 }
 
 impl<R> Client<R>
@@ -37,11 +353,45 @@ where
 	R: Ratelimiter + Clone + Sync + Send + 'static,
 {
 	fn create_request(&self, data: &SerializableHttpRequest) -> Result<Request> {
-		let path_str = format!(
-			"/api/v{}/{}",
-			self.api_version,
-			data.path.strip_prefix('/').unwrap_or_default()
-		);
+		let method = Method::from_str(&data.method)?;
+		if !ALLOWED_METHODS.contains(&method) {
+			return Err(Rejection::new(
+				ResponseStatus::InvalidMethod,
+				format!("{} requests aren't allowed", method),
+			)
+			.into());
+		}
+
+		let upload = match data.mode {
+			RequestMode::Upload => Some(self.upload_url(&data.path)?),
+			_ => None,
+		};
+
+		let (data_path, path_query) = match &upload {
+			Some((_, url)) => (url.path(), url.query()),
+			None => match data.path.split_once('?') {
+				Some((path, query)) => (path, Some(query)),
+				None => (data.path.as_str(), None),
+			},
+		};
+
+		let (base, prefix) = match &upload {
+			Some((host, _)) => (host, false),
+			None if data.mode == RequestMode::Cdn => (&self.cdn_base, data.prefix.unwrap_or(false)),
+			None => (&self.api_base, data.prefix.unwrap_or(true)),
+		};
+
+		let path_str = if prefix {
+			format!(
+				"/api/v{}/{}",
+				data.api_version.unwrap_or(self.api_version),
+				data_path.strip_prefix('/').unwrap_or_default()
+			)
+		} else if data_path.starts_with('/') {
+			data_path.to_string()
+		} else {
+			return Err(Rejection::new(ResponseStatus::InvalidPath, "path is not absolute").into());
+		};
 		let mut path: Path = path_str.as_str().try_into()?;
 		path.normalize(false);
 
@@ -49,121 +399,655 @@ where
 		builder
 			.scheme(self.api_scheme.clone())
 			.authority(Some(
-				(&*self.api_base)
+				(&**base)
 					.try_into()
 					.expect("Invalid authority configuration"),
 			))
 			.path(path);
 
-		if let Some(query) = &data.query {
-			let maybe_qs =
-				query
-					.iter()
-					.map(|(k, v)| format!("{}={}", k, v))
-					.reduce(|mut acc, pair| {
-						acc.push('&');
-						acc.push_str(&pair);
-						acc
-					});
+		let mut pairs = merge_query(path_query, data.query.as_ref());
+		if upload.is_none() {
+			self.add_default_query(data_path, &mut pairs);
+		}
+		self.check_query_limits(&pairs)?;
 
-			if let Some(qs) = maybe_qs {
-				let mut query: Query = qs.as_str().try_into()?;
-				query.normalize();
-				builder.query(Some(query.into_owned()));
-			}
+		let maybe_qs = pairs
+			.into_iter()
+			.map(|(k, v)| format!("{}={}", k, v))
+			.reduce(|mut acc, pair| {
+				acc.push('&');
+				acc.push_str(&pair);
+				acc
+			});
+
+		if let Some(qs) = maybe_qs {
+			let mut query: Query = qs.as_str().try_into()?;
+			query.normalize();
+			builder.query(Some(query.into_owned()));
 		}
 
 		let url = builder.build()?;
 
+		let mut headers: HeaderMap = (&*encode_audit_log_reason(&data.headers)).try_into()?;
+		self.strip_reserved_headers(&mut headers);
+		if self.forwarded.strip {
+			for name in FORWARDED_HEADERS {
+				headers.remove(*name);
+			}
+		}
+		if let Some(name) = &data.profile {
+			let profile = self.header_profiles.get(name).ok_or_else(|| {
+				Rejection::new(
+					ResponseStatus::InvalidHeaders,
+					format!("unknown header profile \"{}\"", name),
+				)
+			})?;
+
+			let mut profile_headers: HeaderMap = profile.try_into()?;
+			profile_headers.extend(headers);
+			headers = profile_headers;
+		}
+		if let Some(via) = &self.forwarded.via {
+			headers.append(VIA, format!("1.1 {}", via).try_into()?);
+		}
+		// the token is only for Discord's API, so it isn't sent to the CDN or upload hosts
+		if let Some(token) = &self.token {
+			if data.mode == RequestMode::Api && !headers.contains_key(AUTHORIZATION) {
+				let mut value: HeaderValue = format!("Bot {}", token).try_into()?;
+				value.set_sensitive(true);
+				headers.insert(AUTHORIZATION, value);
+			}
+		}
+
+		let body = match &data.files {
+			Some(files) => Some(Cow::Owned(RequestBody::with_files(
+				data.body.as_ref(),
+				files,
+			)?)),
+			None => data.body.as_ref().map(Cow::Borrowed),
+		};
+		let encoded = match &body {
+			Some(body) => {
+				let (content_type, body) = body.encode()?;
+				if let Some(content_type) = content_type {
+					headers.insert(CONTENT_TYPE, content_type.try_into()?);
+				}
+				Some(body)
+			}
+			None => None,
+		};
+
+		// raw bodies are sent with the requested content type, unless a header already sets one
+		let raw = matches!(body.as_deref(), Some(RequestBody::Raw(_))) || data.body_key.is_some();
+		if raw && !headers.contains_key(CONTENT_TYPE) {
+			let content_type = data.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE);
+			headers.insert(CONTENT_TYPE, content_type.try_into()?);
+		}
+
+		// typed bodies are always encoded correctly, so only raw bodies need validating
+		if let Some(RequestBody::Raw(body)) = body.as_deref() {
+			if self.validate_json && is_json(&headers) {
+				serde_json::from_slice::<IgnoredAny>(body)?;
+			}
+		}
+
 		let mut req_builder = self
 			.http
-			.request(Method::from_str(&data.method)?, &url.to_string())
-			.headers((&data.headers).try_into()?);
+			.get(data.mode)
+			.request(method, &url.to_string())
+			.headers(headers);
+		if let Some(http_timeout) = data.http_timeout {
+			req_builder = req_builder.timeout(http_timeout);
+		}
 
-		if let Some(body) = data.body.clone() {
+		if let Some(body) = encoded {
 			req_builder = req_builder.body(body);
 		}
 
-		Ok(req_builder
+		if let Some(key) = &data.body_key {
+			if data.body.is_some() || data.files.is_some() {
+				return Err(Rejection::new(
+					ResponseStatus::InvalidRequestFormat,
+					"body_key can't be set with body or files",
+				)
+				.into());
+			}
+
+			let store = self.body_store.as_ref().ok_or_else(|| {
+				Rejection::new(
+					ResponseStatus::InvalidRequestFormat,
+					"streamed bodies aren't supported",
+				)
+			})?;
+			req_builder = req_builder.body(Body::wrap_stream(store.stream(key.clone())));
+		}
+
+		let req = req_builder
 			.build()
-			.context("Unable to build HTTP request")?)
+			.context("Unable to build HTTP request")?;
+		debug!(url = %redact_url(req.url()), "built request");
+		Ok(req)
 	}
 
-	#[instrument(level = "trace", skip(self), ret)]
-	async fn claim(&self, data: &SerializableHttpRequest) -> Result<(Request, String)> {
-		let req = self.create_request(data)?;
-		let bucket = make_route(req.url().path())?;
-		self.ratelimiter.claim(bucket.clone()).await?;
+	/// Parse the full URL of an upload, returning it along with its host, which must be one of the
+	/// allowed upload hosts.
+	fn upload_url(&self, url: &str) -> Result<(String, reqwest::Url), Rejection> {
+		let url = reqwest::Url::parse(url)
+			.map_err(|e| Rejection::new(ResponseStatus::InvalidPath, e.to_string()))?;
+
+		let host = match (url.host_str(), url.port()) {
+			(Some(host), Some(port)) => format!("{}:{}", host, port),
+			(Some(host), None) => host.to_string(),
+			(None, _) => {
+				return Err(Rejection::new(
+					ResponseStatus::InvalidPath,
+					"upload URL has no host",
+				))
+			}
+		};
+
+		if url.scheme() != self.api_scheme.as_str() || !self.upload_hosts.contains(&host) {
+			return Err(Rejection::new(
+				ResponseStatus::InvalidPath,
+				format!(
+					"\"{}://{}\" isn't an allowed upload host",
+					url.scheme(),
+					host
+				),
+			));
+		}
 
-		Ok((req, bucket))
+		Ok((host, url))
 	}
 
-	#[instrument(level = "debug", skip(self))]
-	async fn do_request<A>(
+	/// Add the configured default query parameters for the path's route, except those the request
+	/// already has.
+	fn add_default_query(&self, path: &str, pairs: &mut Vec<(String, String)>) {
+		if self.query_limits.defaults.is_empty() {
+			return;
+		}
+		let route = match make_route_with_rules(path, &self.routes) {
+			Ok(route) => route,
+			Err(_) => return,
+		};
+
+		for defaults in self
+			.query_limits
+			.defaults
+			.iter()
+			.filter(|defaults| defaults.matches(&route))
+		{
+			for (key, value) in &defaults.params {
+				let key = utf8_percent_encode(key, QUERY_COMPONENT).to_string();
+				if !pairs.iter().any(|(k, _)| *k == key) {
+					let value = utf8_percent_encode(value, QUERY_COMPONENT).to_string();
+					pairs.push((key, value));
+				}
+			}
+		}
+	}
+
+	/// Reject queries with more parameters or a longer query string than are allowed.
+	fn check_query_limits(&self, pairs: &[(String, String)]) -> Result<(), Rejection> {
+		if pairs.len() > self.query_limits.max_params {
+			return Err(Rejection::new(
+				ResponseStatus::InvalidQuery,
+				format!(
+					"query has {} parameters, more than the limit of {}",
+					pairs.len(),
+					self.query_limits.max_params
+				),
+			));
+		}
+
+		// each pair is joined with "=", and pairs are separated by "&"
+		let length = pairs
+			.iter()
+			.map(|(k, v)| k.len() + v.len() + 1)
+			.sum::<usize>()
+			+ pairs.len().saturating_sub(1);
+		if length > self.query_limits.max_length {
+			return Err(Rejection::new(
+				ResponseStatus::InvalidQuery,
+				format!(
+					"query is {} bytes long, more than the limit of {}",
+					length, self.query_limits.max_length
+				),
+			));
+		}
+
+		Ok(())
+	}
+
+	/// Remove any headers starting with a reserved prefix, so producers can't spoof them.
+	fn strip_reserved_headers(&self, headers: &mut HeaderMap) {
+		let reserved = headers
+			.keys()
+			.filter(|name| {
+				self.reserved_headers.iter().any(|prefix| {
+					matches!(
+						name.as_str().get(..prefix.len()),
+						Some(start) if start.eq_ignore_ascii_case(prefix)
+					)
+				})
+			})
+			.cloned()
+			.collect::<Vec<_>>();
+
+		for name in reserved {
+			headers.remove(name);
+		}
+	}
+
+	/// How long a request has to release its bucket if it's dropped. Requests which can be
+	/// cancelled always release it.
+	fn release_grace_for(&self, cancellable: bool) -> Option<Duration> {
+		if cancellable {
+			Some(self.release_grace.unwrap_or(CANCELLED_RELEASE_GRACE))
+		} else {
+			self.release_grace
+		}
+	}
+
+	/// Claim the request's bucket, releasing it within `release_grace` if the request is dropped
+	/// before it releases it itself.
+	#[instrument(level = "trace", skip(self, req), ret)]
+	async fn claim_with_grace(
 		&self,
-		message: &message::Message<A, SerializableHttpRequest>,
 		data: &SerializableHttpRequest,
-	) -> Result<SerializableHttpResponse>
-	where
-		A: ToSocketAddrs + Clone + Send + Sync + Debug,
-	{
+		req: Request,
+		lane: Option<&LaneConfig>,
+		release_grace: Option<Duration>,
+	) -> Result<Claimed> {
 		#[cfg(feature = "metrics")]
-		let req_labels: [&str; 2] = [&data.method, &data.path];
+		let req_labels: [&str; 3] = [&self.profile, &data.method, &data.path];
+		#[cfg(feature = "metrics")]
+		let _latency = LatencyTracker::new(&RATELIMIT_LATENCY, &req_labels);
 
-		let claim = {
-			#[cfg(feature = "metrics")]
-			let _ = LatencyTracker::new(&RATELIMIT_LATENCY, &req_labels);
-			self.claim(data).await
+		// uploads go to storage rather than Discord, so they aren't subject to its limits
+		if data.mode == RequestMode::Upload {
+			return Ok(Claimed {
+				req,
+				route: UPLOAD_BUCKET.to_string(),
+				bucket: UPLOAD_BUCKET.to_string(),
+				waited: Duration::ZERO,
+				holds_bucket: false,
+				global: false,
+				guard: None,
+			});
+		}
+
+		let (route, version) = self.route(&req)?;
+		// interaction responses aren't subject to the global limit
+		let global = !route.starts_with("/interactions/");
+		let mut bucket = match &self.bucket_hashes {
+			Some(hashes) => hashes.bucket(route.clone()),
+			None => route.clone(),
 		};
+		if let Some(version) = version.filter(|_| self.version_buckets) {
+			bucket = format!("v{}:{}", version, bucket);
+		}
+		if let Some(limit) = &self.bucket_limit {
+			bucket = limit.bucket(bucket);
+		}
 
-		message.ack().await?;
-		let (req, bucket) = claim?;
+		#[cfg(feature = "metrics")]
+		let _waiting = GaugeGuard::new(RATELIMIT_WAITING.with_label_values(&[&self.profile]));
+		let start = Instant::now();
+		self.pause.wait(&route).await;
+		if let Some(rate_cap) = &self.rate_cap {
+			rate_cap.acquire(&self.profile).await?;
+		}
+
+		// the bucket and the global limit are claimed together, so neither is held while the other
+		// can't be claimed
+		let holds_bucket = match lane.map(|lane| (lane.ratelimit, lane.max_wait)) {
+			None | Some((RatelimitStrategy::Full, _)) => {
+				self.ratelimiter
+					.claim_all(vec![bucket.clone()], global, None)
+					.await?;
+				true
+			}
+			Some((RatelimitStrategy::Relaxed, max_wait)) => {
+				let deadline = Instant::now() + max_wait;
+				match self
+					.ratelimiter
+					.claim_all(vec![bucket.clone()], global, Some(deadline))
+					.await
+				{
+					Ok(()) => true,
+					Err(e) if e.is::<ClaimTimedOut>() => {
+						warn!(
+							"Sending without \"{}\" after waiting {:?}",
+							bucket, max_wait
+						);
+						if global {
+							self.ratelimiter.claim_global().await?;
+						}
+						false
+					}
+					Err(e) => return Err(e),
+				}
+			}
+			Some((RatelimitStrategy::None, _)) => {
+				if global {
+					self.ratelimiter.claim_global().await?;
+				}
+				false
+			}
+		};
+		if let Some(min) = min_spacing(&route, &self.routes).filter(|_| holds_bucket) {
+			self.spacing.wait(&bucket, min).await;
+		}
+
+		let guard = release_grace.filter(|_| holds_bucket).map(|grace| {
+			let ratelimiter = self.ratelimiter.clone();
+			let release_bucket = bucket.clone();
+			ReleaseGuard {
+				bucket: bucket.clone(),
+				grace,
+				release: Some(
+					async move {
+						ratelimiter
+							.release(release_bucket, RatelimitInfo::default())
+							.await
+					}
+					.boxed(),
+				),
+			}
+		});
+
+		Ok(Claimed {
+			req,
+			route,
+			bucket,
+			waited: start.elapsed(),
+			holds_bucket,
+			global,
+			guard,
+		})
+	}
+
+	/// The route of an API request, as bucketed by its method and path, and the API version it's
+	/// sent with.
+	fn route(&self, req: &Request) -> Result<(String, Option<u8>)> {
+		let (version, path) = split_api_version(req.url().path());
+		let route = if self.path_buckets {
+			make_route_with_rules(path, &self.routes)?
+		} else {
+			make_route_with_method(req.method(), path, &self.routes)?
+		};
+		Ok((route, version))
+	}
+
+	/// The configured timeout for requests to the route, if any.
+	fn timeout_for(&self, route: Option<&str>) -> Option<Duration> {
+		route
+			.and_then(|route| self.timeouts.get(route))
+			.copied()
+			.or(self.timeout)
+	}
+
+	/// Make a request without an associated broker message, waiting on the ratelimiter as needed.
+	#[instrument(level = "debug", skip(self), fields(route, otel.name))]
+	pub async fn request(
+		&self,
+		data: &SerializableHttpRequest,
+	) -> Result<SerializableHttpResponse> {
+		#[cfg(feature = "otel")]
+		super::otel::set_parent(&tracing::Span::current(), data);
+
+		let req = self.create_request(data)?;
+		#[cfg(feature = "metrics")]
+		let _in_flight = GaugeGuard::new(REQUESTS_IN_FLIGHT.with_label_values(&[&self.profile]));
+		self.send(data, req, None, self.release_grace).await
+	}
+
+	/// The lane requests received from the event are handled in, if it's one of the lanes.
+	fn lane(&self, event: &[u8]) -> Option<&LaneConfig> {
+		std::str::from_utf8(event)
+			.ok()
+			.and_then(|event| self.lanes.get(event))
+	}
+
+	#[instrument(level = "debug", skip(self, req), fields(route, otel.name))]
+	async fn do_request(
+		&self,
+		data: &SerializableHttpRequest,
+		req: Request,
+		lane: Option<&LaneConfig>,
+		cancellable: bool,
+	) -> Result<SerializableHttpResponse> {
+		#[cfg(feature = "metrics")]
+		let _in_flight = GaugeGuard::new(REQUESTS_IN_FLIGHT.with_label_values(&[&self.profile]));
+		self.send(data, req, lane, self.release_grace_for(cancellable))
+			.await
+	}
+
+	/// Claim the request's bucket and send it, sending it again after a 429 as many times as
+	/// `backoff` allows. The 429 holds the bucket closed until it's safe to try again, so retries
+	/// wait by claiming it; requests which don't hold their bucket aren't retried.
+	async fn send(
+		&self,
+		data: &SerializableHttpRequest,
+		mut req: Request,
+		lane: Option<&LaneConfig>,
+		release_grace: Option<Duration>,
+	) -> Result<SerializableHttpResponse> {
+		let max_retries = self.backoff.as_ref().map_or(0, |backoff| backoff.retries());
+		let mut retries = 0;
+		loop {
+			// streamed bodies can't be sent again
+			let retry = (retries < max_retries).then(|| req.try_clone()).flatten();
+			let claimed = self
+				.claim_with_grace(data, req, lane, release_grace)
+				.await?;
+			record_route(&claimed);
+			let retry = retry.filter(|_| claimed.holds_bucket);
+
+			let res = self.execute(data, claimed).await?;
+			match retry {
+				Some(next) if res.status == 429 => {
+					retries += 1;
+					debug!("Ratelimited: retrying ({}/{})", retries, max_retries);
+					req = next;
+				}
+				_ => return Ok(res),
+			}
+		}
+	}
+
+	#[instrument(level = "trace", skip(self, claimed))]
+	async fn execute(
+		&self,
+		data: &SerializableHttpRequest,
+		claimed: Claimed,
+	) -> Result<SerializableHttpResponse> {
+		let Claimed {
+			req,
+			route,
+			bucket,
+			waited,
+			holds_bucket,
+			global,
+			mut guard,
+		} = claimed;
+
+		#[cfg(feature = "metrics")]
+		let req_labels: [&str; 3] = [&self.profile, &data.method, &data.path];
 
 		#[cfg(feature = "metrics")]
 		REQUESTS_TOTAL
 			.get_metric_with_label_values(&req_labels)?
 			.inc();
 
+		// the connection is busy until the response body is read
+		#[cfg(feature = "metrics")]
+		let in_flight = GaugeGuard::new(HTTP_IN_FLIGHT.with_label_values(&[&self.profile]));
+		let sent = Instant::now();
 		let res = {
 			#[cfg(feature = "metrics")]
-			let _ = LatencyTracker::new(&REQUEST_LATENCY, &req_labels);
-			self.http.execute(req).await
+			let _latency = LatencyTracker::new(&REQUEST_LATENCY, &req_labels);
+			self.http.get(data.mode).execute(req).await
 		};
+		let ttfb = sent.elapsed();
+
+		let mut info: RatelimitInfo = res.as_ref().into();
+		if let (Some(hashes), Some(hash)) = (&self.bucket_hashes, &info.bucket) {
+			hashes.learn(&route, hash);
+		}
+		if let Some(backoff) = &self.backoff {
+			backoff.apply(&bucket, res.as_ref(), &mut info);
+		}
+		if bucket == OVERFLOW_BUCKET {
+			// routes in the overflow bucket can have different limits, so only allow one at a time
+			info.limit = Some(1);
+		}
+
+		let debug = data.debug.then(|| RatelimitDebug {
+			bucket: bucket.clone(),
+			waited,
+			info: info.clone(),
+		});
 
-		self.ratelimiter
-			.release(bucket, res.as_ref().into())
-			.await?;
+		if let Some(guard) = &mut guard {
+			guard.disarm();
+		}
+		if global && info.global {
+			// the request's bucket wasn't what limited it, so it's released as it was
+			self.ratelimiter.release_global(info).await?;
+			info = RatelimitInfo::default();
+		}
+		if holds_bucket {
+			self.ratelimiter.release(bucket.clone(), info).await?;
+		}
 		let res = res?;
 
 		#[cfg(feature = "metrics")]
 		{
 			let status = res.status();
-			let res_labels = [&data.method, &data.path, status.as_str()];
+			let res_labels = [&self.profile, &data.method, &data.path, status.as_str()];
 			RESPONSES_TOTAL
 				.get_metric_with_label_values(&res_labels)?
 				.inc();
+
+			if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+				let scope = ratelimit_scope(res.headers());
+				RATELIMITED_TOTAL
+					.get_metric_with_label_values(&[
+						&self.profile,
+						&data.method,
+						&data.path,
+						scope,
+					])?
+					.inc();
+			}
 		}
 
-		Ok(SerializableHttpResponse {
-			status: res.status().as_u16(),
-			headers: res
-				.headers()
-				.into_iter()
-				.map(|(name, value)| {
-					(
-						name.as_str().to_string(),
-						value.to_str().unwrap().to_string(),
-					)
+		let status = res.status().as_u16();
+		let headers = res.headers().clone().into();
+		let url = res.url().to_string();
+		// Cloudflare bans are HTML pages, so they can only be told apart once the body is read
+		let may_be_banned = matches!(status, 403 | 429) && !is_json(res.headers());
+		let retry_after = res
+			.headers()
+			.get(RETRY_AFTER)
+			.and_then(|value| value.to_str().ok()?.parse().ok())
+			.map(Duration::from_secs);
+		let check_json = self.check_response_json && !data.resolve_only && is_json(res.headers());
+		let body = if data.resolve_only {
+			// dropping the response closes its connection without downloading the body
+			drop(res);
+			Bytes::new()
+		} else {
+			res.bytes().await?
+		};
+		let elapsed = sent.elapsed();
+		#[cfg(feature = "metrics")]
+		drop(in_flight);
+		let json = check_json && serde_json::from_slice::<IgnoredAny>(&body).is_ok();
+
+		#[cfg(feature = "metrics")]
+		{
+			RESPONSE_TTFB
+				.get_metric_with_label_values(&req_labels)?
+				.observe(ttfb.as_secs_f64());
+			RESPONSE_ELAPSED
+				.get_metric_with_label_values(&req_labels)?
+				.observe(elapsed.as_secs_f64());
+		}
+
+		if may_be_banned && is_cloudflare_ban(&body) {
+			let wait = retry_after.unwrap_or(CLOUDFLARE_BAN_WAIT);
+			warn!(
+				"Banned by Cloudflare: holding the global limit closed for {:?}",
+				wait
+			);
+			self.ratelimiter
+				.release_global(RatelimitInfo {
+					global: true,
+					resets_in: Some(wait.as_millis() as u64),
+					..Default::default()
 				})
-				.collect(),
-			url: res.url().to_string(),
-			body: res.bytes().await?,
+				.await?;
+			return Err(Rejection::new(
+				ResponseStatus::CloudflareBanned,
+				"banned by Cloudflare for too many invalid requests",
+			)
+			.into());
+		}
+
+		Ok(SerializableHttpResponse {
+			status,
+			headers,
+			url,
+			body,
+			bucket: Some(bucket),
+			debug,
+			ttfb_ms: data.timing.then_some(ttfb.as_millis() as u64),
+			elapsed_ms: data.timing.then_some(elapsed.as_millis() as u64),
+			json,
 		})
 	}
 
+	/// A copy of this client with the latest reloadable settings applied.
+	fn reloaded(&self) -> Self {
+		let mut client = self.clone();
+		if let Some(reload) = &self.reload {
+			let settings = reload.borrow();
+			client.timeout = settings.timeout;
+			client.timeouts = Arc::clone(&settings.timeouts);
+			client.routes = Arc::clone(&settings.routes);
+			client.reserved_headers = Arc::clone(&settings.reserved_headers);
+			client.header_profiles = Arc::clone(&settings.header_profiles);
+			client.query_limits = settings.query_limits.clone();
+			client.forwarded = settings.forwarded.clone();
+			client.paths = settings.paths.clone();
+			client.validate_json = settings.validate_json;
+			client.echo = settings.echo;
+			client.check_response_json = settings.check_response_json;
+		}
+
+		client
+	}
+
+	/// Wait for a slot to handle a message in, if concurrency is limited.
+	async fn acquire_permit(&self) -> Result<Option<OwnedSemaphorePermit>> {
+		match &self.concurrency {
+			Some(semaphore) => {
+				#[cfg(feature = "metrics")]
+				let _timer = CONCURRENCY_WAIT
+					.with_label_values(&[&self.profile])
+					.start_timer();
+				Ok(Some(Arc::clone(semaphore).acquire_owned().await?))
+			}
+			None => Ok(None),
+		}
+	}
+
 	pub async fn consume_stream<A>(
 		&self,
-		mut stream: impl TryStream<
+		stream: impl TryStream<
 				Ok = message::Message<A, SerializableHttpRequest>,
 				Error = rustacles_brokers::error::Error,
 			> + Unpin,
@@ -171,36 +1055,326 @@ where
 	where
 		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
 	{
-		while let Some(message) = stream.try_next().await? {
-			let client = self.clone();
-			match message.timeout_at {
-				Some(timeout) => {
-					let duration = timeout.duration_since(SystemTime::now()).expect("duration");
-					let instant = Instant::now() + duration;
-					spawn(async move {
-						timeout_at(instant, client.handle_message(message)).await;
-					});
-				}
-				None => {
-					spawn(async move {
-						client.handle_message(message).await;
-					});
-				}
-			}
-		}
+		self.consume_stream_until(stream, future::pending()).await
+	}
 
-		Ok(())
+	/// Consume messages until `shutdown` resolves, then wait up to the shutdown grace for those
+	/// still being handled to finish. Any which don't are stopped and published again.
+	pub async fn consume_stream_until<A>(
+		&self,
+		stream: impl TryStream<
+				Ok = message::Message<A, SerializableHttpRequest>,
+				Error = rustacles_brokers::error::Error,
+			> + Unpin,
+		shutdown: impl Future<Output = ()>,
+	) -> Result<()>
+	where
+		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		self.consume(stream, shutdown).await
 	}
 
-	#[instrument(level = "debug", skip(self))]
-	pub async fn handle_message<A>(
+	/// Like [`Client::consume_stream_until`], for messages carrying batches of requests.
+	pub async fn consume_batches_until<A>(
 		&self,
-		message: message::Message<A, SerializableHttpRequest>,
+		stream: impl TryStream<
+				Ok = message::Message<A, BatchRequest>,
+				Error = rustacles_brokers::error::Error,
+			> + Unpin,
+		shutdown: impl Future<Output = ()>,
 	) -> Result<()>
 	where
-		A: ToSocketAddrs + Clone + Send + Sync + Debug,
+		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
 	{
-		message.ack().await?;
+		self.consume(stream, shutdown).await
+	}
+
+	/// Consume cancellations until `shutdown` resolves. Each is the cancel id of a message being
+	/// handled, which is cancelled.
+	pub async fn consume_cancellations_until<A>(
+		&self,
+		mut stream: impl TryStream<Ok = message::Message<A, String>, Error = rustacles_brokers::error::Error>
+			+ Unpin,
+		shutdown: impl Future<Output = ()>,
+	) -> Result<()>
+	where
+		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		tokio::pin!(shutdown);
+		loop {
+			let message = tokio::select! {
+				biased;
+				_ = &mut shutdown => break,
+				next = stream.try_next() => match next? {
+					Some(message) => message,
+					None => break,
+				},
+			};
+			message.ack().await?;
+
+			if let Some(id) = &message.data {
+				let cancelled = self.cancellations.cancel(id);
+				info!(
+					"--> CANCEL({}): {}",
+					id,
+					if cancelled { "cancelled" } else { "not found" }
+				);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Consume bucket resets until `shutdown` resolves. Each is the name of a bucket, which is
+	/// reset to its default state in the ratelimiter.
+	pub async fn consume_resets_until<A>(
+		&self,
+		mut stream: impl TryStream<Ok = message::Message<A, String>, Error = rustacles_brokers::error::Error>
+			+ Unpin,
+		shutdown: impl Future<Output = ()>,
+	) -> Result<()>
+	where
+		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		tokio::pin!(shutdown);
+		loop {
+			let message = tokio::select! {
+				biased;
+				_ = &mut shutdown => break,
+				next = stream.try_next() => match next? {
+					Some(message) => message,
+					None => break,
+				},
+			};
+			message.ack().await?;
+
+			if let Some(bucket) = &message.data {
+				info!("--> RESET({})", bucket);
+				if let Err(e) = self.ratelimiter.reset_bucket(bucket.clone()).await {
+					warn!("Unable to reset \"{}\": {:?}", bucket, e);
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Consume pauses and resumes of buckets until `shutdown` resolves.
+	pub async fn consume_pauses_until<A>(
+		&self,
+		mut stream: impl TryStream<
+				Ok = message::Message<A, PauseControl>,
+				Error = rustacles_brokers::error::Error,
+			> + Unpin,
+		shutdown: impl Future<Output = ()>,
+	) -> Result<()>
+	where
+		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		tokio::pin!(shutdown);
+		loop {
+			let message = tokio::select! {
+				biased;
+				_ = &mut shutdown => break,
+				next = stream.try_next() => match next? {
+					Some(message) => message,
+					None => break,
+				},
+			};
+			message.ack().await?;
+
+			if let Some(control) = &message.data {
+				match control {
+					PauseControl::Pause(pattern) => info!("--> PAUSE({})", pattern),
+					PauseControl::Resume(pattern) => info!("--> RESUME({})", pattern),
+				}
+				self.pause.apply(control);
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn consume<A, V>(
+		&self,
+		mut stream: impl TryStream<Ok = message::Message<A, V>, Error = rustacles_brokers::error::Error>
+			+ Unpin,
+		shutdown: impl Future<Output = ()>,
+	) -> Result<()>
+	where
+		A: 'static + ToSocketAddrs + Clone + Send + Sync + Debug,
+		V: Payload,
+	{
+		tokio::pin!(shutdown);
+		let (abort, aborted) = watch::channel(false);
+		#[cfg(feature = "metrics")]
+		let backlog = Arc::new(Backlog::default());
+		#[cfg(feature = "metrics")]
+		let _updater = backlog.start_updating(
+			OLDEST_UNACKED_AGE.with_label_values(&[&self.profile]),
+			BACKLOG_UPDATE_PERIOD,
+		);
+
+		let shutting_down = loop {
+			let permit = tokio::select! {
+				biased;
+				_ = &mut shutdown => break true,
+				permit = async {
+					self.quiesce.wait(false).await;
+					self.acquire_permit().await
+				} => permit?,
+			};
+			// stop waiting for a message once quiesced; the stream keeps any it's partway through
+			// reading for when the proxy resumes
+			let next = tokio::select! {
+				biased;
+				_ = &mut shutdown => break true,
+				_ = self.quiesce.wait(true) => continue,
+				next = stream.try_next() => next?,
+			};
+			let message = match next {
+				Some(message) => message,
+				None => break false,
+			};
+			let received = Instant::now();
+			let handling = self.quiesce.handle();
+			#[cfg(feature = "metrics")]
+			let tracked = backlog.track();
+
+			let client = self.reloaded();
+			let unfinished = Unfinished::new(&message);
+			let mut aborted = aborted.clone();
+			spawn(async move {
+				let handled = async move {
+					match message.timeout_at {
+						Some(timeout) => {
+							let duration =
+								timeout.duration_since(SystemTime::now()).expect("duration");
+							let instant = Instant::now() + duration;
+							let _ =
+								timeout_at(instant, V::handle(&client, message, received)).await;
+						}
+						None => {
+							let _ = V::handle(&client, message, received).await;
+						}
+					}
+				};
+
+				tokio::select! {
+					_ = handled => {}
+					Ok(()) = aborted.changed() => unfinished.requeue().await,
+				}
+				#[cfg(feature = "metrics")]
+				drop(tracked);
+				drop(handling);
+				drop(permit);
+			});
+		};
+
+		if shutting_down {
+			drop(aborted);
+			self.shut_down(abort).await;
+		}
+
+		Ok(())
+	}
+
+	/// Wait up to the shutdown grace for messages being handled to finish, then requeue the rest.
+	/// Each message's handler holds a receiver of `abort` until it's finished.
+	async fn shut_down(&self, abort: watch::Sender<bool>) {
+		let grace = self.shutdown_grace.unwrap_or_default();
+		info!(
+			"Shutting down: waiting up to {:?} for {} messages to finish",
+			grace,
+			abort.receiver_count()
+		);
+		if time::timeout(grace, abort.closed()).await.is_ok() {
+			return;
+		}
+
+		warn!(
+			"Requeueing {} messages which didn't finish",
+			abort.receiver_count()
+		);
+		let _ = abort.send(true);
+		abort.closed().await;
+	}
+
+	/// Build the request to send, checking that it may be sent, along with how long it may take.
+	fn prepare(
+		&self,
+		data: &SerializableHttpRequest,
+	) -> Result<(Request, Option<Duration>), Rejection> {
+		// the deadline is absolute, so how much of it is left depends on when this is reached
+		let until_deadline = match data
+			.deadline
+			.map(|deadline| deadline.duration_since(SystemTime::now()))
+		{
+			Some(Ok(until_deadline)) if !until_deadline.is_zero() => Some(until_deadline),
+			Some(_) => {
+				return Err(Rejection::new(
+					ResponseStatus::Expired,
+					"request deadline has passed",
+				))
+			}
+			None => None,
+		};
+
+		let req = self.create_request(data)?;
+
+		// uploads aren't sent to Discord, so they're never restricted to its routes
+		let route = match data.mode {
+			RequestMode::Upload => None,
+			_ => self.route(&req).ok().map(|(route, _)| route),
+		};
+		if let Some(false) = route.as_ref().map(|route| self.paths.allows(route)) {
+			return Err(Rejection::new(
+				ResponseStatus::RouteNotAllowed,
+				"route is not allowed by the proxy",
+			));
+		}
+
+		let timeout = [
+			self.timeout_for(route.as_deref()),
+			data.timeout,
+			until_deadline,
+		]
+		.iter()
+		.flatten()
+		.min()
+		.copied();
+		Ok((req, timeout))
+	}
+
+	pub async fn handle_message<A>(
+		&self,
+		message: message::Message<A, SerializableHttpRequest>,
+	) -> Result<()>
+	where
+		A: ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		self.handle_message_received(message, Instant::now()).await
+	}
+
+	/// Like [`Client::handle_message`], for a message received from the broker at `received`,
+	/// which the ack delay is measured from.
+	#[instrument(level = "debug", skip(self))]
+	#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+	pub async fn handle_message_received<A>(
+		&self,
+		message: message::Message<A, SerializableHttpRequest>,
+		received: Instant,
+	) -> Result<()>
+	where
+		A: ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		// the message is only acked here: from now on, its outcome is replied to, even if it's
+		// rejected before being sent
+		message.ack().await?;
+		#[cfg(feature = "metrics")]
+		ACK_DELAY
+			.with_label_values(&[&self.profile])
+			.observe(received.elapsed().as_secs_f64());
 
 		let data = match message.data {
 			Some(ref data) => data,
@@ -209,29 +1383,1904 @@ where
 				return Ok(());
 			}
 		};
+		#[cfg(feature = "otel")]
+		super::otel::set_parent(&tracing::Span::current(), data);
 		info!("--> REQ({}): {}", message.id, data);
 
-		let timeout = data.timeout;
-		let req = self.do_request(&message, &data);
+		if let Some(signer) = &self.signer {
+			if let Err(rejection) = signer.verify(data) {
+				return self.reject(&message, data, rejection).await;
+			}
+		}
 
-		let body = if let Some(min_timeout) = self.timeout.min(timeout) {
-			time::timeout(min_timeout, req).await?
-		} else {
-			req.await
+		if let Some(dedup) = &self.dedup {
+			if let Some(reply) = dedup.get(&message.id).await? {
+				info!("<-- DUPLICATE({})", message.id);
+				if !data.no_reply {
+					self.reply(&message, &reply).await?;
+				}
+				return Ok(());
+			}
+		}
+
+		let (req, timeout) = match self.prepare(data) {
+			Ok(prepared) => prepared,
+			Err(rejection) => return self.reject(&message, data, rejection).await,
+		};
+
+		if data.echo {
+			let echo = match self.echo(data, &req) {
+				Ok(echo) => echo,
+				Err(e) => return self.reject(&message, data, e.into()).await,
+			};
+			info!("<-- ECHO({})", message.id);
+			self.reply(&message, &RequestResponse::from(Ok(echo)))
+				.await?;
+			return Ok(());
+		}
+
+		let cancel = data
+			.cancel_id
+			.as_deref()
+			.map(|id| self.cancellations.register(id));
+		let req = self.do_request(data, req, self.lane(&message.event), cancel.is_some());
+		// a request which times out is replied to with an error, like any other failure
+		let req = async {
+			match timeout {
+				Some(timeout) => time::timeout(timeout, req)
+					.await
+					.map_err(anyhow::Error::from)
+					.and_then(|res| res),
+				None => req.await,
+			}
+		};
+
+		let mut body = match &cancel {
+			Some(cancel) => tokio::select! {
+				biased;
+				_ = cancel.cancelled() => {
+					info!("<-- CANCELLED({})", message.id);
+					return Ok(());
+				}
+				body = req => body,
+			},
+			None => req.await,
 		};
 
 		match &body {
 			Ok(res) => info!("<-- RES({}): {}", message.id, res),
-			Err(e) => warn!("<-- ERR({}): {:?}", message.id, e),
+			Err(e) => match &self.error_log {
+				Some(log) => log.log(format!("{:#}", e), || {
+					format!("<-- ERR({}): {:?}", message.id, e)
+				}),
+				None => warn!("<-- ERR({}): {:?}", message.id, e),
+			},
+		}
+
+		if let Some(requeue) = &self.requeue {
+			if requeue.should_requeue(data, &body) {
+				info!("~~> REQUEUE({}): in {:?}", message.id, requeue.delay);
+				requeue.schedule(data.clone());
+				return Ok(());
+			}
+
+			if requeue.is_poisoned(data, &body) {
+				warn!("<-- POISON({}): {}", message.id, data);
+				requeue.poison(data).await;
+				body = Err(PoisonedError {
+					redeliveries: data.redeliveries,
+					cause: match &body {
+						Ok(res) => format!("status {}", res.status),
+						Err(e) => e.to_string(),
+					},
+				}
+				.into());
+			}
 		}
 
 		let body = RequestResponse::<SerializableHttpResponse>::from(body);
 
-		message
-			.reply(&body)
+		if let Some(dedup) = &self.dedup {
+			if let Err(e) = dedup.store(&message.id, &body).await {
+				warn!("Unable to remember reply to {}: {:?}", message.id, e);
+			}
+		}
+
+		if data.no_reply {
+			return Ok(());
+		}
+
+		self.reply(&message, &body).await
+	}
+
+	/// Handle a message with a batch of requests, replying with their responses in order.
+	pub async fn handle_batch<A>(&self, message: message::Message<A, BatchRequest>) -> Result<()>
+	where
+		A: ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		self.handle_batch_received(message, Instant::now()).await
+	}
+
+	/// Like [`Client::handle_batch`], for a message received from the broker at `received`, which
+	/// the ack delay is measured from.
+	#[instrument(level = "debug", skip(self))]
+	#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+	pub async fn handle_batch_received<A>(
+		&self,
+		message: message::Message<A, BatchRequest>,
+		received: Instant,
+	) -> Result<()>
+	where
+		A: ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		message.ack().await?;
+		#[cfg(feature = "metrics")]
+		ACK_DELAY
+			.with_label_values(&[&self.profile])
+			.observe(received.elapsed().as_secs_f64());
+
+		let batch = match message.data {
+			Some(ref batch) => batch,
+			None => {
+				warn!("Message missing data");
+				return Ok(());
+			}
+		};
+		info!(
+			"--> BATCH({}): {} requests",
+			message.id,
+			batch.requests.len()
+		);
+
+		let cancel = batch
+			.cancel_id
+			.as_deref()
+			.map(|id| self.cancellations.register(id));
+		let responses = self
+			.batch(batch, self.lane(&message.event), cancel.as_ref())
+			.await;
+		info!(
+			"<-- BATCH({}): {} of {} succeeded",
+			message.id,
+			responses.iter().filter(|res| succeeded(res)).count(),
+			responses.len()
+		);
+
+		let replied = match self.compress(&message.id, &responses) {
+			Some(compressed) => message.reply(&compressed).await,
+			None => message.reply(&responses).await,
+		};
+		self.replied(&message.id, &responses, replied).await
+	}
+
+	/// Send a batch of requests without an associated broker message, responding to each in order.
+	/// Requests which haven't finished once the batch is cancelled are responded to as cancelled,
+	/// and none are sent if there are more than the maximum batch size.
+	pub async fn request_batch(
+		&self,
+		batch: &BatchRequest,
+		cancel: Option<&Cancellable>,
+	) -> Vec<RequestResponse<SerializableHttpResponse>> {
+		self.batch(batch, None, cancel).await
+	}
+
+	async fn batch(
+		&self,
+		batch: &BatchRequest,
+		lane: Option<&LaneConfig>,
+		cancel: Option<&Cancellable>,
+	) -> Vec<RequestResponse<SerializableHttpResponse>> {
+		if batch.requests.len() > self.batch.max_size {
+			let message = format!(
+				"the batch has {} requests, more than the maximum of {}",
+				batch.requests.len(),
+				self.batch.max_size
+			);
+			return batch
+				.requests
+				.iter()
+				.map(|_| Rejection::new(ResponseStatus::BatchTooLarge, &message).into())
+				.collect();
+		}
+
+		let state = BatchState {
+			lane,
+			cancel,
+			stop_on_error: batch.stop_on_error,
+			failed: AtomicBool::new(false),
+		};
+		let items = batch
+			.requests
+			.iter()
+			.map(|data| self.batch_item_unless_stopped(data, &state))
+			.collect::<Vec<_>>();
+		stream::iter(items)
+			.buffered(self.batch.concurrency.max(1))
+			.collect()
 			.await
-			.expect("Unable to respond to query");
+	}
 
-		Ok(())
+	/// Send a request from a batch, unless the batch was cancelled or an earlier request failed.
+	async fn batch_item_unless_stopped(
+		&self,
+		data: &SerializableHttpRequest,
+		state: &BatchState<'_>,
+	) -> RequestResponse<SerializableHttpResponse> {
+		let cancelled =
+			|| Rejection::new(ResponseStatus::Cancelled, "the batch was cancelled").into();
+		if state.cancel.is_some_and(Cancellable::is_cancelled) {
+			return cancelled();
+		}
+		if state.stop_on_error && state.failed.load(Ordering::Acquire) {
+			return Rejection::new(
+				ResponseStatus::Skipped,
+				"an earlier request in the batch failed",
+			)
+			.into();
+		}
+
+		let response = match state.cancel {
+			Some(cancel) => tokio::select! {
+				biased;
+				_ = cancel.cancelled() => cancelled(),
+				response = self.batch_item(data, state.lane, true) => response,
+			},
+			None => self.batch_item(data, state.lane, false).await,
+		};
+		if !succeeded(&response) {
+			state.failed.store(true, Ordering::Release);
+		}
+		response
+	}
+
+	/// Send a request from a batch, checking it as if it were sent in its own message. Requests
+	/// which can be cancelled always release their bucket if they're dropped.
+	async fn batch_item(
+		&self,
+		data: &SerializableHttpRequest,
+		lane: Option<&LaneConfig>,
+		cancellable: bool,
+	) -> RequestResponse<SerializableHttpResponse> {
+		if let Some(signer) = &self.signer {
+			if let Err(rejection) = signer.verify(data) {
+				return rejection.into();
+			}
+		}
+
+		let (req, timeout) = match self.prepare(data) {
+			Ok(prepared) => prepared,
+			Err(rejection) => return rejection.into(),
+		};
+		if data.echo {
+			return self.echo(data, &req).into();
+		}
+
+		let release_grace = self.release_grace_for(cancellable);
+		let res = async {
+			#[cfg(feature = "metrics")]
+			let _in_flight = GaugeGuard::new(REQUESTS_IN_FLIGHT.with_label_values(&[&self.profile]));
+			let claimed = self
+				.claim_with_grace(data, req, lane, release_grace)
+				.await?;
+			self.execute(data, claimed).await
+		};
+		let res = match timeout {
+			Some(timeout) => time::timeout(timeout, res)
+				.await
+				.map_err(anyhow::Error::from)
+				.and_then(|res| res),
+			None => res.await,
+		};
+		res.into()
+	}
+
+	/// A response describing the request as it was decoded, for producers to check their encoding
+	/// against. The response body is the request as JSON, and the URL is where it would be sent.
+	fn echo(
+		&self,
+		data: &SerializableHttpRequest,
+		req: &Request,
+	) -> Result<SerializableHttpResponse> {
+		if !self.echo {
+			return Err(
+				Rejection::new(ResponseStatus::InvalidRequestFormat, "echo is disabled").into(),
+			);
+		}
+
+		Ok(SerializableHttpResponse {
+			status: 200,
+			headers: vec![(CONTENT_TYPE, HeaderValue::from_static("application/json"))]
+				.into_iter()
+				.collect::<HeaderMap>()
+				.into(),
+			url: req.url().to_string(),
+			body: serde_json::to_vec(data)?.into(),
+			bucket: None,
+			debug: None,
+			ttfb_ms: None,
+			elapsed_ms: None,
+			json: false,
+		})
+	}
+
+	/// Reply to the message, handing the response to the unreplied policy if it has nowhere to be
+	/// replied to.
+	async fn reply<A>(
+		&self,
+		message: &message::Message<A, SerializableHttpRequest>,
+		response: &RequestResponse<SerializableHttpResponse>,
+	) -> Result<()>
+	where
+		A: ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		let replied = match self.compress(&message.id, response) {
+			Some(compressed) => message.reply(&compressed).await,
+			None => message.reply(response).await,
+		};
+		self.replied(&message.id, response, replied).await
+	}
+
+	/// Handle the outcome of replying to the message with the given id. Failures to reach the
+	/// broker are returned, like failures to ack the message.
+	async fn replied<T, E>(&self, id: &str, response: &T, replied: Result<(), E>) -> Result<()>
+	where
+		T: Serialize + Sync + ?Sized,
+		E: std::error::Error + Send + Sync + 'static,
+	{
+		match replied {
+			Ok(()) => Ok(()),
+			Err(e) if is_missing_destination(&e) => {
+				self.unreplied.handle(id, response, e).await;
+				Ok(())
+			}
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Compress the reply to a message, if it's large enough and the proxy is configured to.
+	fn compress<T: Serialize + ?Sized>(&self, id: &str, reply: &T) -> Option<CompressedReply> {
+		match self.compression.as_ref()?.compress(reply) {
+			Ok(compressed) => compressed,
+			Err(e) => {
+				warn!("Unable to compress reply to {}: {:?}", id, e);
+				None
+			}
+		}
+	}
+
+	/// Reply to a request which failed validation, without processing it any further.
+	async fn reject<A>(
+		&self,
+		message: &message::Message<A, SerializableHttpRequest>,
+		data: &SerializableHttpRequest,
+		rejection: Rejection,
+	) -> Result<()>
+	where
+		A: ToSocketAddrs + Clone + Send + Sync + Debug,
+	{
+		warn!(
+			"<-- REJECT({}): {:?} {}",
+			message.id, rejection.status, rejection
+		);
+
+		#[cfg(feature = "metrics")]
+		REJECTIONS_TOTAL
+			.get_metric_with_label_values(&[&self.profile, &format!("{:?}", rejection.status)])?
+			.inc();
+
+		if data.no_reply {
+			return Ok(());
+		}
+
+		self.reply(message, &RequestResponse::from(rejection)).await
+	}
+}
+
+/// Merge a query string embedded in the request path with the request's query map. Pairs from the
+/// path keep their order; a key present in the query map replaces every pair for that key from the
+/// path.
+fn merge_query(
+	path_query: Option<&str>,
+	query: Option<&HashMap<String, String>>,
+) -> Vec<(String, String)> {
+	let mut pairs: Vec<(String, String)> = path_query
+		.into_iter()
+		.flat_map(|qs| qs.split('&'))
+		.filter(|pair| !pair.is_empty())
+		.map(|pair| match pair.split_once('=') {
+			Some((k, v)) => (k.to_string(), v.to_string()),
+			None => (pair.to_string(), String::new()),
+		})
+		.collect();
+
+	if let Some(query) = query {
+		pairs.retain(|(k, _)| !query.contains_key(k));
+		pairs.extend(query.iter().map(|(k, v)| (k.clone(), v.clone())));
+	}
+
+	pairs
+}
+
+/// Characters escaped in query keys and values from config, leaving only the unreserved ones.
+const QUERY_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+	.remove(b'-')
+	.remove(b'.')
+	.remove(b'_')
+	.remove(b'~');
+
+/// The scope of a 429's ratelimit, or `unknown` if Discord didn't report a known one.
+#[cfg(feature = "metrics")]
+fn ratelimit_scope(headers: &HeaderMap) -> &'static str {
+	match headers.get("x-ratelimit-scope").map(HeaderValue::as_bytes) {
+		Some(b"user") => "user",
+		Some(b"global") => "global",
+		Some(b"shared") => "shared",
+		_ => "unknown",
+	}
+}
+
+/// Query parameters whose values are secret, and so are redacted from logged URLs.
+const SECRET_QUERY_KEYS: &[&str] = &[
+	"token",
+	"access_token",
+	"refresh_token",
+	"client_secret",
+	"code",
+	"X-Goog-Signature",
+	"X-Goog-Credential",
+];
+
+/// Replace the values of secret query parameters in the URL, leaving the rest of it as sent.
+fn redact_url(url: &reqwest::Url) -> reqwest::Url {
+	let mut redacted = url.clone();
+	if let Some(query) = url.query() {
+		let query = query
+			.split('&')
+			.map(|pair| match pair.split_once('=') {
+				Some((key, _))
+					if SECRET_QUERY_KEYS
+						.iter()
+						.any(|secret| key.eq_ignore_ascii_case(secret)) =>
+				{
+					format!("{}=REDACTED", key)
+				}
+				_ => pair.to_string(),
+			})
+			.collect::<Vec<_>>()
+			.join("&");
+		redacted.set_query(Some(&query));
+	}
+
+	redacted
+}
+
+/// The content type of raw bodies which don't specify one.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Headers describing the chain of proxies a request passed through, which producers' requests
+/// didn't pass through on the way to Discord.
+const FORWARDED_HEADERS: &[&str] = &[
+	"forwarded",
+	"via",
+	"x-forwarded-for",
+	"x-forwarded-host",
+	"x-forwarded-proto",
+	"x-real-ip",
+];
+
+/// The header audit log reasons are sent in, which Discord decodes as percent-encoded UTF-8.
+const AUDIT_LOG_REASON: &str = "x-audit-log-reason";
+
+/// Percent-encode the audit log reason if it has characters which aren't visible ASCII, such as
+/// emoji or accents, which would otherwise be rejected or sent as raw bytes Discord doesn't read
+/// as UTF-8.
+fn encode_audit_log_reason(headers: &HashMap<String, String>) -> Cow<'_, HashMap<String, String>> {
+	let invalid = headers.iter().find(|(name, value)| {
+		name.eq_ignore_ascii_case(AUDIT_LOG_REASON)
+			&& value.chars().any(|c| !(c == ' ' || c.is_ascii_graphic()))
+	});
+
+	match invalid {
+		Some((name, reason)) => {
+			let mut headers = headers.clone();
+			let encoded = utf8_percent_encode(reason, NON_ALPHANUMERIC).to_string();
+			headers.insert(name.clone(), encoded);
+			Cow::Owned(headers)
+		}
+		None => Cow::Borrowed(headers),
+	}
+}
+
+/// Whether the request was sent and Discord responded without an error status.
+fn succeeded(response: &RequestResponse<SerializableHttpResponse>) -> bool {
+	matches!(&response.body, RequestResponseBody::Ok(res) if res.status < 400)
+}
+
+/// Whether a failed reply had nowhere to go. The broker was reached but couldn't deliver it, as
+/// opposed to failing to reach the broker at all, which is caused by an I/O error.
+fn is_missing_destination(e: &(dyn std::error::Error + 'static)) -> bool {
+	!std::iter::successors(Some(e), |e| e.source()).any(|e| e.is::<std::io::Error>())
+}
+
+/// Record the claimed request's route on the current span, such as `POST /channels/:id/messages`,
+/// and name the span after it in traces so they group by endpoint.
+fn record_route(claimed: &Claimed) {
+	let path = claimed.route.split('?').next().unwrap_or_default();
+	let route = format!("{} {}", claimed.req.method(), path);
+	let span = tracing::Span::current();
+	span.record("route", route.as_str());
+	span.record("otel.name", route.as_str());
+}
+
+/// Whether the headers declare a JSON body.
+fn is_json(headers: &HeaderMap) -> bool {
+	matches!(
+		headers.get(CONTENT_TYPE).map(|value| value.to_str()),
+		Some(Ok(value)) if value.trim_start().starts_with("application/json")
+	)
+}
+
+/// Whether a response body is Cloudflare's page for a 1015 ban, which Discord's API is served with
+/// after too many invalid requests.
+fn is_cloudflare_ban(body: &[u8]) -> bool {
+	let body = String::from_utf8_lossy(body).to_ascii_lowercase();
+	body.contains("error code: 1015") || (body.contains("cloudflare") && body.contains("1015"))
+}
+
+#[cfg(test)]
+mod test {
+	use super::{message, Client, Unreplied};
+	use crate::{
+		models::{
+			FilePart, Rejection, RequestBody, RequestResponse, ResponseStatus,
+			SerializableHttpRequest, SerializableHttpResponse,
+		},
+		ratelimiter::local::LocalRatelimiter,
+	};
+	use rustacles_brokers::redis::{
+		redust::pool::{Manager, Pool},
+		RedisBroker,
+	};
+	use std::{collections::HashMap, sync::Arc};
+	use uriparse::Scheme;
+
+	fn get_client() -> Client<LocalRatelimiter> {
+		Client {
+			profile: Default::default(),
+			http: Default::default(),
+			ratelimiter: LocalRatelimiter::default(),
+			api_scheme: Scheme::HTTPS,
+			api_version: 10,
+			api_base: "discord.com".to_string(),
+			cdn_base: "cdn.discordapp.com".to_string(),
+			upload_hosts: vec!["uploads.example.com".to_string()].into(),
+			token: None,
+			timeout: None,
+			timeouts: Default::default(),
+			requeue: None,
+			routes: Default::default(),
+			reserved_headers: vec!["x-proxy-".to_string()].into(),
+			header_profiles: Arc::new(
+				vec![(
+					"browser".to_string(),
+					vec![
+						("User-Agent".to_string(), "Mozilla/5.0".to_string()),
+						("Sec-Fetch-Mode".to_string(), "cors".to_string()),
+					]
+					.into_iter()
+					.collect(),
+				)]
+				.into_iter()
+				.collect(),
+			),
+			query_limits: Default::default(),
+			forwarded: Default::default(),
+			paths: Default::default(),
+			validate_json: true,
+			echo: false,
+			check_response_json: false,
+			bucket_limit: None,
+			bucket_hashes: None,
+			path_buckets: false,
+			version_buckets: false,
+			spacing: Default::default(),
+			pause: Default::default(),
+			quiesce: Default::default(),
+			cancellations: Default::default(),
+			body_store: None,
+			backoff: None,
+			signer: None,
+			compression: None,
+			release_grace: None,
+			shutdown_grace: None,
+			rate_cap: None,
+			lanes: Default::default(),
+			dedup: None,
+			unreplied: Default::default(),
+			error_log: None,
+			concurrency: None,
+			batch: Default::default(),
+			reload: None,
+		}
+	}
+
+	fn get_url(path: &str, query: Option<&[(&str, &str)]>) -> String {
+		get_request_url(SerializableHttpRequest {
+			method: "GET".into(),
+			path: path.into(),
+			query: query.map(|query| {
+				query
+					.iter()
+					.map(|(k, v)| (k.to_string(), v.to_string()))
+					.collect::<HashMap<_, _>>()
+			}),
+			..Default::default()
+		})
+	}
+
+	fn get_request_url(data: SerializableHttpRequest) -> String {
+		get_client()
+			.create_request(&data)
+			.unwrap()
+			.url()
+			.to_string()
+	}
+
+	#[test]
+	fn path_query() {
+		assert_eq!(
+			get_url("/channels/123/messages?limit=50&before=1", None),
+			"https://discord.com/api/v10/channels/123/messages?limit=50&before=1"
+		);
+	}
+
+	#[test]
+	fn field_query() {
+		assert_eq!(
+			get_url("/channels/123/messages", Some(&[("limit", "50")])),
+			"https://discord.com/api/v10/channels/123/messages?limit=50"
+		);
+	}
+
+	#[test]
+	fn merged_query() {
+		assert_eq!(
+			get_url(
+				"/channels/123/messages?limit=50&before=1",
+				Some(&[("limit", "100")])
+			),
+			"https://discord.com/api/v10/channels/123/messages?before=1&limit=100"
+		);
+	}
+
+	#[test]
+	fn default_query() {
+		use crate::runtime::config::{QueryConfig, QueryDefaults};
+
+		let mut client = get_client();
+		client.query_limits = QueryConfig {
+			defaults: vec![QueryDefaults {
+				pattern: "/guilds/*".to_string(),
+				params: vec![
+					("with_counts".to_string(), "true".to_string()),
+					("locale".to_string(), "zh TW&".to_string()),
+				]
+				.into_iter()
+				.collect(),
+			}],
+			..Default::default()
+		};
+		let url = |path: &str, query: Option<&[(&str, &str)]>| {
+			let data = SerializableHttpRequest {
+				method: "GET".into(),
+				path: path.into(),
+				query: query.map(|query| {
+					query
+						.iter()
+						.map(|(k, v)| (k.to_string(), v.to_string()))
+						.collect()
+				}),
+				..Default::default()
+			};
+			client.create_request(&data).unwrap().url().to_string()
+		};
+
+		assert_eq!(
+			url("/guilds/123", None),
+			"https://discord.com/api/v10/guilds/123?locale=zh%20TW%26&with_counts=true"
+		);
+		assert_eq!(
+			url("/guilds/123?with_counts=false", Some(&[("locale", "fr")])),
+			"https://discord.com/api/v10/guilds/123?with_counts=false&locale=fr"
+		);
+		assert_eq!(
+			url("/channels/123", None),
+			"https://discord.com/api/v10/channels/123"
+		);
+	}
+
+	#[test]
+	fn prefixed_path() {
+		assert_eq!(
+			get_request_url(SerializableHttpRequest {
+				method: "GET".into(),
+				path: "/users/@me".into(),
+				prefix: Some(true),
+				..Default::default()
+			}),
+			"https://discord.com/api/v10/users/@me"
+		);
+	}
+
+	#[test]
+	fn unprefixed_path() {
+		assert_eq!(
+			get_request_url(SerializableHttpRequest {
+				method: "GET".into(),
+				path: "/users/@me".into(),
+				prefix: Some(false),
+				..Default::default()
+			}),
+			"https://discord.com/users/@me"
+		);
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "users/@me".into(),
+			prefix: Some(false),
+			..Default::default()
+		};
+		assert!(get_client().create_request(&data).is_err());
+	}
+
+	#[test]
+	fn sets_http_timeout() {
+		use std::time::Duration;
+
+		let mut data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			..Default::default()
+		};
+		assert_eq!(get_client().create_request(&data).unwrap().timeout(), None);
+
+		data.http_timeout = Some(Duration::from_millis(200));
+		assert_eq!(
+			get_client().create_request(&data).unwrap().timeout(),
+			Some(&Duration::from_millis(200))
+		);
+	}
+
+	#[test]
+	fn rejects_invalid_requests() {
+		use crate::models::{Rejection, ResponseStatus};
+
+		let cases = vec![
+			(
+				SerializableHttpRequest {
+					method: "NOT A METHOD".into(),
+					path: "/gateway".into(),
+					..Default::default()
+				},
+				ResponseStatus::InvalidMethod,
+			),
+			(
+				SerializableHttpRequest {
+					method: "TRACE".into(),
+					path: "/gateway".into(),
+					..Default::default()
+				},
+				ResponseStatus::InvalidMethod,
+			),
+			(
+				SerializableHttpRequest {
+					method: "GET".into(),
+					path: "gateway".into(),
+					prefix: Some(false),
+					..Default::default()
+				},
+				ResponseStatus::InvalidPath,
+			),
+			(
+				SerializableHttpRequest {
+					method: "GET".into(),
+					path: "/gateway".into(),
+					headers: vec![("bad header".to_string(), "1".to_string())]
+						.into_iter()
+						.collect(),
+					..Default::default()
+				},
+				ResponseStatus::InvalidHeaders,
+			),
+			(
+				SerializableHttpRequest {
+					method: "POST".into(),
+					path: "/channels/1/messages".into(),
+					headers: vec![("content-type".to_string(), "application/json".to_string())]
+						.into_iter()
+						.collect(),
+					body: Some("{".into()),
+					..Default::default()
+				},
+				ResponseStatus::InvalidRequestFormat,
+			),
+		];
+
+		for (data, status) in cases {
+			let rejection = Rejection::from(get_client().create_request(&data).unwrap_err());
+			assert_eq!(rejection.status, status, "{}", data);
+			assert!(!rejection.message.is_empty());
+		}
+	}
+
+	#[cfg(feature = "metrics")]
+	#[tokio::test]
+	async fn records_concurrency_wait() {
+		use crate::metrics::CONCURRENCY_WAIT;
+		use std::sync::Arc;
+		use tokio::{
+			spawn,
+			sync::Semaphore,
+			time::{sleep, Duration},
+		};
+
+		let mut client = get_client();
+		client.concurrency = Some(Arc::new(Semaphore::new(1)));
+		let before = CONCURRENCY_WAIT.with_label_values(&[""]).get_sample_sum();
+
+		let permit = client.acquire_permit().await.unwrap();
+		let waiting = spawn(async move { client.acquire_permit().await.map(|_| ()) });
+		sleep(Duration::from_millis(100)).await;
+		drop(permit);
+		waiting.await.unwrap().unwrap();
+
+		assert!(CONCURRENCY_WAIT.with_label_values(&[""]).get_sample_sum() - before >= 0.1);
+	}
+
+	#[test]
+	fn applies_reloaded_settings() {
+		use crate::{route::RouteRule, runtime::reload::Reloadable};
+		use tokio::{sync::watch, time::Duration};
+
+		let (sender, receiver) = watch::channel(Reloadable {
+			timeout: None,
+			timeouts: Default::default(),
+			routes: Default::default(),
+			reserved_headers: Default::default(),
+			header_profiles: Default::default(),
+			query_limits: Default::default(),
+			forwarded: Default::default(),
+			paths: Default::default(),
+			validate_json: false,
+			echo: false,
+			check_response_json: false,
+			paused: Default::default(),
+		});
+		let mut client = get_client();
+		client.reload = Some(receiver);
+		assert_eq!(client.reloaded().timeout, None);
+
+		let routes: Vec<RouteRule> = vec![RouteRule {
+			prefix: "/guilds/:id/members".to_string(),
+			segments: vec![3],
+			min_spacing: None,
+		}];
+		sender
+			.send(Reloadable {
+				timeout: Some(Duration::from_secs(5)),
+				timeouts: Default::default(),
+				routes: routes.clone().into(),
+				reserved_headers: Default::default(),
+				header_profiles: Default::default(),
+				query_limits: Default::default(),
+				forwarded: Default::default(),
+				paths: Default::default(),
+				validate_json: false,
+				echo: false,
+				check_response_json: false,
+				paused: Default::default(),
+			})
+			.unwrap();
+
+		let reloaded = client.reloaded();
+		assert_eq!(reloaded.timeout, Some(Duration::from_secs(5)));
+		assert_eq!(&*reloaded.routes, &routes[..]);
+	}
+
+	#[test]
+	fn overrides_timeout_by_route() {
+		use crate::runtime::Config;
+		use tokio::time::Duration;
+
+		let config: Config = toml::from_str(
+			r#"
+			timeout = "5s"
+
+			[timeouts]
+			"/guilds/:id/members?get" = "1min"
+			"#,
+		)
+		.unwrap();
+
+		let mut client = get_client();
+		client.timeout = config.timeout;
+		client.timeouts = Arc::new(config.timeouts);
+		assert_eq!(
+			client.timeout_for(Some("/guilds/:id/members?get")),
+			Some(Duration::from_secs(60))
+		);
+		assert_eq!(
+			client.timeout_for(Some("/guilds/:id/members?put")),
+			Some(Duration::from_secs(5))
+		);
+		assert_eq!(client.timeout_for(None), Some(Duration::from_secs(5)));
+	}
+
+	#[test]
+	fn strips_reserved_headers() {
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			headers: vec![
+				("X-Proxy-Timing".to_string(), "0".to_string()),
+				("X-Audit-Log-Reason".to_string(), "test".to_string()),
+			]
+			.into_iter()
+			.collect(),
+			..Default::default()
+		};
+
+		let req = get_client().create_request(&data).unwrap();
+		assert!(req.headers().get("x-proxy-timing").is_none());
+		assert_eq!(req.headers()["x-audit-log-reason"], "test");
+	}
+
+	#[test]
+	fn strips_forwarded_headers() {
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			headers: vec![
+				("X-Forwarded-For".to_string(), "10.0.0.1".to_string()),
+				("Forwarded".to_string(), "for=10.0.0.1".to_string()),
+				("Via".to_string(), "1.1 producer".to_string()),
+				("X-Audit-Log-Reason".to_string(), "test".to_string()),
+			]
+			.into_iter()
+			.collect(),
+			..Default::default()
+		};
+
+		let mut client = get_client();
+		let req = client.create_request(&data).unwrap();
+		assert!(req.headers().get("x-forwarded-for").is_none());
+		assert!(req.headers().get("forwarded").is_none());
+		assert!(req.headers().get("via").is_none());
+		assert_eq!(req.headers()["x-audit-log-reason"], "test");
+
+		client.forwarded.via = Some("spectacles".to_string());
+		let req = client.create_request(&data).unwrap();
+		assert_eq!(req.headers()["via"], "1.1 spectacles");
+
+		client.forwarded.strip = false;
+		let req = client.create_request(&data).unwrap();
+		assert_eq!(req.headers()["x-forwarded-for"], "10.0.0.1");
+		assert_eq!(
+			req.headers().get_all("via").iter().collect::<Vec<_>>(),
+			["1.1 producer", "1.1 spectacles"]
+		);
+	}
+
+	#[test]
+	fn encodes_audit_log_reason() {
+		let mut data = SerializableHttpRequest {
+			method: "PUT".into(),
+			path: "/guilds/1/bans/2".into(),
+			headers: vec![(
+				"X-Audit-Log-Reason".to_string(),
+				"Banned for spam 🚫".to_string(),
+			)]
+			.into_iter()
+			.collect(),
+			..Default::default()
+		};
+
+		let client = get_client();
+		let req = client.create_request(&data).unwrap();
+		assert_eq!(
+			req.headers()["x-audit-log-reason"],
+			"Banned%20for%20spam%20%F0%9F%9A%AB"
+		);
+
+		// reasons which are already valid are sent as they are
+		data.headers.insert(
+			"X-Audit-Log-Reason".to_string(),
+			"Banned for spam".to_string(),
+		);
+		let req = client.create_request(&data).unwrap();
+		assert_eq!(req.headers()["x-audit-log-reason"], "Banned for spam");
+	}
+
+	#[test]
+	fn sets_authorization() {
+		let mut data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			..Default::default()
+		};
+
+		let mut client = get_client();
+		let req = client.create_request(&data).unwrap();
+		assert!(req.headers().get("authorization").is_none());
+
+		client.token = Some("abc".to_string());
+		let req = client.create_request(&data).unwrap();
+		assert_eq!(req.headers()["authorization"], "Bot abc");
+
+		data.headers = vec![("Authorization".to_string(), "Bearer xyz".to_string())]
+			.into_iter()
+			.collect();
+		let req = client.create_request(&data).unwrap();
+		assert_eq!(req.headers()["authorization"], "Bearer xyz");
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/avatars/1/a.png".into(),
+			mode: crate::models::RequestMode::Cdn,
+			..Default::default()
+		};
+		let req = client.create_request(&data).unwrap();
+		assert!(req.headers().get("authorization").is_none());
+	}
+
+	#[test]
+	fn rejects_malformed_json() {
+		use crate::models::{RequestResponse, ResponseStatus};
+
+		let mut data = SerializableHttpRequest {
+			method: "POST".into(),
+			path: "/channels/1/messages".into(),
+			headers: vec![("Content-Type".to_string(), "application/json".to_string())]
+				.into_iter()
+				.collect(),
+			body: Some(r#"{"content": "hi""#.into()),
+			..Default::default()
+		};
+
+		let res = get_client().create_request(&data).map(|_| ());
+		assert_eq!(
+			RequestResponse::from(res).status,
+			ResponseStatus::InvalidRequestFormat
+		);
+
+		data.body = Some(r#"{"content": "hi"}"#.into());
+		assert!(get_client().create_request(&data).is_ok());
+	}
+
+	#[test]
+	fn applies_header_profile() {
+		let mut data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			headers: vec![("user-agent".to_string(), "DiscordBot".to_string())]
+				.into_iter()
+				.collect(),
+			profile: Some("browser".into()),
+			..Default::default()
+		};
+
+		let req = get_client().create_request(&data).unwrap();
+		assert_eq!(req.headers()["sec-fetch-mode"], "cors");
+		assert_eq!(req.headers()["user-agent"], "DiscordBot");
+
+		data.profile = Some("unknown".into());
+		let rejection =
+			crate::models::Rejection::from(get_client().create_request(&data).unwrap_err());
+		assert_eq!(
+			rejection.status,
+			crate::models::ResponseStatus::InvalidHeaders
+		);
+	}
+
+	#[test]
+	fn sets_typed_body_content_type() {
+		let mut data = SerializableHttpRequest {
+			method: "POST".into(),
+			path: "/channels/1/messages".into(),
+			headers: vec![("content-type".to_string(), "text/plain".to_string())]
+				.into_iter()
+				.collect(),
+			body: Some(RequestBody::Json(serde_json::json!({"content": "hi"}))),
+			..Default::default()
+		};
+
+		let req = get_client().create_request(&data).unwrap();
+		assert_eq!(req.headers()["content-type"], "application/json");
+		assert_eq!(
+			req.body().unwrap().as_bytes().unwrap(),
+			br#"{"content":"hi"}"#
+		);
+
+		data.body = Some(RequestBody::Multipart(vec![FilePart {
+			name: "files[0]".to_string(),
+			filename: Some("hi.txt".to_string()),
+			content_type: None,
+			data: "hi".into(),
+		}]));
+		let req = get_client().create_request(&data).unwrap();
+		let content_type = req.headers()["content-type"].to_str().unwrap();
+		let boundary = content_type
+			.strip_prefix("multipart/form-data; boundary=")
+			.unwrap();
+		assert_eq!(
+			std::str::from_utf8(req.body().unwrap().as_bytes().unwrap()).unwrap(),
+			format!(
+				"--{0}\r\nContent-Disposition: form-data; name=\"files[0]\"; filename=\"hi.txt\"\r\n\r\nhi\r\n--{0}--\r\n",
+				boundary
+			)
+		);
+	}
+
+	#[test]
+	fn sends_files() {
+		let file = FilePart {
+			name: "files[0]".to_string(),
+			filename: Some("hi.txt".to_string()),
+			content_type: Some("text/plain".to_string()),
+			data: "hi".into(),
+		};
+		let mut data = SerializableHttpRequest {
+			method: "POST".into(),
+			path: "/channels/1/messages".into(),
+			body: Some(RequestBody::Json(serde_json::json!({"content": "hi"}))),
+			files: Some(vec![file.clone()]),
+			..Default::default()
+		};
+
+		let req = get_client().create_request(&data).unwrap();
+		let content_type = req.headers()["content-type"].to_str().unwrap();
+		let boundary = content_type
+			.strip_prefix("multipart/form-data; boundary=")
+			.unwrap();
+		assert_eq!(
+			std::str::from_utf8(req.body().unwrap().as_bytes().unwrap()).unwrap(),
+			format!(
+				"--{0}\r\nContent-Disposition: form-data; name=\"payload_json\"\r\nContent-Type: application/json\r\n\r\n{{\"content\":\"hi\"}}\r\n--{0}\r\nContent-Disposition: form-data; name=\"files[0]\"; filename=\"hi.txt\"\r\nContent-Type: text/plain\r\n\r\nhi\r\n--{0}--\r\n",
+				boundary
+			)
+		);
+
+		// a form body has nowhere to go alongside the files
+		data.body = Some(RequestBody::Form(vec![("a".to_string(), "b".to_string())]));
+		let err = get_client().create_request(&data).unwrap_err();
+		assert_eq!(
+			err.downcast_ref::<Rejection>().unwrap().status,
+			ResponseStatus::InvalidRequestFormat
+		);
+	}
+
+	#[test]
+	fn sets_raw_body_content_type() {
+		let content_type = |content_type: Option<&str>, header: Option<&str>| {
+			let data = SerializableHttpRequest {
+				method: "POST".into(),
+				path: "/channels/1/messages".into(),
+				body: Some(r#"{"content": "hi"}"#.into()),
+				content_type: content_type.map(Into::into),
+				headers: header
+					.map(|header| ("Content-Type".to_string(), header.to_string()))
+					.into_iter()
+					.collect(),
+				..Default::default()
+			};
+
+			let req = get_client().create_request(&data).unwrap();
+			req.headers()["content-type"].to_str().unwrap().to_string()
+		};
+
+		assert_eq!(
+			content_type(Some("application/json"), None),
+			"application/json"
+		);
+		assert_eq!(content_type(None, Some("text/plain")), "text/plain");
+		assert_eq!(
+			content_type(Some("application/json"), Some("text/plain")),
+			"text/plain"
+		);
+		assert_eq!(content_type(None, None), "application/octet-stream");
+	}
+
+	#[test]
+	fn builds_upload_request() {
+		let mut data = SerializableHttpRequest {
+			method: "PUT".into(),
+			path: "https://uploads.example.com/bucket/file.bin?upload_id=abc&X-Goog-Signature=def"
+				.into(),
+			mode: crate::models::RequestMode::Upload,
+			..Default::default()
+		};
+
+		let req = get_client().create_request(&data).unwrap();
+		assert_eq!(
+			req.url().as_str(),
+			"https://uploads.example.com/bucket/file.bin?upload_id=abc&X-Goog-Signature=def"
+		);
+
+		for path in [
+			"https://evil.example.com/bucket/file.bin",
+			"http://uploads.example.com/bucket/file.bin",
+			"/bucket/file.bin",
+		] {
+			data.path = path.into();
+			let rejection = Rejection::from(get_client().create_request(&data).unwrap_err());
+			assert_eq!(rejection.status, ResponseStatus::InvalidPath, "{}", path);
+		}
+	}
+
+	#[derive(Clone, Default)]
+	struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+	impl std::io::Write for LogBuffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn logs_redacted_url() {
+		let logs = LogBuffer::default();
+		let writer = logs.clone();
+		let subscriber = tracing_subscriber::fmt()
+			.with_max_level(tracing::Level::DEBUG)
+			.with_ansi(false)
+			.with_writer(move || writer.clone())
+			.finish();
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/oauth2/token?code=abc123".into(),
+			query: Some(
+				vec![("limit".to_string(), "50".to_string())]
+					.into_iter()
+					.collect(),
+			),
+			..Default::default()
+		};
+		tracing::subscriber::with_default(subscriber, || {
+			get_client().create_request(&data).unwrap();
+		});
+
+		let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+		assert!(
+			logs.contains("https://discord.com/api/v10/oauth2/token?code=REDACTED&limit=50"),
+			"{}",
+			logs
+		);
+		assert!(!logs.contains("abc123"), "{}", logs);
+	}
+
+	#[test]
+	fn limits_query_params() {
+		let client = get_client();
+		let query = (0..=client.query_limits.max_params)
+			.map(|i| (i.to_string(), String::new()))
+			.collect();
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/channels/1234/messages".into(),
+			query: Some(query),
+			..Default::default()
+		};
+
+		let rejection = crate::models::Rejection::from(client.create_request(&data).unwrap_err());
+		assert_eq!(
+			rejection.status,
+			crate::models::ResponseStatus::InvalidQuery
+		);
+	}
+
+	#[test]
+	fn limits_query_length() {
+		let client = get_client();
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/channels/1234/messages".into(),
+			query: Some(
+				vec![(
+					"before".to_string(),
+					"1".repeat(client.query_limits.max_length),
+				)]
+				.into_iter()
+				.collect(),
+			),
+			..Default::default()
+		};
+
+		let rejection = crate::models::Rejection::from(client.create_request(&data).unwrap_err());
+		assert_eq!(
+			rejection.status,
+			crate::models::ResponseStatus::InvalidQuery
+		);
+	}
+
+	#[tokio::test]
+	async fn releases_timed_out_request() {
+		use crate::ratelimiter::Ratelimiter;
+		use tokio::{
+			net::TcpListener,
+			time::{timeout, Duration},
+		};
+
+		// accept connections without ever responding, so requests hang until they time out
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = tokio::spawn(async move {
+			let mut conns = Vec::new();
+			while let Ok((conn, _)) = listener.accept().await {
+				conns.push(conn);
+			}
+		});
+
+		let mut client = get_client();
+		client.api_scheme = Scheme::HTTP;
+		client.api_base = addr.to_string();
+		client.release_grace = Some(Duration::from_secs(1));
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			..Default::default()
+		};
+		assert!(timeout(Duration::from_millis(100), client.request(&data))
+			.await
+			.is_err());
+
+		timeout(
+			Duration::from_millis(100),
+			client.ratelimiter.claim("/gateway?get".into()),
+		)
+		.await
+		.expect("bucket wasn't released")
+		.unwrap();
+		server.abort();
+	}
+
+	#[tokio::test]
+	async fn times_response() {
+		use tokio::{
+			io::{AsyncReadExt, AsyncWriteExt},
+			net::TcpListener,
+			time::{sleep, Duration},
+		};
+
+		// delay the headers and then the body, so each part of the response takes a known time
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = tokio::spawn(async move {
+			let (mut conn, _) = listener.accept().await.unwrap();
+			let mut buf = [0; 1024];
+			let _ = conn.read(&mut buf).await.unwrap();
+
+			sleep(Duration::from_millis(100)).await;
+			conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n")
+				.await
+				.unwrap();
+			conn.flush().await.unwrap();
+			sleep(Duration::from_millis(200)).await;
+			conn.write_all(b"[]").await.unwrap();
+		});
+
+		let mut client = get_client();
+		client.api_scheme = Scheme::HTTP;
+		client.api_base = addr.to_string();
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			timing: true,
+			..Default::default()
+		};
+		let res = client.request(&data).await.unwrap();
+		server.await.unwrap();
+
+		let ttfb = res.ttfb_ms.expect("ttfb");
+		let elapsed = res.elapsed_ms.expect("elapsed");
+		assert!((100..300).contains(&ttfb), "ttfb of {}ms", ttfb);
+		assert!(elapsed - ttfb >= 200, "body took {}ms", elapsed - ttfb);
+	}
+
+	#[tokio::test]
+	async fn resolves_without_body() {
+		use tokio::{
+			io::{AsyncReadExt, AsyncWriteExt},
+			net::TcpListener,
+			time::{timeout, Duration},
+		};
+
+		// redirect to a response whose body never arrives
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = tokio::spawn(async move {
+			while let Ok((mut conn, _)) = listener.accept().await {
+				tokio::spawn(async move {
+					let mut buf = [0; 1024];
+					while let Ok(len) = conn.read(&mut buf).await {
+						if len == 0 {
+							break;
+						}
+						let res: &[u8] = if buf[..len].starts_with(b"GET /final ") {
+							b"HTTP/1.1 200 OK\r\ncontent-length: 1048576\r\n\r\n"
+						} else {
+							b"HTTP/1.1 302 Found\r\nlocation: /final\r\ncontent-length: 0\r\n\r\n"
+						};
+						conn.write_all(res).await.unwrap();
+					}
+				});
+			}
+		});
+
+		let mut client = get_client();
+		client.api_scheme = Scheme::HTTP;
+		client.api_base = addr.to_string();
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/attachments/1".into(),
+			prefix: Some(false),
+			resolve_only: true,
+			..Default::default()
+		};
+		let res = timeout(Duration::from_secs(1), client.request(&data))
+			.await
+			.expect("response body was downloaded")
+			.unwrap();
+		server.abort();
+
+		assert_eq!(res.status, 200);
+		assert_eq!(res.url, format!("http://{}/final", addr));
+		assert!(res.body.is_empty());
+	}
+
+	#[cfg(feature = "metrics")]
+	#[tokio::test]
+	async fn counts_http_in_flight() {
+		use crate::metrics::HTTP_IN_FLIGHT;
+		use tokio::{
+			io::{AsyncReadExt, AsyncWriteExt},
+			net::TcpListener,
+			sync::oneshot,
+		};
+
+		// hold the response until the gauge has been checked
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (received_tx, received_rx) = oneshot::channel();
+		let (respond_tx, respond_rx) = oneshot::channel::<()>();
+		let server = tokio::spawn(async move {
+			let (mut conn, _) = listener.accept().await.unwrap();
+			let mut buf = [0; 1024];
+			let _ = conn.read(&mut buf).await.unwrap();
+			received_tx.send(()).unwrap();
+
+			respond_rx.await.unwrap();
+			conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n[]")
+				.await
+				.unwrap();
+		});
+
+		let mut client = get_client();
+		client.api_scheme = Scheme::HTTP;
+		client.api_base = addr.to_string();
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			..Default::default()
+		};
+		let request = tokio::spawn(async move { client.request(&data).await.map(|_| ()) });
+
+		received_rx.await.unwrap();
+		// other tests can make requests at the same time, so this one is only known to be counted
+		assert!(HTTP_IN_FLIGHT.with_label_values(&[""]).get() >= 1);
+		respond_tx.send(()).unwrap();
+
+		request.await.unwrap().unwrap();
+		server.await.unwrap();
+	}
+
+	#[cfg(feature = "metrics")]
+	#[tokio::test]
+	async fn counts_waiting_requests() {
+		use crate::{
+			metrics::{RATELIMIT_WAITING, REQUESTS_IN_FLIGHT},
+			ratelimiter::Ratelimiter,
+		};
+		use tokio::time::{sleep, Duration};
+
+		let client = Arc::new(get_client());
+		client
+			.ratelimiter
+			.claim("/guilds/:id?get".into())
+			.await
+			.unwrap();
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/guilds/1234".into(),
+			..Default::default()
+		};
+		let waiting = tokio::spawn({
+			let client = Arc::clone(&client);
+			async move { client.request(&data).await.map(|_| ()) }
+		});
+		sleep(Duration::from_millis(50)).await;
+
+		// other tests can make requests at the same time, so this one is only known to be counted
+		assert!(RATELIMIT_WAITING.with_label_values(&[""]).get() >= 1);
+		assert!(REQUESTS_IN_FLIGHT.with_label_values(&[""]).get() >= 1);
+		waiting.abort();
+	}
+
+	#[cfg(feature = "metrics")]
+	#[tokio::test]
+	async fn counts_ratelimited_responses() {
+		use crate::metrics::RATELIMITED_TOTAL;
+		use tokio::{
+			io::{AsyncReadExt, AsyncWriteExt},
+			net::TcpListener,
+		};
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = tokio::spawn(async move {
+			let (mut conn, _) = listener.accept().await.unwrap();
+			let mut buf = [0; 1024];
+			for scope in ["x-ratelimit-scope: shared\r\n", ""] {
+				let _ = conn.read(&mut buf).await.unwrap();
+				let res = format!(
+					"HTTP/1.1 429 Too Many Requests\r\n{}content-length: 0\r\n\r\n",
+					scope
+				);
+				conn.write_all(res.as_bytes()).await.unwrap();
+			}
+		});
+
+		let mut client = get_client();
+		client.api_scheme = Scheme::HTTP;
+		client.api_base = addr.to_string();
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/ratelimited".into(),
+			..Default::default()
+		};
+		for _ in 0..2 {
+			assert_eq!(client.request(&data).await.unwrap().status, 429);
+		}
+		server.await.unwrap();
+
+		for scope in ["shared", "unknown"] {
+			let count = RATELIMITED_TOTAL
+				.with_label_values(&["", "GET", "/ratelimited", scope])
+				.get();
+			assert_eq!(count, 1, "{}", scope);
+		}
+	}
+
+	#[tokio::test]
+	async fn records_route_on_span() {
+		use std::sync::{Arc, Mutex};
+		use tokio::{
+			io::{AsyncReadExt, AsyncWriteExt},
+			net::TcpListener,
+		};
+		use tracing::{
+			field::{Field, Visit},
+			span::{Id, Record},
+			Subscriber,
+		};
+		use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+		/// Captures the routes recorded on spans.
+		struct Routes(Arc<Mutex<Vec<String>>>);
+
+		impl Visit for &Routes {
+			fn record_str(&mut self, field: &Field, value: &str) {
+				if field.name() == "route" {
+					self.0.lock().unwrap().push(value.to_string());
+				}
+			}
+
+			fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+		}
+
+		impl<S: Subscriber> Layer<S> for Routes {
+			fn on_record(&self, _: &Id, values: &Record<'_>, _: Context<'_, S>) {
+				values.record(&mut &*self);
+			}
+		}
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = tokio::spawn(async move {
+			let (mut conn, _) = listener.accept().await.unwrap();
+			let mut buf = [0; 1024];
+			let _ = conn.read(&mut buf).await.unwrap();
+			conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}")
+				.await
+				.unwrap();
+		});
+
+		let mut client = get_client();
+		client.api_scheme = Scheme::HTTP;
+		client.api_base = addr.to_string();
+
+		let routes = Arc::new(Mutex::new(Vec::new()));
+		let _subscriber = tracing_subscriber::registry()
+			.with(Routes(Arc::clone(&routes)))
+			.set_default();
+		let data = SerializableHttpRequest {
+			method: "POST".into(),
+			path: "/channels/1234/messages".into(),
+			..Default::default()
+		};
+		client.request(&data).await.unwrap();
+		server.await.unwrap();
+
+		assert_eq!(*routes.lock().unwrap(), ["POST /channels/:id/messages"]);
+	}
+
+	#[tokio::test]
+	async fn detects_cloudflare_ban() {
+		use crate::{
+			models::{Rejection, ResponseStatus},
+			ratelimiter::Ratelimiter,
+		};
+		use tokio::{
+			io::{AsyncReadExt, AsyncWriteExt},
+			net::TcpListener,
+			time::{timeout, Duration},
+		};
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = tokio::spawn(async move {
+			let (mut conn, _) = listener.accept().await.unwrap();
+			let mut buf = [0; 1024];
+			let _ = conn.read(&mut buf).await.unwrap();
+			let body = "<html><title>Access denied | discord.com used Cloudflare to restrict \
+				access</title><span class=\"cf-error-code\">1015</span></html>";
+			let res = format!(
+				"HTTP/1.1 429 Too Many Requests\r\ncontent-type: text/html\r\nretry-after: 60\r\n\
+				content-length: {}\r\n\r\n{}",
+				body.len(),
+				body
+			);
+			conn.write_all(res.as_bytes()).await.unwrap();
+		});
+
+		let mut client = get_client();
+		client.api_scheme = Scheme::HTTP;
+		client.api_base = addr.to_string();
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/banned".into(),
+			..Default::default()
+		};
+		let rejection = Rejection::from(client.request(&data).await.unwrap_err());
+		assert_eq!(rejection.status, ResponseStatus::CloudflareBanned);
+		server.await.unwrap();
+
+		// the global limit is held closed, so no other request can be sent
+		assert!(
+			timeout(Duration::from_millis(50), client.ratelimiter.claim_global())
+				.await
+				.is_err()
+		);
+	}
+
+	#[tokio::test]
+	async fn claims_by_lane() {
+		use crate::{
+			ratelimiter::Ratelimiter,
+			runtime::config::{LaneConfig, RatelimitStrategy},
+		};
+		use tokio::time::{timeout, Duration};
+
+		let mut client = get_client();
+		client.lanes = Arc::new(
+			vec![
+				("PRIORITY", RatelimitStrategy::None),
+				("BULK", RatelimitStrategy::Full),
+			]
+			.into_iter()
+			.map(|(event, ratelimit)| {
+				(
+					event.to_string(),
+					LaneConfig {
+						event: event.to_string(),
+						ratelimit,
+						max_wait: Duration::from_secs(1),
+					},
+				)
+			})
+			.collect(),
+		);
+
+		let data = SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/gateway".into(),
+			..Default::default()
+		};
+		client
+			.ratelimiter
+			.claim("/gateway?get".into())
+			.await
+			.unwrap();
+
+		let req = client.create_request(&data).unwrap();
+		let claimed = timeout(
+			Duration::from_millis(100),
+			client.claim_with_grace(&data, req, client.lane(b"PRIORITY"), None),
+		)
+		.await
+		.expect("priority lane waited for the bucket")
+		.unwrap();
+		assert!(!claimed.holds_bucket);
+
+		let req = client.create_request(&data).unwrap();
+		assert!(timeout(
+			Duration::from_millis(100),
+			client.claim_with_grace(&data, req, client.lane(b"BULK"), None),
+		)
+		.await
+		.is_err());
+	}
+
+	#[tokio::test]
+	async fn handles_unrepliable_message() {
+		use std::{
+			fmt,
+			io::{Error, ErrorKind},
+			sync::Mutex,
+		};
+		use tracing::{Event, Level, Subscriber};
+		use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+		/// Captures the levels of the events logged.
+		struct Levels(Arc<Mutex<Vec<Level>>>);
+
+		impl<S: Subscriber> Layer<S> for Levels {
+			fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+				self.0.lock().unwrap().push(*event.metadata().level());
+			}
+		}
+
+		/// The broker was reached, but the message has no reply destination.
+		#[derive(Debug)]
+		struct NoReplyTo;
+
+		impl fmt::Display for NoReplyTo {
+			fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+				f.write_str("no reply-to")
+			}
+		}
+
+		impl std::error::Error for NoReplyTo {}
+
+		let levels = Arc::new(Mutex::new(Vec::new()));
+		let _subscriber = tracing_subscriber::registry()
+			.with(Levels(Arc::clone(&levels)))
+			.set_default();
+		let response = RequestResponse::<SerializableHttpResponse>::from(Rejection::new(
+			ResponseStatus::Unknown,
+			"failed",
+		));
+
+		let mut client = get_client();
+		for (unreplied, level) in [
+			(Unreplied::Drop, Level::DEBUG),
+			(Unreplied::Warn, Level::WARN),
+		] {
+			client.unreplied = unreplied;
+			client
+				.replied("1-0", &response, Err(NoReplyTo))
+				.await
+				.unwrap();
+			assert_eq!(
+				levels.lock().unwrap().drain(..).collect::<Vec<_>>(),
+				[level]
+			);
+
+			// the broker couldn't be reached, which isn't up to the policy
+			let unreachable = Error::new(ErrorKind::ConnectionRefused, "refused");
+			assert!(client
+				.replied("1-0", &response, Err(unreachable))
+				.await
+				.is_err());
+			assert!(levels.lock().unwrap().is_empty());
+		}
+	}
+
+	#[tokio::test]
+	async fn quiesces_consumption() {
+		use futures::StreamExt;
+		use std::sync::atomic::{AtomicUsize, Ordering};
+		use tokio::{
+			net::TcpListener,
+			sync::mpsc,
+			time::{sleep, Duration},
+		};
+		use tokio_stream::wrappers::UnboundedReceiverStream;
+
+		// neither the broker nor Discord reply, so messages are held in flight until they disconnect
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = tokio::spawn(async move {
+			let mut conns = Vec::new();
+			while let Ok((conn, _)) = listener.accept().await {
+				conns.push(conn);
+			}
+		});
+		let broker = RedisBroker::new(
+			"proxy",
+			Pool::builder(Manager::new(addr.to_string()))
+				.build()
+				.unwrap(),
+		);
+		let message = || message::Message {
+			id: "1-0".to_string(),
+			event: "REQUEST".into(),
+			data: Some(SerializableHttpRequest {
+				method: "GET".into(),
+				path: "/gateway".into(),
+				..Default::default()
+			}),
+			timeout_at: None,
+			broker: broker.clone(),
+		};
+
+		let mut client = get_client();
+		client.api_scheme = Scheme::HTTP;
+		client.api_base = addr.to_string();
+		let client = Arc::new(client);
+		let (tx, rx) = mpsc::unbounded_channel();
+		let taken = Arc::new(AtomicUsize::new(0));
+		let stream = UnboundedReceiverStream::new(rx).map({
+			let taken = Arc::clone(&taken);
+			move |message| {
+				taken.fetch_add(1, Ordering::SeqCst);
+				Ok(message)
+			}
+		});
+		tokio::spawn({
+			let client = Arc::clone(&client);
+			async move { client.consume_stream(stream).await }
+		});
+
+		tx.send(message()).unwrap();
+		sleep(Duration::from_millis(50)).await;
+		assert_eq!(taken.load(Ordering::SeqCst), 1);
+
+		client.quiesce.quiesce();
+		tx.send(message()).unwrap();
+		sleep(Duration::from_millis(50)).await;
+		assert_eq!(taken.load(Ordering::SeqCst), 1);
+		assert_eq!(client.quiesce.status().handling, 1);
+
+		// the message in flight finishes once the servers disconnect
+		server.abort();
+		sleep(Duration::from_millis(50)).await;
+		assert_eq!(client.quiesce.status().handling, 0);
+		assert_eq!(taken.load(Ordering::SeqCst), 1);
+
+		client.quiesce.resume();
+		sleep(Duration::from_millis(50)).await;
+		assert_eq!(taken.load(Ordering::SeqCst), 2);
 	}
 }
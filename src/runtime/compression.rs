@@ -0,0 +1,74 @@
+use crate::models::CompressedReply;
+use anyhow::Result;
+use flate2::{write::GzEncoder, Compression as Level};
+use serde::Serialize;
+use std::io::Write;
+
+/// Gzips replies which would be large to send through the broker.
+#[derive(Debug, Clone)]
+pub struct Compression {
+	min_size: usize,
+}
+
+impl Compression {
+	pub fn new(min_size: usize) -> Self {
+		Self { min_size }
+	}
+
+	/// Compress the reply if it's at least the minimum size once encoded, or `None` if it's
+	/// smaller.
+	pub fn compress<T: Serialize + ?Sized>(&self, reply: &T) -> Result<Option<CompressedReply>> {
+		let encoded = rmp_serde::to_vec(reply)?;
+		if encoded.len() < self.min_size {
+			return Ok(None);
+		}
+
+		let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+		encoder.write_all(&encoded)?;
+		Ok(Some(CompressedReply {
+			gzip: encoder.finish()?.into(),
+		}))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::Compression;
+	use crate::models::{RequestResponse, SerializableHttpResponse};
+	use flate2::read::GzDecoder;
+	use std::io::Read;
+
+	fn reply(body: &str) -> RequestResponse<SerializableHttpResponse> {
+		RequestResponse::from(Ok(SerializableHttpResponse {
+			status: 200,
+			headers: Default::default(),
+			url: "https://discord.com/api/v10/guilds/1/members".into(),
+			body: body.to_string().into(),
+			bucket: None,
+			debug: None,
+			ttfb_ms: None,
+			elapsed_ms: None,
+			json: false,
+		}))
+	}
+
+	#[test]
+	fn compresses_large_replies() {
+		let compression = Compression::new(1024);
+		assert_eq!(compression.compress(&reply("[]")).unwrap(), None);
+
+		let large = reply(&"[{\"user\":{\"id\":\"1234\"}}]".repeat(1000));
+		let compressed = compression
+			.compress(&large)
+			.unwrap()
+			.expect("reply should be compressed");
+		let encoded = rmp_serde::to_vec(&large).unwrap();
+		assert!(compressed.gzip.len() < encoded.len());
+
+		let mut decompressed = Vec::new();
+		GzDecoder::new(&compressed.gzip[..])
+			.read_to_end(&mut decompressed)
+			.unwrap();
+		assert_eq!(decompressed, encoded);
+	}
+}
@@ -0,0 +1,77 @@
+use crate::models::{RequestResponse, SerializableHttpResponse};
+use anyhow::Result;
+use bytes::Bytes;
+use redust::{pool::Pool, resp::from_data};
+use std::{
+	fmt::{self, Debug, Formatter},
+	time::Duration,
+};
+
+const KEY_PREFIX: &str = "proxy_dedup:";
+
+/// Remembers the replies to messages which have been fully processed, so a message redelivered by
+/// the broker is answered from its first reply instead of being sent to Discord again.
+#[derive(Clone)]
+pub struct Dedup {
+	pool: Pool<String>,
+	ttl: Duration,
+	/// Prepended to every key, so that proxies sharing Redis keep separate replies.
+	prefix: String,
+}
+
+impl Debug for Dedup {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Dedup").field("ttl", &self.ttl).finish()
+	}
+}
+
+impl Dedup {
+	pub fn new(pool: Pool<String>, ttl: Duration) -> Self {
+		Self {
+			pool,
+			ttl,
+			prefix: String::new(),
+		}
+	}
+
+	/// Prepend the prefix to every key.
+	pub fn with_prefix(mut self, prefix: String) -> Self {
+		self.prefix = prefix;
+		self
+	}
+
+	fn key(&self, id: &str) -> String {
+		format!("{}{}{}", self.prefix, KEY_PREFIX, id)
+	}
+
+	/// Get the reply to the message with the given id, if it has already been processed.
+	pub async fn get(&self, id: &str) -> Result<Option<RequestResponse<SerializableHttpResponse>>> {
+		let mut conn = self.pool.get().await?;
+		let reply = from_data::<Option<Bytes>>(conn.cmd(["GET", &self.key(id)]).await?)?;
+		Ok(reply
+			.map(|reply| rmp_serde::from_slice(&reply))
+			.transpose()?)
+	}
+
+	/// Remember the reply to the message with the given id until the TTL passes.
+	pub async fn store(
+		&self,
+		id: &str,
+		reply: &RequestResponse<SerializableHttpResponse>,
+	) -> Result<()> {
+		let key = self.key(id);
+		let reply = rmp_serde::to_vec(reply)?;
+		let ttl = self.ttl.as_millis().to_string();
+
+		let mut conn = self.pool.get().await?;
+		conn.cmd([
+			b"SET".as_ref(),
+			key.as_bytes(),
+			&reply,
+			b"PX",
+			ttl.as_bytes(),
+		])
+		.await?;
+		Ok(())
+	}
+}
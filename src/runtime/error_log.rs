@@ -0,0 +1,124 @@
+use std::{
+	collections::HashMap,
+	fmt::{self, Debug, Formatter},
+	sync::{Arc, Mutex},
+};
+use tokio::{
+	spawn,
+	time::{sleep, Duration},
+};
+use tracing::warn;
+
+type Sink = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Collapses floods of identical errors in the log. Within each window, the first `threshold`
+/// errors with the same key are logged in full, and the rest are counted and logged as a single
+/// line once the window ends.
+#[derive(Clone)]
+pub struct ErrorLog {
+	window: Duration,
+	threshold: u32,
+	counts: Arc<Mutex<HashMap<String, u32>>>,
+	sink: Sink,
+}
+
+impl Debug for ErrorLog {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ErrorLog")
+			.field("window", &self.window)
+			.field("threshold", &self.threshold)
+			.finish_non_exhaustive()
+	}
+}
+
+impl ErrorLog {
+	pub fn new(window: Duration, threshold: u32) -> Self {
+		Self::with_sink(window, threshold, Arc::new(|line| warn!("{}", line)))
+	}
+
+	fn with_sink(window: Duration, threshold: u32, sink: Sink) -> Self {
+		Self {
+			window,
+			threshold,
+			counts: Default::default(),
+			sink,
+		}
+	}
+
+	/// Log the line for an error, unless more than `threshold` errors with the same key have
+	/// been logged in this window.
+	pub fn log(&self, key: String, line: impl FnOnce() -> String) {
+		let count = {
+			let mut counts = self.counts.lock().unwrap();
+			let count = counts.entry(key.clone()).or_default();
+			*count += 1;
+			*count
+		};
+
+		if count == 1 {
+			self.flush_after_window(key);
+		}
+		if count <= self.threshold {
+			(self.sink)(&line());
+		}
+	}
+
+	/// Once the window for the key ends, log how many of its errors weren't logged and start a
+	/// new window.
+	fn flush_after_window(&self, key: String) {
+		let log = self.clone();
+		spawn(async move {
+			sleep(log.window).await;
+			let count = log.counts.lock().unwrap().remove(&key).unwrap_or_default();
+			if count > log.threshold {
+				(log.sink)(&format!(
+					"{} more occurrences of {} in the last {:?}",
+					count - log.threshold,
+					key,
+					log.window
+				));
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::ErrorLog;
+	use std::sync::{Arc, Mutex};
+	use tokio::time::{sleep, Duration};
+
+	#[tokio::test]
+	async fn aggregates_identical_errors() {
+		let lines = Arc::new(Mutex::new(Vec::new()));
+		let sink_lines = Arc::clone(&lines);
+		let log = ErrorLog::with_sink(
+			Duration::from_millis(100),
+			2,
+			Arc::new(move |line| sink_lines.lock().unwrap().push(line.to_string())),
+		);
+
+		for id in 0..10 {
+			log.log("connection refused".into(), || {
+				format!("<-- ERR({}): connection refused", id)
+			});
+		}
+		log.log("timed out".into(), || "<-- ERR(10): timed out".into());
+		sleep(Duration::from_millis(150)).await;
+
+		log.log("connection refused".into(), || {
+			"<-- ERR(11): connection refused".into()
+		});
+
+		assert_eq!(
+			*lines.lock().unwrap(),
+			vec![
+				"<-- ERR(0): connection refused",
+				"<-- ERR(1): connection refused",
+				"<-- ERR(10): timed out",
+				"8 more occurrences of connection refused in the last 100ms",
+				"<-- ERR(11): connection refused",
+			]
+		);
+	}
+}
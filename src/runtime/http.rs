@@ -0,0 +1,79 @@
+use crate::models::{DnsError, RequestMode};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::sync::Arc;
+use tokio::net::lookup_host;
+
+/// The HTTP clients requests are sent with. Each has its own connection pool and configuration, so
+/// that CDN downloads don't share connections or settings with API calls.
+#[derive(Debug, Clone)]
+pub struct HttpClients {
+	pub api: reqwest::Client,
+	pub cdn: reqwest::Client,
+}
+
+impl HttpClients {
+	/// The client to send requests in the given mode with.
+	pub fn get(&self, mode: RequestMode) -> &reqwest::Client {
+		match mode {
+			RequestMode::Api => &self.api,
+			RequestMode::Cdn | RequestMode::Upload => &self.cdn,
+		}
+	}
+}
+
+impl Default for HttpClients {
+	fn default() -> Self {
+		let client = || {
+			reqwest::Client::builder()
+				.dns_resolver(Arc::new(Resolver))
+				.build()
+				.expect("default HTTP client")
+		};
+
+		Self {
+			api: client(),
+			cdn: client(),
+		}
+	}
+}
+
+/// Resolves hosts with the system resolver, like reqwest does by default, but reports failures as
+/// [`DnsError`]s so they can be told apart from other connection failures.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Resolver;
+
+impl Resolve for Resolver {
+	fn resolve(&self, name: Name) -> Resolving {
+		let host = name.as_str().to_string();
+		Box::pin(async move {
+			let addrs = lookup_host((host, 0)).await.map_err(DnsError)?;
+			Ok(Box::new(addrs) as Addrs)
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::HttpClients;
+	use crate::models::{RequestResponse, ResponseStatus};
+
+	#[tokio::test]
+	async fn reports_dns_failures() {
+		let client = HttpClients::default().api;
+		let send = |url: &'static str| {
+			let req = client.get(url);
+			async move { req.send().await.map_err(anyhow::Error::from) }
+		};
+
+		// .invalid is reserved, so it never resolves
+		assert_eq!(
+			RequestResponse::from(send("http://proxy-test.invalid/").await).status,
+			ResponseStatus::DnsFailure
+		);
+		assert_eq!(
+			RequestResponse::from(send("http://127.0.0.1:1/").await).status,
+			ResponseStatus::RequestFailure
+		);
+	}
+}
@@ -0,0 +1,113 @@
+use super::config::OtelConfig;
+use crate::models::SerializableHttpRequest;
+use anyhow::Result;
+use opentelemetry::{
+	propagation::TextMapPropagator,
+	sdk::{propagation::TraceContextPropagator, trace::Tracer},
+	trace::TraceContextExt,
+};
+use opentelemetry_otlp::WithExportConfig;
+use std::collections::HashMap;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Create a tracing layer which exports spans to the configured OTLP endpoint.
+pub fn layer<S>(config: &OtelConfig) -> Result<OpenTelemetryLayer<S, Tracer>>
+where
+	S: Subscriber + for<'span> LookupSpan<'span>,
+{
+	let tracer = opentelemetry_otlp::new_pipeline()
+		.tracing()
+		.with_exporter(
+			opentelemetry_otlp::new_exporter()
+				.tonic()
+				.with_endpoint(&config.endpoint),
+		)
+		.install_batch(opentelemetry::runtime::Tokio)?;
+
+	Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Link the span to the producer's trace, if the request has a `traceparent` header.
+pub fn set_parent(span: &Span, data: &SerializableHttpRequest) {
+	let headers = data
+		.headers
+		.iter()
+		.map(|(k, v)| (k.to_ascii_lowercase(), v.clone()))
+		.collect::<HashMap<_, _>>();
+
+	let cx = TraceContextPropagator::new().extract(&headers);
+	if cx.span().span_context().is_valid() {
+		span.set_parent(cx);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::set_parent;
+	use crate::models::SerializableHttpRequest;
+	use async_trait::async_trait;
+	use opentelemetry::{
+		sdk::{
+			export::trace::{ExportResult, SpanData, SpanExporter},
+			trace::TracerProvider,
+		},
+		trace::{SpanId, TraceId, TracerProvider as _},
+	};
+	use std::{
+		sync::mpsc::{channel, Sender},
+		time::Duration,
+	};
+	use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+	#[derive(Debug)]
+	struct ChannelExporter(Sender<SpanData>);
+
+	#[async_trait]
+	impl SpanExporter for ChannelExporter {
+		async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+			for span in batch {
+				let _ = self.0.send(span);
+			}
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn exports_linked_spans() {
+		let (sender, receiver) = channel();
+		let provider = TracerProvider::builder()
+			.with_simple_exporter(ChannelExporter(sender))
+			.build();
+		let subscriber = Registry::default()
+			.with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+		let data = SerializableHttpRequest {
+			headers: vec![(
+				"Traceparent".to_string(),
+				"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string(),
+			)]
+			.into_iter()
+			.collect(),
+			..Default::default()
+		};
+
+		tracing::subscriber::with_default(subscriber, || {
+			let span = tracing::info_span!("handle_message");
+			set_parent(&span, &data);
+			let _enter = span.enter();
+		});
+
+		let span = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+		assert_eq!(span.name, "handle_message");
+		assert_eq!(
+			span.span_context.trace_id(),
+			TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap()
+		);
+		assert_eq!(
+			span.parent_span_id,
+			SpanId::from_hex("b7ad6b7169203331").unwrap()
+		);
+	}
+}
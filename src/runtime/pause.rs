@@ -0,0 +1,142 @@
+use super::reload::Reloadable;
+use crate::route::matches_pattern;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Buckets which are paused, by the patterns of their routes. Claims on paused buckets wait until
+/// they're resumed, while other buckets are unaffected.
+#[derive(Debug)]
+pub struct Pause {
+	patterns: watch::Sender<Vec<String>>,
+}
+
+/// A change to the paused buckets, consumed from the pause event, such as
+/// `{"action": "pause", "pattern": "/channels/*/messages"}`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "action", content = "pattern", rename_all = "lowercase")]
+pub enum PauseControl {
+	Pause(String),
+	Resume(String),
+}
+
+impl Default for Pause {
+	fn default() -> Self {
+		Self {
+			patterns: watch::channel(Vec::new()).0,
+		}
+	}
+}
+
+impl Pause {
+	/// Pause routes matching the pattern, such as `/channels/*/messages`.
+	pub fn pause(&self, pattern: &str) {
+		self.patterns.send_if_modified(|patterns| {
+			let paused = !patterns.iter().any(|paused| paused == pattern);
+			if paused {
+				patterns.push(pattern.to_string());
+			}
+			paused
+		});
+	}
+
+	/// Resume routes matching the pattern, waking claims which are no longer paused.
+	pub fn resume(&self, pattern: &str) {
+		self.patterns.send_if_modified(|patterns| {
+			let len = patterns.len();
+			patterns.retain(|paused| paused != pattern);
+			patterns.len() != len
+		});
+	}
+
+	/// Apply a pause or resume.
+	pub fn apply(&self, control: &PauseControl) {
+		match control {
+			PauseControl::Pause(pattern) => self.pause(pattern),
+			PauseControl::Resume(pattern) => self.resume(pattern),
+		}
+	}
+
+	/// Replace the paused patterns.
+	pub fn set(&self, patterns: Vec<String>) {
+		self.patterns.send_if_modified(|paused| {
+			let changed = *paused != patterns;
+			*paused = patterns;
+			changed
+		});
+	}
+
+	pub fn is_paused(&self, route: &str) -> bool {
+		is_paused(&self.patterns.borrow(), route)
+	}
+
+	/// Wait until the route isn't paused.
+	pub async fn wait(&self, route: &str) {
+		let mut patterns = self.patterns.subscribe();
+		while is_paused(&patterns.borrow_and_update(), route) {
+			// the sender can't be dropped while it's borrowed
+			let _ = patterns.changed().await;
+		}
+	}
+
+	/// Pause the buckets in each reloaded config, until the reloads stop.
+	pub async fn follow(self: Arc<Self>, mut reload: watch::Receiver<Reloadable>) {
+		loop {
+			self.set(reload.borrow_and_update().paused.clone());
+			if reload.changed().await.is_err() {
+				break;
+			}
+		}
+	}
+}
+
+fn is_paused(patterns: &[String], route: &str) -> bool {
+	patterns
+		.iter()
+		.any(|pattern| matches_pattern(pattern, route))
+}
+
+#[cfg(test)]
+mod test {
+	use super::{Pause, PauseControl};
+	use tokio::time::{timeout, Duration};
+
+	#[tokio::test]
+	async fn pauses_matching_routes() {
+		let pause = Pause::default();
+		pause.pause("/channels/*/messages");
+		assert!(pause.is_paused("/channels/:id/messages?post"));
+		assert!(!pause.is_paused("/channels/:id/pins?get"));
+
+		let paused = pause.wait("/channels/:id/messages?post");
+		tokio::pin!(paused);
+		assert!(timeout(Duration::from_millis(50), &mut paused)
+			.await
+			.is_err());
+		timeout(
+			Duration::from_millis(50),
+			pause.wait("/channels/:id/pins?get"),
+		)
+		.await
+		.expect("other routes aren't paused");
+
+		pause.resume("/channels/*/messages");
+		timeout(Duration::from_millis(50), paused)
+			.await
+			.expect("resumed route is still paused");
+	}
+
+	#[test]
+	fn applies_controls() {
+		let pause = Pause::default();
+		let control = serde_json::from_str::<PauseControl>(
+			r#"{"action":"pause","pattern":"/channels/*/messages"}"#,
+		)
+		.unwrap();
+		pause.apply(&control);
+		assert!(pause.is_paused("/channels/:id/messages?post"));
+
+		pause.apply(&PauseControl::Resume("/channels/*/messages".into()));
+		assert!(!pause.is_paused("/channels/:id/messages?post"));
+	}
+}
@@ -0,0 +1,140 @@
+use serde::Serialize;
+use std::sync::{
+	atomic::{AtomicUsize, Ordering},
+	Arc,
+};
+use tokio::sync::watch;
+
+/// Whether the proxy has stopped taking new messages, so it can be drained before it's stopped,
+/// and how many messages it's still handling.
+#[derive(Debug)]
+pub struct Quiesce {
+	quiesced: watch::Sender<bool>,
+	handling: AtomicUsize,
+}
+
+impl Default for Quiesce {
+	fn default() -> Self {
+		Self {
+			quiesced: watch::channel(false).0,
+			handling: AtomicUsize::new(0),
+		}
+	}
+}
+
+/// The state of a [`Quiesce`], as served from the readiness endpoint.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct QuiesceStatus {
+	pub quiesced: bool,
+	/// The number of messages which are still being handled.
+	pub handling: usize,
+}
+
+impl Quiesce {
+	/// Stop taking new messages, while those already taken are finished.
+	pub fn quiesce(&self) {
+		self.quiesced
+			.send_if_modified(|quiesced| !std::mem::replace(quiesced, true));
+	}
+
+	/// Take new messages again.
+	pub fn resume(&self) {
+		self.quiesced
+			.send_if_modified(|quiesced| std::mem::replace(quiesced, false));
+	}
+
+	pub fn is_quiesced(&self) -> bool {
+		*self.quiesced.borrow()
+	}
+
+	pub fn status(&self) -> QuiesceStatus {
+		QuiesceStatus {
+			quiesced: self.is_quiesced(),
+			handling: self.handling.load(Ordering::Acquire),
+		}
+	}
+
+	/// Count a message as being handled until the returned guard is dropped.
+	pub fn handle(self: &Arc<Self>) -> Handling {
+		self.handling.fetch_add(1, Ordering::AcqRel);
+		Handling(Arc::clone(self))
+	}
+
+	/// Wait until the proxy is quiesced, or resumed if `quiesced` is false.
+	pub async fn wait(&self, quiesced: bool) {
+		let mut state = self.quiesced.subscribe();
+		while *state.borrow_and_update() != quiesced {
+			// the sender can't be dropped while it's borrowed
+			let _ = state.changed().await;
+		}
+	}
+
+	/// Quiesce on SIGUSR1 and resume on SIGUSR2.
+	#[cfg(unix)]
+	pub async fn follow_signals(self: Arc<Self>) -> anyhow::Result<()> {
+		use tokio::signal::unix::{signal, SignalKind};
+		use tracing::info;
+
+		let mut quiesce = signal(SignalKind::user_defined1())?;
+		let mut resume = signal(SignalKind::user_defined2())?;
+		loop {
+			tokio::select! {
+				Some(_) = quiesce.recv() => {
+					info!("Quiescing: no new messages will be taken");
+					self.quiesce();
+				}
+				Some(_) = resume.recv() => {
+					info!("Resuming taking new messages");
+					self.resume();
+				}
+				else => break,
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// A message being handled, counted by a [`Quiesce`].
+#[derive(Debug)]
+pub struct Handling(Arc<Quiesce>);
+
+impl Drop for Handling {
+	fn drop(&mut self) {
+		self.0.handling.fetch_sub(1, Ordering::AcqRel);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{Quiesce, QuiesceStatus};
+	use std::sync::Arc;
+	use tokio::time::{timeout, Duration};
+
+	#[tokio::test]
+	async fn counts_messages_until_idle() {
+		let quiesce = Arc::new(Quiesce::default());
+		let handling = quiesce.handle();
+
+		let quiesced = quiesce.wait(true);
+		tokio::pin!(quiesced);
+		assert!(timeout(Duration::from_millis(50), &mut quiesced)
+			.await
+			.is_err());
+
+		quiesce.quiesce();
+		timeout(Duration::from_millis(50), quiesced)
+			.await
+			.expect("wasn't quiesced");
+		assert_eq!(
+			quiesce.status(),
+			QuiesceStatus {
+				quiesced: true,
+				handling: 1
+			}
+		);
+
+		drop(handling);
+		assert_eq!(quiesce.status().handling, 0);
+	}
+}
@@ -0,0 +1,100 @@
+#[cfg(feature = "metrics")]
+use crate::metrics::RATE_CAP_WAIT;
+use crate::models::{Rejection, ResponseStatus};
+use tokio::{
+	sync::Mutex,
+	time::{sleep, Duration, Instant},
+};
+
+/// A token bucket capping the rate of all outgoing requests, regardless of Discord's limits.
+#[derive(Debug)]
+pub struct RateCap {
+	per_second: f64,
+	burst: f64,
+	fail_fast: bool,
+	state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+	/// Tokens available as of `updated`. This is negative when requests are waiting for tokens
+	/// which haven't been added yet.
+	tokens: f64,
+	updated: Instant,
+}
+
+impl RateCap {
+	pub fn new(per_second: f64, burst: u32, fail_fast: bool) -> Self {
+		let burst = burst.max(1) as f64;
+		Self {
+			per_second,
+			burst,
+			fail_fast,
+			state: Mutex::new(State {
+				tokens: burst,
+				updated: Instant::now(),
+			}),
+		}
+	}
+
+	/// Take a token, waiting until one is available unless the cap fails fast. The wait is
+	/// recorded for the profile.
+	#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+	pub async fn acquire(&self, profile: &str) -> Result<(), Rejection> {
+		let wait = {
+			let mut state = self.state.lock().await;
+			let now = Instant::now();
+			let refilled = now.duration_since(state.updated).as_secs_f64() * self.per_second;
+			state.tokens = (state.tokens + refilled).min(self.burst);
+			state.updated = now;
+
+			if state.tokens < 1. && self.fail_fast {
+				return Err(Rejection::new(
+					ResponseStatus::RateCapped,
+					format!("outbound rate is capped at {}/s", self.per_second),
+				));
+			}
+
+			// reserve the token now, so later requests wait behind this one
+			state.tokens -= 1.;
+			Duration::from_secs_f64((-state.tokens).max(0.) / self.per_second)
+		};
+
+		#[cfg(feature = "metrics")]
+		RATE_CAP_WAIT
+			.with_label_values(&[profile])
+			.observe(wait.as_secs_f64());
+		sleep(wait).await;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::RateCap;
+	use crate::models::ResponseStatus;
+	use tokio::time::{Duration, Instant};
+
+	#[tokio::test]
+	async fn caps_rate() {
+		let cap = RateCap::new(20., 1, false);
+		let start = Instant::now();
+		for _ in 0..11 {
+			cap.acquire("").await.unwrap();
+		}
+
+		// the first request uses the initial token, and each of the rest waits 50ms for another
+		assert!(start.elapsed() >= Duration::from_millis(500));
+	}
+
+	#[tokio::test]
+	async fn fails_fast() {
+		let cap = RateCap::new(1., 2, true);
+		cap.acquire("").await.unwrap();
+		cap.acquire("").await.unwrap();
+		assert_eq!(
+			cap.acquire("").await.unwrap_err().status,
+			ResponseStatus::RateCapped
+		);
+	}
+}
@@ -0,0 +1,114 @@
+use super::{
+	config::{ForwardedConfig, PathsConfig, QueryConfig},
+	Config,
+};
+use crate::route::RouteRule;
+use std::{collections::HashMap, sync::Arc};
+use tokio::{sync::watch, time::Duration};
+
+/// The subset of the configuration which can be changed without restarting the proxy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloadable {
+	pub timeout: Option<Duration>,
+	pub timeouts: Arc<HashMap<String, Duration>>,
+	pub routes: Arc<[RouteRule]>,
+	pub reserved_headers: Arc<[String]>,
+	pub header_profiles: Arc<HashMap<String, HashMap<String, String>>>,
+	pub query_limits: QueryConfig,
+	pub forwarded: ForwardedConfig,
+	pub paths: PathsConfig,
+	pub validate_json: bool,
+	pub echo: bool,
+	pub check_response_json: bool,
+	pub paused: Vec<String>,
+}
+
+impl From<&Config> for Reloadable {
+	fn from(config: &Config) -> Self {
+		Self {
+			timeout: config.timeout,
+			timeouts: Arc::new(config.timeouts.clone()),
+			routes: config.routes.clone().into(),
+			reserved_headers: config.headers.reserved_prefixes.clone().into(),
+			header_profiles: Arc::new(config.headers.profiles.clone()),
+			query_limits: config.query.clone(),
+			forwarded: config.headers.forwarded.clone(),
+			paths: config.paths.clone(),
+			validate_json: config.validate_json,
+			echo: config.echo,
+			check_response_json: config.check_response_json,
+			paused: config.paused.clone(),
+		}
+	}
+}
+
+/// Reload the config file whenever the process receives SIGHUP, publishing the reloadable settings
+/// to `reload`. Changes to any other settings are logged as requiring a restart.
+#[cfg(unix)]
+pub async fn reload_on_hangup(
+	path: String,
+	config: Config,
+	reload: watch::Sender<Reloadable>,
+) -> anyhow::Result<()> {
+	reload_with(path, config, reload, Some).await
+}
+
+/// Like [`reload_on_hangup`], for the profile with the name in the config file.
+#[cfg(unix)]
+pub async fn reload_profile_on_hangup(
+	path: String,
+	name: String,
+	config: Config,
+	reload: watch::Sender<Reloadable>,
+) -> anyhow::Result<()> {
+	reload_with(path, config, reload, move |new_config| {
+		new_config
+			.profiles
+			.into_iter()
+			.find(|profile| profile.name == name)
+			.map(|profile| profile.config)
+	})
+	.await
+}
+
+/// Reload on SIGHUP, taking the config to apply from each reloaded config file.
+#[cfg(unix)]
+async fn reload_with(
+	path: String,
+	mut config: Config,
+	reload: watch::Sender<Reloadable>,
+	select: impl Fn(Config) -> Option<Config>,
+) -> anyhow::Result<()> {
+	use tokio::signal::unix::{signal, SignalKind};
+	use tracing::{info, warn};
+
+	let mut hangup = signal(SignalKind::hangup())?;
+	while hangup.recv().await.is_some() {
+		info!("Reloading config from \"{}\"", path);
+		let new_config = match Config::from_toml_file(&path) {
+			Ok(new_config) => new_config.with_env(),
+			Err(e) => {
+				warn!("Unable to reload config: {:?}", e);
+				continue;
+			}
+		};
+		let new_config = match select(new_config) {
+			Some(new_config) => new_config,
+			None => {
+				warn!("Profile was removed from the config, which requires a restart to apply");
+				continue;
+			}
+		};
+
+		if new_config.requires_restart(&config) {
+			warn!("Config changes to connection-level settings require a restart to apply");
+		}
+
+		if reload.send(Reloadable::from(&new_config)).is_err() {
+			break;
+		}
+		config = new_config;
+	}
+
+	Ok(())
+}
@@ -0,0 +1,91 @@
+use crate::models::{SerializableHttpRequest, SerializableHttpResponse};
+use anyhow::Result;
+use rustacles_brokers::redis::RedisBroker;
+use std::fmt::{self, Debug, Formatter};
+use tokio::{
+	spawn,
+	time::{sleep, Duration},
+};
+use tracing::{debug, warn};
+
+/// Publishes transiently failed requests back onto the broker after a delay, instead of retrying
+/// them inline.
+#[derive(Clone)]
+pub struct Requeue {
+	pub broker: RedisBroker<String>,
+	pub event: String,
+	pub delay: Duration,
+	pub max_redeliveries: u32,
+	pub poison_event: Option<String>,
+}
+
+impl Debug for Requeue {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Requeue")
+			.field("event", &self.event)
+			.field("delay", &self.delay)
+			.field("max_redeliveries", &self.max_redeliveries)
+			.field("poison_event", &self.poison_event)
+			.finish()
+	}
+}
+
+impl Requeue {
+	/// Whether the given request should be requeued based on its outcome and redelivery count.
+	pub fn should_requeue(
+		&self,
+		data: &SerializableHttpRequest,
+		res: &Result<SerializableHttpResponse>,
+	) -> bool {
+		data.redeliveries < self.max_redeliveries && is_transient(res)
+	}
+
+	/// Whether the given request failed transiently but has already been requeued as many times
+	/// as allowed.
+	pub fn is_poisoned(
+		&self,
+		data: &SerializableHttpRequest,
+		res: &Result<SerializableHttpResponse>,
+	) -> bool {
+		data.redeliveries >= self.max_redeliveries && is_transient(res)
+	}
+
+	/// Publish a poisoned request to the poison event, if one is configured.
+	pub async fn poison(&self, data: &SerializableHttpRequest) {
+		if let Some(event) = &self.poison_event {
+			if let Err(e) = self.broker.publish(event.as_str(), data).await {
+				warn!("Unable to publish poisoned request: {:?}", e);
+			}
+		}
+	}
+
+	/// Schedule the request to be published again after the configured delay. The redelivery
+	/// count of the published request is incremented.
+	pub fn schedule(&self, mut data: SerializableHttpRequest) {
+		data.redeliveries += 1;
+
+		let requeue = self.clone();
+		spawn(async move {
+			sleep(requeue.delay).await;
+			debug!(
+				"Requeueing \"{} {}\" (redelivery {})",
+				data.method, data.path, data.redeliveries
+			);
+
+			if let Err(e) = requeue.broker.publish(requeue.event.as_str(), &data).await {
+				warn!("Unable to requeue request: {:?}", e);
+			}
+		});
+	}
+}
+
+/// Whether a request outcome represents a failure that's likely to succeed if tried again later.
+pub fn is_transient(res: &Result<SerializableHttpResponse>) -> bool {
+	match res {
+		Ok(res) => matches!(res.status, 502..=504),
+		Err(e) => match e.downcast_ref::<reqwest::Error>() {
+			Some(e) => e.is_connect() || e.is_timeout(),
+			None => false,
+		},
+	}
+}
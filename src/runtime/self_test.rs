@@ -0,0 +1,86 @@
+use super::Client;
+#[cfg(feature = "metrics")]
+use crate::metrics::{SELF_TEST_LAST_SUCCESS, SELF_TEST_SUCCESS};
+use crate::{models::SerializableHttpRequest, ratelimiter::Ratelimiter};
+#[cfg(feature = "metrics")]
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tracing::{info, warn};
+
+/// Periodically sends a request through the whole proxy, from claiming its bucket to receiving
+/// its response, so failures are noticed even when there's no traffic.
+#[derive(Debug, Clone)]
+pub struct SelfTest {
+	interval: Duration,
+	path: String,
+}
+
+impl SelfTest {
+	pub fn new(interval: Duration, path: String) -> Self {
+		Self { interval, path }
+	}
+
+	/// Run the self-test every interval, forever.
+	pub async fn run<R>(self, client: Client<R>)
+	where
+		R: Ratelimiter + Clone + Sync + Send + 'static,
+	{
+		let mut ticks = interval(self.interval);
+		ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		loop {
+			ticks.tick().await;
+			self.check(&client).await;
+		}
+	}
+
+	/// Send the self-test request once, returning whether it succeeded.
+	pub async fn check<R>(&self, client: &Client<R>) -> bool
+	where
+		R: Ratelimiter + Clone + Sync + Send + 'static,
+	{
+		// the request's own metrics are labeled apart from real traffic
+		let tester = Client {
+			profile: match client.profile.as_str() {
+				"" => "self-test".to_string(),
+				profile => format!("{}/self-test", profile),
+			},
+			..client.clone()
+		};
+		let data = SerializableHttpRequest::builder()
+			.method("GET")
+			.path(&self.path)
+			.build();
+
+		let succeeded = match tester.request(&data).await {
+			Ok(res) if res.status < 400 => {
+				info!("<-- SELF-TEST: {}", res);
+				true
+			}
+			Ok(res) => {
+				warn!("<-- SELF-TEST failed: {}", res);
+				false
+			}
+			Err(e) => {
+				warn!("<-- SELF-TEST failed: {:?}", e);
+				false
+			}
+		};
+
+		#[cfg(feature = "metrics")]
+		{
+			SELF_TEST_SUCCESS
+				.with_label_values(&[&client.profile])
+				.set(succeeded as i64);
+			if succeeded {
+				let now = SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.unwrap_or_default();
+				SELF_TEST_LAST_SUCCESS
+					.with_label_values(&[&client.profile])
+					.set(now.as_secs_f64());
+			}
+		}
+
+		succeeded
+	}
+}
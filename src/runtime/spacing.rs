@@ -0,0 +1,52 @@
+use std::{collections::HashMap, sync::Mutex};
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// Spaces out requests to the same bucket, so they aren't all sent at the start of the bucket's
+/// window. This applies in addition to the bucket's limit.
+#[derive(Debug, Default)]
+pub struct Spacing {
+	/// When the last request to each bucket was (or is scheduled to be) sent.
+	last_sent: Mutex<HashMap<String, Instant>>,
+}
+
+impl Spacing {
+	/// Wait until at least `min` has passed since the last request to the bucket.
+	pub async fn wait(&self, bucket: &str, min: Duration) {
+		let send_at = {
+			let mut last_sent = self.last_sent.lock().unwrap();
+			let now = Instant::now();
+			let send_at = match last_sent.get(bucket) {
+				Some(last) => (*last + min).max(now),
+				None => now,
+			};
+
+			// reserve the slot now, so later requests are spaced after this one
+			last_sent.insert(bucket.to_string(), send_at);
+			send_at
+		};
+
+		sleep_until(send_at).await;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::Spacing;
+	use tokio::time::{Duration, Instant};
+
+	#[tokio::test]
+	async fn spaces_requests() {
+		let spacing = Spacing::default();
+		let min = Duration::from_millis(50);
+
+		let start = Instant::now();
+		for _ in 0..5 {
+			spacing.wait("/channels/:id/messages", min).await;
+		}
+		spacing.wait("/channels/:id/pins", min).await;
+
+		// the first request to each bucket is sent immediately, and the rest 50ms apart
+		assert!(start.elapsed() >= Duration::from_millis(200));
+		assert!(start.elapsed() < Duration::from_millis(250));
+	}
+}
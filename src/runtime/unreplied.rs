@@ -0,0 +1,53 @@
+use crate::models::UnrepliedResponse;
+use rustacles_brokers::redis::RedisBroker;
+use serde::Serialize;
+use std::fmt::{self, Debug, Formatter};
+use tracing::{debug, warn};
+
+/// Handles the responses to messages which can't be replied to.
+#[derive(Clone, Default)]
+pub enum Unreplied {
+	/// Drop the responses, only logging them at debug level.
+	Drop,
+	/// Drop the responses with a warning.
+	#[default]
+	Warn,
+	/// Publish the responses to an event, along with the ids of the messages they answer.
+	Publish {
+		broker: RedisBroker<String>,
+		event: String,
+	},
+}
+
+impl Debug for Unreplied {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Drop => f.write_str("Drop"),
+			Self::Warn => f.write_str("Warn"),
+			Self::Publish { event, .. } => f.debug_struct("Publish").field("event", event).finish(),
+		}
+	}
+}
+
+impl Unreplied {
+	/// Handle the response to the message with the given id, which failed to be replied to.
+	pub async fn handle<T>(&self, id: &str, response: &T, error: impl Debug)
+	where
+		T: Serialize + Sync + ?Sized,
+	{
+		match self {
+			Self::Drop => debug!("Dropping response to {}: {:?}", id, error),
+			Self::Warn => warn!("Unable to reply to {}: {:?}", id, error),
+			Self::Publish { broker, event } => {
+				debug!("Publishing response to {} to {}: {:?}", id, event, error);
+				let published = UnrepliedResponse {
+					id: id.to_string(),
+					response,
+				};
+				if let Err(e) = broker.publish(event.as_str(), &published).await {
+					warn!("Unable to publish response to {}: {:?}", id, e);
+				}
+			}
+		}
+	}
+}
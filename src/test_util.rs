@@ -0,0 +1,133 @@
+//! Utilities for testing the proxy against something that behaves like Discord.
+
+use std::{
+	collections::VecDeque,
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+use tokio::{spawn, task::JoinHandle};
+use warp::{http::Response, Filter};
+
+#[derive(Debug)]
+struct State {
+	limit: usize,
+	reset_after: Duration,
+	remaining: usize,
+	resets_at: Option<Instant>,
+	injected: VecDeque<u16>,
+	requests: usize,
+	ratelimited: usize,
+}
+
+impl State {
+	fn respond(&mut self) -> Response<String> {
+		self.requests += 1;
+
+		let now = Instant::now();
+		let resets_at = match self.resets_at {
+			Some(resets_at) if resets_at > now => resets_at,
+			_ => {
+				self.remaining = self.limit;
+				*self.resets_at.insert(now + self.reset_after)
+			}
+		};
+		let reset_after = format!("{:.3}", (resets_at - now).as_secs_f64());
+
+		let builder = Response::builder()
+			.header("x-ratelimit-bucket", "fake")
+			.header("x-ratelimit-limit", self.limit)
+			.header("x-ratelimit-reset-after", &reset_after);
+
+		let status = match self.injected.pop_front() {
+			Some(status) => status,
+			None if self.remaining == 0 => 429,
+			None => {
+				self.remaining -= 1;
+				200
+			}
+		};
+
+		if status == 429 {
+			self.ratelimited += 1;
+			return builder
+				.status(429)
+				.header("x-ratelimit-remaining", 0)
+				.header("x-ratelimit-scope", "user")
+				.header("retry-after", &reset_after)
+				.body(format!(
+					r#"{{"message":"You are being rate limited.","retry_after":{},"global":false}}"#,
+					reset_after
+				))
+				.unwrap();
+		}
+
+		builder
+			.status(status)
+			.header("x-ratelimit-remaining", self.remaining)
+			.body("{}".to_string())
+			.unwrap()
+	}
+}
+
+/// An HTTP server which emulates Discord's ratelimiting for a single bucket shared by every route.
+/// Requests over the limit receive a 429 with `Retry-After`, and arbitrary statuses can be
+/// injected to simulate failures.
+#[derive(Debug)]
+pub struct FakeDiscord {
+	addr: SocketAddr,
+	state: Arc<Mutex<State>>,
+	server: JoinHandle<()>,
+}
+
+impl FakeDiscord {
+	/// Start a server allowing `limit` requests every `reset_after`.
+	pub fn start(limit: usize, reset_after: Duration) -> Self {
+		let state = Arc::new(Mutex::new(State {
+			limit,
+			reset_after,
+			remaining: limit,
+			resets_at: None,
+			injected: VecDeque::new(),
+			requests: 0,
+			ratelimited: 0,
+		}));
+
+		let route_state = Arc::clone(&state);
+		let route = warp::any().map(move || route_state.lock().unwrap().respond());
+		let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+
+		Self {
+			addr,
+			state,
+			server: spawn(server),
+		}
+	}
+
+	/// The address the server is listening on, suitable for `Client::api_base`.
+	pub fn addr(&self) -> SocketAddr {
+		self.addr
+	}
+
+	/// Respond to the next request with the given status instead of the normal behavior. Injected
+	/// statuses are used in the order they're added.
+	pub fn inject(&self, status: u16) {
+		self.state.lock().unwrap().injected.push_back(status);
+	}
+
+	/// The total number of requests received.
+	pub fn requests(&self) -> usize {
+		self.state.lock().unwrap().requests
+	}
+
+	/// The number of requests which received a 429.
+	pub fn ratelimited(&self) -> usize {
+		self.state.lock().unwrap().ratelimited
+	}
+}
+
+impl Drop for FakeDiscord {
+	fn drop(&mut self) {
+		self.server.abort();
+	}
+}
@@ -0,0 +1,119 @@
+#![cfg(feature = "test-util")]
+
+use anyhow::Result;
+use spectacles_proxy::{
+	models::SerializableHttpRequest,
+	ratelimiter::local::LocalRatelimiter,
+	runtime::{backoff::Backoff, Client},
+	test_util::FakeDiscord,
+};
+use std::sync::Arc;
+use test_log::test;
+use tokio::time::{Duration, Instant};
+
+fn get_client(discord: &FakeDiscord) -> Client<LocalRatelimiter> {
+	Client {
+		profile: Default::default(),
+		api_base: discord.addr().to_string(),
+		cdn_base: discord.addr().to_string(),
+		upload_hosts: Default::default(),
+		token: None,
+		api_scheme: uriparse::Scheme::HTTP,
+		api_version: 10,
+		http: Default::default(),
+		ratelimiter: LocalRatelimiter::default(),
+		timeout: None,
+		timeouts: Default::default(),
+		requeue: None,
+		routes: Default::default(),
+		reserved_headers: Default::default(),
+		header_profiles: Default::default(),
+		query_limits: Default::default(),
+		forwarded: Default::default(),
+		paths: Default::default(),
+		validate_json: false,
+		echo: false,
+		check_response_json: false,
+		bucket_limit: None,
+		bucket_hashes: None,
+		path_buckets: false,
+		version_buckets: false,
+		spacing: Default::default(),
+		pause: Default::default(),
+		quiesce: Default::default(),
+		cancellations: Default::default(),
+		body_store: None,
+		backoff: None,
+		signer: None,
+		compression: None,
+		release_grace: None,
+		shutdown_grace: None,
+		rate_cap: None,
+		lanes: Default::default(),
+		dedup: None,
+		unreplied: Default::default(),
+		error_log: None,
+		concurrency: None,
+		batch: Default::default(),
+		reload: None,
+	}
+}
+
+fn get_request() -> SerializableHttpRequest {
+	SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/channels/1/messages".into(),
+		..Default::default()
+	}
+}
+
+#[test(tokio::test)]
+async fn returns_server_error() -> Result<()> {
+	let discord = FakeDiscord::start(5, Duration::from_secs(1));
+	let client = get_client(&discord);
+
+	discord.inject(503);
+	let res = client.request(&get_request()).await?;
+	assert_eq!(res.status, 503);
+
+	let res = client.request(&get_request()).await?;
+	assert_eq!(res.status, 200);
+	assert_eq!(discord.requests(), 2);
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn returns_ratelimited_response() -> Result<()> {
+	let discord = FakeDiscord::start(5, Duration::from_secs(1));
+	let client = get_client(&discord);
+
+	discord.inject(429);
+	let res = client.request(&get_request()).await?;
+	assert_eq!(res.status, 429);
+	assert!(res.headers.contains_key("retry-after"));
+	assert_eq!(discord.ratelimited(), 1);
+
+	let res = client.request(&get_request()).await?;
+	assert_eq!(res.status, 200);
+	assert_eq!(discord.requests(), 2);
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn retries_ratelimited_request() -> Result<()> {
+	let discord = FakeDiscord::start(5, Duration::from_millis(500));
+	let mut client = get_client(&discord);
+	client.backoff = Some(Arc::new(
+		Backoff::new(Duration::from_secs(1), Duration::from_secs(60)).with_retries(1),
+	));
+
+	// the 429's Retry-After lasts until the window resets, when the retry succeeds
+	discord.inject(429);
+	let start = Instant::now();
+	let res = client.request(&get_request()).await?;
+	assert_eq!(res.status, 200);
+	assert!(start.elapsed() >= Duration::from_millis(400));
+	assert_eq!(discord.ratelimited(), 1);
+	assert_eq!(discord.requests(), 2);
+	Ok(())
+}
@@ -1,48 +1,101 @@
 use anyhow::Result;
 use bytes::Bytes;
 use futures::TryStreamExt;
+use http::{
+	header::{CONNECTION, CONTENT_LENGTH},
+	HeaderMap, HeaderValue, Method,
+};
 use mockito::mock;
 use rustacles_brokers::common::Rpc;
 use rustacles_brokers::redis::redust::pool::{Manager, Pool};
 use rustacles_brokers::redis::RedisBroker;
-use spectacles_proxy::ratelimiter::local::LocalRatelimiter;
+use spectacles_proxy::ratelimiter::{local::LocalRatelimiter, RatelimitInfo, Ratelimiter};
 use spectacles_proxy::{
 	models::{
-		RequestResponse, RequestResponseBody, ResponseStatus, SerializableHttpRequest,
-		SerializableHttpResponse,
+		BatchRequest, Rejection, RequestBody, RequestMode, RequestResponse, RequestResponseBody,
+		ResponseStatus, SerializableHttpRequest, SerializableHttpResponse, UnrepliedResponse,
+	},
+	route::make_route,
+	runtime::{
+		backoff::Backoff, body::BodyStore, bucket_map::BucketMap, config::PathsConfig,
+		dedup::Dedup, pause::PauseControl, requeue::Requeue, unreplied::Unreplied, Client, Config,
 	},
-	runtime::{Client, Config},
 };
-use std::sync::Arc;
+use std::{
+	sync::Arc,
+	time::{Instant, SystemTime},
+};
 use test_log::test;
 use tokio::{
 	spawn,
 	time::{timeout, Duration},
 };
 
-#[test(tokio::test)]
-async fn handles_request() -> Result<()> {
-	let config = dbg!(Config::default().with_env());
+fn get_broker(config: &Config) -> RedisBroker<String> {
 	let manager = Manager::new(config.redis.url.clone());
 	let pool = Pool::builder(manager)
 		.max_size(config.redis.pool_size)
 		.build()
 		.expect("pool should be built");
-	let broker = RedisBroker::new(config.broker.group.clone(), pool.clone());
-
-	let rpc_broker = RedisBroker::new(config.broker.group, pool);
-
-	let ratelimiter = LocalRatelimiter::default();
-	let mock_addr = mockito::server_address();
+	RedisBroker::new(config.broker.group.clone(), pool)
+}
 
-	let client = Client {
-		api_base: mock_addr.to_string(),
+fn get_client() -> Client<Arc<LocalRatelimiter>> {
+	Client {
+		profile: Default::default(),
+		api_base: mockito::server_address().to_string(),
+		cdn_base: mockito::server_address().to_string(),
+		upload_hosts: Default::default(),
+		token: None,
 		api_scheme: uriparse::Scheme::HTTP,
 		api_version: 6,
-		http: reqwest::Client::new(),
-		ratelimiter: Arc::new(ratelimiter),
+		http: Default::default(),
+		ratelimiter: Arc::new(LocalRatelimiter::default()),
 		timeout: None,
-	};
+		timeouts: Default::default(),
+		requeue: None,
+		routes: Default::default(),
+		reserved_headers: Default::default(),
+		header_profiles: Default::default(),
+		query_limits: Default::default(),
+		forwarded: Default::default(),
+		paths: Default::default(),
+		validate_json: false,
+		echo: false,
+		check_response_json: false,
+		bucket_limit: None,
+		bucket_hashes: None,
+		path_buckets: false,
+		version_buckets: false,
+		spacing: Default::default(),
+		pause: Default::default(),
+		quiesce: Default::default(),
+		cancellations: Default::default(),
+		body_store: None,
+		backoff: None,
+		signer: None,
+		compression: None,
+		release_grace: None,
+		shutdown_grace: None,
+		rate_cap: None,
+		lanes: Default::default(),
+		dedup: None,
+		unreplied: Default::default(),
+		error_log: None,
+		concurrency: None,
+		batch: Default::default(),
+		reload: None,
+	}
+}
+
+#[test(tokio::test)]
+async fn handles_request() -> Result<()> {
+	let config = dbg!(Config::default().with_env());
+	let broker = get_broker(&config);
+	let rpc_broker = get_broker(&config);
+
+	let client = get_client();
+	let mock_addr = mockito::server_address();
 
 	let mock = mock("GET", "/api/v6/foo/bar")
 		.with_body(rmp_serde::to_vec(&["hello world"])?)
@@ -63,10 +116,7 @@ async fn handles_request() -> Result<()> {
 	let payload = SerializableHttpRequest {
 		method: "GET".into(),
 		path: "/foo/bar".into(),
-		query: None,
-		body: None,
-		headers: Default::default(),
-		timeout: None,
+		..Default::default()
 	};
 
 	let rpc = timeout(
@@ -87,15 +137,1719 @@ async fn handles_request() -> Result<()> {
 		RequestResponseBody::Ok(SerializableHttpResponse {
 			status: 200,
 			headers: vec![
-				("connection".to_string(), "close".to_string()),
-				("content-length".to_string(), "13".to_string())
+				(CONNECTION, HeaderValue::from_static("close")),
+				(CONTENT_LENGTH, HeaderValue::from_static("13")),
 			]
 			.into_iter()
-			.collect(),
+			.collect::<HeaderMap>()
+			.into(),
 			url: format!("http://{}/api/v6/foo/bar", mock_addr),
 			body: rmp_serde::to_vec(&["hello world"])?.into(),
+			bucket: Some("/foo/bar?get".to_string()),
+			debug: None,
+			ttfb_ms: None,
+			elapsed_ms: None,
+			json: false,
+		})
+	);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn rejects_invalid_request() -> Result<()> {
+	let event = "REJECT_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let client = get_client();
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let payload = SerializableHttpRequest {
+		method: "NOT A METHOD".into(),
+		path: "/foo/bar".into(),
+		..Default::default()
+	};
+	let rpc = broker.call(event, &payload, None).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	client.handle_message(message).await?;
+
+	let response = timeout(
+		Duration::from_secs(5),
+		rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+	)
+	.await??
+	.unwrap();
+	assert_eq!(response.status, ResponseStatus::InvalidMethod);
+	assert!(matches!(response.body, RequestResponseBody::Err(_)));
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn rejects_invalid_path_once() -> Result<()> {
+	let event = "INVALID_PATH_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let client = get_client();
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "foo/bar".into(),
+		prefix: Some(false),
+		..Default::default()
+	};
+	let rpc = broker.call(event, &payload, None).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	client.handle_message(message).await?;
+
+	let response = timeout(
+		Duration::from_secs(5),
+		rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+	)
+	.await??
+	.unwrap();
+	assert_eq!(response.status, ResponseStatus::InvalidPath);
+	assert!(matches!(response.body, RequestResponseBody::Err(_)));
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn rejects_expired_request() -> Result<()> {
+	let event = "EXPIRED_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let client = get_client();
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let mock = mock("GET", "/api/v6/expired").expect(0).create();
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/expired".into(),
+		// the relative timeout would still allow the request, but the deadline has passed
+		timeout: Some(Duration::from_secs(60)),
+		deadline: Some(SystemTime::now() - Duration::from_secs(1)),
+		..Default::default()
+	};
+	let rpc = broker.call(event, &payload, None).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	client.handle_message(message).await?;
+
+	let response = timeout(
+		Duration::from_secs(5),
+		rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+	)
+	.await??
+	.unwrap();
+	assert_eq!(response.status, ResponseStatus::Expired);
+	mock.assert();
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn replies_to_timed_out_request() -> Result<()> {
+	let event = "TIMED_OUT_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let client = get_client();
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	// the request times out waiting on its bucket, which is held until after the timeout
+	client
+		.ratelimiter
+		.claim(make_route(&Method::GET, "/timed_out")?)
+		.await?;
+
+	let mock = mock("GET", "/api/v6/timed_out").expect(0).create();
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/timed_out".into(),
+		timeout: Some(Duration::from_millis(100)),
+		..Default::default()
+	};
+	let rpc = broker.call(event, &payload, None).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	client.handle_message(message).await?;
+
+	let response = timeout(
+		Duration::from_secs(5),
+		rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+	)
+	.await??
+	.unwrap();
+	assert_eq!(response.status, ResponseStatus::RequestTimeout);
+	mock.assert();
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn restricts_paths() -> Result<()> {
+	let event = "PATHS_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let mut client = get_client();
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let allowed = mock("GET", "/api/v6/channels/1/pins").expect(2).create();
+	let denied = mock("GET", "/api/v6/channels/1/messages")
+		.expect(1)
+		.create();
+
+	let mut statuses = Vec::new();
+	for paths in [
+		// everything is allowed by default
+		PathsConfig::default(),
+		PathsConfig {
+			allow: vec!["/channels".into()],
+			deny: vec!["/channels/*/messages".into()],
+		},
+	] {
+		client.paths = paths;
+		for path in ["/channels/1/pins", "/channels/1/messages"] {
+			let payload = SerializableHttpRequest {
+				method: "GET".into(),
+				path: path.into(),
+				..Default::default()
+			};
+			let rpc = broker.call(event, &payload, None).await?;
+			let message = timeout(Duration::from_secs(5), consumer.try_next())
+				.await??
+				.expect("message");
+			client.handle_message(message).await?;
+			let response = timeout(
+				Duration::from_secs(5),
+				rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+			)
+			.await??
+			.unwrap();
+			statuses.push(response.status);
+		}
+	}
+
+	assert_eq!(
+		statuses,
+		[
+			ResponseStatus::Success,
+			ResponseStatus::Success,
+			ResponseStatus::Success,
+			ResponseStatus::RouteNotAllowed,
+		]
+	);
+	allowed.assert();
+	denied.assert();
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn echoes_request() -> Result<()> {
+	let event = "ECHO_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let mut client = get_client();
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let mock = mock("POST", "/api/v6/channels/1/messages")
+		.expect(0)
+		.create();
+	let payload = SerializableHttpRequest {
+		method: "POST".into(),
+		path: "/channels/1/messages".into(),
+		query: Some(
+			vec![("wait".to_string(), "true".to_string())]
+				.into_iter()
+				.collect(),
+		),
+		body: Some(RequestBody::Json(serde_json::json!({ "content": "hello" }))),
+		headers: vec![("X-Audit-Log-Reason".to_string(), "test".to_string())]
+			.into_iter()
+			.collect(),
+		echo: true,
+		..Default::default()
+	};
+
+	let mut responses = Vec::new();
+	for echo in [false, true] {
+		client.echo = echo;
+		let rpc = broker.call(event, &payload, None).await?;
+		let message = timeout(Duration::from_secs(5), consumer.try_next())
+			.await??
+			.expect("message");
+		client.handle_message(message).await?;
+		responses.push(
+			timeout(
+				Duration::from_secs(5),
+				rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+			)
+			.await??
+			.unwrap(),
+		);
+	}
+
+	let response = responses.pop().unwrap();
+	assert_eq!(
+		responses.pop().unwrap().status,
+		ResponseStatus::InvalidRequestFormat
+	);
+	assert_eq!(response.status, ResponseStatus::Success);
+	let echo = match response.body {
+		RequestResponseBody::Ok(echo) => echo,
+		RequestResponseBody::Err(e) => panic!("echo failed: {}", e),
+	};
+	assert_eq!(
+		echo.url,
+		format!(
+			"http://{}/api/v6/channels/1/messages?wait=true",
+			mockito::server_address()
+		)
+	);
+	assert_eq!(
+		serde_json::from_slice::<SerializableHttpRequest>(&echo.body)?,
+		payload
+	);
+	mock.assert();
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn streams_body() -> Result<()> {
+	let config = Config::default().with_env();
+	let pool = redust::pool::Pool::builder(redust::pool::Manager::new(config.redis.url.clone()))
+		.build()
+		.expect("pool should be built");
+
+	let key = "STREAM_TEST_BODY";
+	let mut conn = pool.get().await?;
+	conn.cmd(["DEL", key]).await?;
+	conn.cmd(["RPUSH", key, "hello ", "streamed ", "world"])
+		.await?;
+	drop(conn);
+
+	let mut client = get_client();
+	client.body_store = Some(BodyStore::new(pool));
+
+	let mock = mock("POST", "/api/v6/channels/1/messages")
+		.match_body("hello streamed world")
+		.create();
+
+	let payload = SerializableHttpRequest {
+		method: "POST".into(),
+		path: "/channels/1/messages".into(),
+		body_key: Some(key.into()),
+		..Default::default()
+	};
+	client.request(&payload).await?;
+	mock.assert();
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn skips_redelivered_message() -> Result<()> {
+	let event = "DEDUP_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+
+	let mut client = get_client();
+	client.dedup = Some(Dedup::new(
+		Pool::builder(Manager::new(config.redis.url.clone()))
+			.build()
+			.expect("pool should be built"),
+		Duration::from_secs(60),
+	));
+
+	let mock = mock("POST", "/api/v6/channels/2/messages")
+		.with_body("sent")
+		.expect(1)
+		.create();
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let payload = SerializableHttpRequest {
+		method: "POST".into(),
+		path: "/channels/2/messages".into(),
+		..Default::default()
+	};
+	let rpc = broker.call(event, &payload, None).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	let redelivered = rustacles_brokers::redis::message::Message {
+		id: message.id.clone(),
+		event: message.event.clone(),
+		data: message.data.clone(),
+		timeout_at: message.timeout_at,
+		broker: message.broker.clone(),
+	};
+	client.handle_message(message).await?;
+	client.handle_message(redelivered).await?;
+	mock.assert();
+
+	let response = timeout(
+		Duration::from_secs(5),
+		rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+	)
+	.await??
+	.unwrap();
+	assert_eq!(response.status, ResponseStatus::Success);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn publishes_unreplied_response() -> Result<()> {
+	let event = "UNREPLIED_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer =
+		broker.consume::<UnrepliedResponse<RequestResponse<SerializableHttpResponse>>>(events);
+
+	let unreplied = Unreplied::Publish {
+		broker: get_broker(&config),
+		event: event.to_string(),
+	};
+	let response = RequestResponse::from(Rejection::new(ResponseStatus::Unknown, "failed"));
+	unreplied.handle("1-0", &response, "no reply-to").await;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	assert_eq!(
+		message.data,
+		Some(UnrepliedResponse {
+			id: "1-0".to_string(),
+			response,
 		})
 	);
 
 	Ok(())
 }
+
+#[test(tokio::test)]
+async fn backs_off_headerless_429() -> Result<()> {
+	let mut client = get_client();
+	client.backoff = Some(Arc::new(Backoff::new(
+		Duration::from_millis(200),
+		Duration::from_millis(500),
+	)));
+
+	let mock = mock("GET", "/api/v6/channels/5678/invites")
+		.with_status(429)
+		.expect(4)
+		.create();
+
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/channels/5678/invites".into(),
+		debug: true,
+		..Default::default()
+	};
+
+	let mut waits = vec![];
+	for _ in 0..4 {
+		let res = client.request(&payload).await?;
+		assert_eq!(res.status, 429);
+		waits.push(res.debug.expect("debug info").info.resets_in);
+	}
+	mock.assert();
+
+	assert_eq!(waits, vec![Some(200), Some(400), Some(500), Some(500)]);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn returns_bucket() -> Result<()> {
+	let client = get_client();
+	let mock = mock("GET", "/api/v6/guilds/1234/roles")
+		.with_body("[]")
+		.create();
+
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/guilds/1234/roles".into(),
+		..Default::default()
+	};
+	let response = client.request(&payload).await?;
+	mock.assert();
+
+	assert_eq!(
+		response.bucket,
+		Some(make_route(&Method::GET, "/guilds/1234/roles")?)
+	);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn buckets_by_api_version() -> Result<()> {
+	let mut client = get_client();
+	let mocks = [9, 10].map(|version| {
+		mock(
+			"GET",
+			format!("/api/v{}/guilds/1234/roles", version).as_str(),
+		)
+		.with_body("[]")
+		.expect(2)
+		.create()
+	});
+
+	let request = |version| SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/guilds/1234/roles".into(),
+		api_version: Some(version),
+		..Default::default()
+	};
+	let route = make_route(&Method::GET, "/guilds/1234/roles")?;
+
+	// buckets are shared between versions by default
+	for version in [9, 10] {
+		assert_eq!(
+			client.request(&request(version)).await?.bucket,
+			Some(route.clone())
+		);
+	}
+
+	client.version_buckets = true;
+	for version in [9, 10] {
+		assert_eq!(
+			client.request(&request(version)).await?.bucket,
+			Some(format!("v{}:{}", version, route))
+		);
+	}
+	for mock in mocks {
+		mock.assert();
+	}
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn checks_response_json() -> Result<()> {
+	let mut client = get_client();
+	client.check_response_json = true;
+	let json = mock("GET", "/api/v6/channels/1234/pins")
+		.with_header("content-type", "application/json")
+		.with_body("[]")
+		.expect(2)
+		.create();
+	let text = mock("GET", "/api/v6/channels/1234/invites")
+		.with_header("content-type", "text/plain")
+		.with_body("[]")
+		.create();
+
+	let request = |path: &str| SerializableHttpRequest {
+		method: "GET".into(),
+		path: path.into(),
+		..Default::default()
+	};
+	assert!(client.request(&request("/channels/1234/pins")).await?.json);
+	assert!(
+		!client
+			.request(&request("/channels/1234/invites"))
+			.await?
+			.json
+	);
+
+	client.check_response_json = false;
+	assert!(!client.request(&request("/channels/1234/pins")).await?.json);
+	json.assert();
+	text.assert();
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn pauses_bucket() -> Result<()> {
+	let client = Arc::new(get_client());
+	let messages = mock("POST", "/api/v6/channels/1234/messages")
+		.with_body("{}")
+		.create();
+	let pins = mock("GET", "/api/v6/channels/1234/pins")
+		.with_body("[]")
+		.create();
+
+	client.pause.pause("/channels/*/messages");
+	let paused = spawn({
+		let client = Arc::clone(&client);
+		async move {
+			let payload = SerializableHttpRequest {
+				method: "POST".into(),
+				path: "/channels/1234/messages".into(),
+				..Default::default()
+			};
+			client.request(&payload).await
+		}
+	});
+
+	// other buckets aren't paused
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/channels/1234/pins".into(),
+		..Default::default()
+	};
+	timeout(Duration::from_millis(500), client.request(&payload)).await??;
+	pins.assert();
+	assert!(!paused.is_finished());
+
+	client.pause.resume("/channels/*/messages");
+	timeout(Duration::from_millis(500), paused).await???;
+	messages.assert();
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn buckets_by_reported_hash() -> Result<()> {
+	let mut client = get_client();
+	client.bucket_hashes = Some(Default::default());
+	let mock = mock(
+		"GET",
+		mockito::Matcher::Regex("^/api/v6/channels/1234/(pins|invites)$".into()),
+	)
+	.with_header("x-ratelimit-bucket", "abcd")
+	.with_body("[]")
+	.expect(3)
+	.create();
+
+	let request = |path: &str| SerializableHttpRequest {
+		method: "GET".into(),
+		path: path.into(),
+		..Default::default()
+	};
+
+	// the hash isn't known until the first response reports it
+	let pins = request("/channels/1234/pins");
+	assert_eq!(
+		client.request(&pins).await?.bucket,
+		Some(make_route(&Method::GET, "/channels/1234/pins")?)
+	);
+	assert_eq!(
+		client.request(&pins).await?.bucket,
+		Some("hash:abcd".into())
+	);
+
+	// routes which share a hash share a bucket once each has reported it
+	let invites = request("/channels/1234/invites");
+	client.request(&invites).await?;
+	assert_eq!(
+		client
+			.bucket_hashes
+			.as_ref()
+			.unwrap()
+			.bucket(make_route(&Method::GET, "/channels/1234/invites")?),
+		"hash:abcd"
+	);
+	mock.assert();
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn imports_bucket_map() -> Result<()> {
+	let config = Config::default().with_env();
+	let bucket_map = BucketMap::new(
+		Pool::builder(Manager::new(config.redis.url.clone()))
+			.build()
+			.expect("pool should be built"),
+	);
+
+	let mut client = get_client();
+	client.bucket_hashes = Some(Default::default());
+	let learned = mock("GET", "/api/v6/channels/1234/webhooks")
+		.with_header("x-ratelimit-bucket", "efgh")
+		.with_body("[]")
+		.create();
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/channels/1234/webhooks".into(),
+		..Default::default()
+	};
+	client.request(&payload).await?;
+	learned.assert();
+	bucket_map
+		.export_bucket_map(client.bucket_hashes.as_ref().unwrap())
+		.await?;
+
+	// a new proxy uses the hash from its first request
+	let mut client = get_client();
+	client.bucket_hashes = Some(Default::default());
+	bucket_map
+		.import_bucket_map(client.bucket_hashes.as_ref().unwrap())
+		.await?;
+	let primed = mock("GET", "/api/v6/channels/1234/webhooks")
+		.with_body("[]")
+		.create();
+	assert_eq!(
+		client.request(&payload).await?.bucket,
+		Some("hash:efgh".into())
+	);
+	primed.assert();
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn returns_ratelimit_debug() -> Result<()> {
+	let client = get_client();
+	let mock = mock("GET", "/api/v6/channels/1234/pins")
+		.with_header("x-ratelimit-limit", "5")
+		.with_header("x-ratelimit-reset-after", "1.5")
+		.with_body("[]")
+		.expect(2)
+		.create();
+
+	let mut payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/channels/1234/pins".into(),
+		..Default::default()
+	};
+	assert_eq!(client.request(&payload).await?.debug, None);
+
+	payload.debug = true;
+	let debug = client.request(&payload).await?.debug.expect("debug info");
+	mock.assert();
+
+	assert_eq!(
+		debug.bucket,
+		make_route(&Method::GET, "/channels/1234/pins")?
+	);
+	assert_eq!(
+		debug.info,
+		RatelimitInfo {
+			limit: Some(5),
+			resets_in: Some(1500),
+			..Default::default()
+		}
+	);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn holds_global_limit() -> Result<()> {
+	let client = get_client();
+	let mock = mock("GET", "/api/v6/channels/1234/invites")
+		.with_status(429)
+		.with_header("x-ratelimit-global", "true")
+		.with_header("x-ratelimit-scope", "global")
+		.with_header("retry-after", "1")
+		.with_body(r#"{"message":"You are being rate limited.","retry_after":1,"global":true}"#)
+		.create();
+
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/channels/1234/invites".into(),
+		..Default::default()
+	};
+	let start = Instant::now();
+	assert_eq!(client.request(&payload).await?.status, 429);
+	mock.assert();
+
+	// the retry applies to every request, rather than just the route's bucket
+	let bucket = make_route(&Method::GET, "/channels/1234/invites")?;
+	timeout(Duration::from_millis(50), client.ratelimiter.claim(bucket)).await??;
+	timeout(
+		Duration::from_millis(1100),
+		client.ratelimiter.claim_global(),
+	)
+	.await??;
+	assert!(start.elapsed() >= Duration::from_secs(1));
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn requeues_transient_failure() -> Result<()> {
+	let event = "REQUEUE_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+
+	let delay = Duration::from_millis(500);
+	let mut client = get_client();
+	client.requeue = Some(Requeue {
+		broker: get_broker(&config),
+		event: event.to_string(),
+		delay,
+		max_redeliveries: 3,
+		poison_event: None,
+	});
+
+	let mock = mock("GET", "/api/v6/flaky").with_status(503).create();
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/flaky".into(),
+		..Default::default()
+	};
+	broker.publish(event, &payload).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("original message");
+	let start = Instant::now();
+	client.handle_message(message).await?;
+	mock.assert();
+
+	let requeued = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("requeued message");
+	assert!(start.elapsed() >= delay);
+	assert_eq!(
+		requeued.data,
+		Some(SerializableHttpRequest {
+			redeliveries: 1,
+			..payload
+		})
+	);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn skips_reply() -> Result<()> {
+	let event = "NO_REPLY_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let client = get_client();
+
+	let mock = mock("POST", "/api/v6/channels/1/typing")
+		.with_status(204)
+		.create();
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let payload = SerializableHttpRequest {
+		method: "POST".into(),
+		path: "/channels/1/typing".into(),
+		no_reply: true,
+		..Default::default()
+	};
+	let rpc = broker.call(event, &payload, None).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	client.handle_message(message).await?;
+	mock.assert();
+
+	let response = timeout(
+		Duration::from_secs(1),
+		rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+	)
+	.await;
+	assert!(response.is_err());
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn poisons_after_max_redeliveries() -> Result<()> {
+	let event = "POISON_TEST";
+	let poison_event = "POISON_TEST_SINK";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+
+	let mut client = get_client();
+	client.requeue = Some(Requeue {
+		broker: get_broker(&config),
+		event: event.to_string(),
+		delay: Duration::from_millis(100),
+		max_redeliveries: 2,
+		poison_event: Some(poison_event.to_string()),
+	});
+
+	let mock = mock("GET", "/api/v6/always-flaky")
+		.with_status(503)
+		.create();
+
+	let events = vec![Bytes::from(event), Bytes::from(poison_event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/always-flaky".into(),
+		redeliveries: 2,
+		..Default::default()
+	};
+	let rpc = broker.call(event, &payload, None).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	client.handle_message(message).await?;
+	mock.assert();
+
+	let response = timeout(
+		Duration::from_secs(5),
+		rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+	)
+	.await??
+	.unwrap();
+	assert_eq!(response.status, ResponseStatus::PoisonMessage);
+
+	let poisoned = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("poisoned message");
+	assert_eq!(poisoned.data, Some(payload));
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn sends_cdn_requests_with_cdn_client() -> Result<()> {
+	let mut client = get_client();
+	client.http.cdn = reqwest::Client::builder()
+		.user_agent("proxy-cdn-test")
+		.build()?;
+
+	let mock = mock("GET", "/attachments/1234/5678/image.png")
+		.match_header("user-agent", "proxy-cdn-test")
+		.with_body("image")
+		.create();
+
+	let response = client
+		.request(&SerializableHttpRequest {
+			method: "GET".into(),
+			path: "/attachments/1234/5678/image.png".into(),
+			mode: RequestMode::Cdn,
+			..Default::default()
+		})
+		.await?;
+	mock.assert();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, "image");
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn streams_upload_to_allowed_host() -> Result<()> {
+	let config = Config::default().with_env();
+	let pool = redust::pool::Pool::builder(redust::pool::Manager::new(config.redis.url.clone()))
+		.build()
+		.expect("pool should be built");
+
+	// 4 MiB in 64 KiB chunks
+	let chunk = "x".repeat(64 * 1024);
+	let key = "UPLOAD_TEST_BODY";
+	let mut conn = pool.get().await?;
+	conn.cmd(["DEL", key]).await?;
+	for _ in 0..64 {
+		conn.cmd(["RPUSH", key, chunk.as_str()]).await?;
+	}
+	drop(conn);
+
+	let mut client = get_client();
+	client.body_store = Some(BodyStore::new(pool));
+	client.upload_hosts = vec![mockito::server_address().to_string()].into();
+
+	let mock = mock("PUT", "/uploads/attachment.bin")
+		.match_query(mockito::Matcher::UrlEncoded(
+			"upload_id".into(),
+			"abc".into(),
+		))
+		.match_body(chunk.repeat(64).as_str())
+		.create();
+
+	let response = client
+		.request(&SerializableHttpRequest {
+			method: "PUT".into(),
+			path: format!(
+				"http://{}/uploads/attachment.bin?upload_id=abc",
+				mockito::server_address()
+			),
+			body_key: Some(key.into()),
+			mode: RequestMode::Upload,
+			..Default::default()
+		})
+		.await?;
+	mock.assert();
+	assert_eq!(response.status, 200);
+
+	let rejected = client
+		.request(&SerializableHttpRequest {
+			method: "PUT".into(),
+			path: "http://uploads.example.com/uploads/attachment.bin".into(),
+			body: Some("hi".into()),
+			mode: RequestMode::Upload,
+			..Default::default()
+		})
+		.await
+		.unwrap_err();
+	assert_eq!(
+		Rejection::from(rejected).status,
+		ResponseStatus::InvalidPath
+	);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn consumes_profiles_independently() -> Result<()> {
+	let config: Config = toml::from_str(
+		r#"
+		[[profile]]
+		name = "first"
+		broker.event = "PROFILE_FIRST"
+		discord.token = "first"
+
+		[[profile]]
+		name = "second"
+		broker.event = "PROFILE_SECOND"
+		discord.token = "second"
+		"#,
+	)?;
+	let redis = Config::default().with_env().redis;
+
+	let mut mocks = Vec::new();
+	for mut profile in config.profiles {
+		profile.config.redis = redis.clone();
+		mocks.push(
+			mock("GET", "/api/v6/users/@me")
+				.match_header("authorization", format!("Bot {}", profile.name).as_str())
+				.with_body(&profile.name)
+				.create(),
+		);
+
+		let mut client = get_client();
+		client.profile = profile.name;
+		client.token = profile.config.discord.token.clone();
+		let broker = get_broker(&profile.config);
+		let events = vec![Bytes::from(profile.config.broker.event.clone())];
+		broker.ensure_events(events.iter()).await?;
+		spawn(async move { client.consume_stream(broker.consume(events)).await });
+	}
+
+	let rpc_broker = get_broker(&Config::default().with_env());
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/users/@me".into(),
+		..Default::default()
+	};
+	for (event, name) in [("PROFILE_FIRST", "first"), ("PROFILE_SECOND", "second")] {
+		let rpc = timeout(
+			Duration::from_secs(5),
+			rpc_broker.call(event, &payload, None),
+		)
+		.await??;
+		let response = rpc
+			.response::<RequestResponse<SerializableHttpResponse>>()
+			.await?
+			.unwrap();
+
+		match response.body {
+			RequestResponseBody::Ok(res) => assert_eq!(res.body, name),
+			body => panic!("{} wasn't handled: {:?}", event, body),
+		}
+	}
+	for mock in mocks {
+		mock.assert();
+	}
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn requeues_unfinished_at_shutdown() -> Result<()> {
+	use tokio::{net::TcpListener, sync::oneshot};
+
+	let event = "SHUTDOWN_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+
+	// Discord never replies, so messages are still being handled when consumption is shut down
+	let listener = TcpListener::bind("127.0.0.1:0").await?;
+	let mut client = get_client();
+	client.api_base = listener.local_addr()?.to_string();
+	client.shutdown_grace = Some(Duration::from_millis(100));
+	let client = Arc::new(client);
+	spawn(async move {
+		let mut conns = Vec::new();
+		while let Ok((conn, _)) = listener.accept().await {
+			conns.push(conn);
+		}
+	});
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let (shut_down, shutdown) = oneshot::channel();
+	let consumer = spawn({
+		let client = Arc::clone(&client);
+		let stream = broker.consume(events.clone());
+		async move {
+			client
+				.consume_stream_until(stream, async {
+					let _ = shutdown.await;
+				})
+				.await
+		}
+	});
+
+	let payloads = ["/first", "/second"].map(|path| SerializableHttpRequest {
+		method: "GET".into(),
+		path: path.into(),
+		..Default::default()
+	});
+	for payload in &payloads {
+		broker.publish(event, payload).await?;
+	}
+	timeout(Duration::from_secs(5), async {
+		while client.quiesce.status().handling < payloads.len() {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+	})
+	.await?;
+
+	shut_down.send(()).unwrap();
+	timeout(Duration::from_secs(5), consumer).await???;
+	assert_eq!(client.quiesce.status().handling, 0);
+
+	let mut requeued = broker.consume::<SerializableHttpRequest>(events);
+	let mut paths = Vec::new();
+	for _ in &payloads {
+		let message = timeout(Duration::from_secs(5), requeued.try_next())
+			.await??
+			.expect("requeued message");
+		let data = message.data.expect("requeued data");
+		assert_eq!(data.redeliveries, 1);
+		paths.push(data.path);
+	}
+	paths.sort();
+	assert_eq!(paths, ["/first", "/second"]);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn handles_batch() -> Result<()> {
+	let client = get_client();
+	let mocks = ["first", "second", "third"].map(|name| {
+		mock("GET", format!("/api/v6/batch/{}", name).as_str())
+			.with_body(name)
+			.create()
+	});
+
+	let batch = BatchRequest {
+		requests: ["first", "second", "third"]
+			.iter()
+			.map(|name| SerializableHttpRequest {
+				method: "GET".into(),
+				path: format!("/batch/{}", name),
+				..Default::default()
+			})
+			.collect(),
+		stop_on_error: false,
+		cancel_id: None,
+	};
+	let responses = client.request_batch(&batch, None).await;
+	for mock in mocks {
+		mock.assert();
+	}
+
+	let bodies = responses
+		.into_iter()
+		.map(|response| match response.body {
+			RequestResponseBody::Ok(res) => res.body,
+			body => panic!("batch item failed: {:?}", body),
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(bodies, ["first", "second", "third"]);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn stops_batch_on_error() -> Result<()> {
+	let client = get_client();
+	let failing = mock("GET", "/api/v6/batch/failing")
+		.with_status(404)
+		.create();
+	let skipped = mock("GET", "/api/v6/batch/skipped").expect(0).create();
+
+	let batch = BatchRequest {
+		requests: ["failing", "skipped"]
+			.iter()
+			.map(|name| SerializableHttpRequest {
+				method: "GET".into(),
+				path: format!("/batch/{}", name),
+				..Default::default()
+			})
+			.collect(),
+		stop_on_error: true,
+		cancel_id: None,
+	};
+	let responses = client.request_batch(&batch, None).await;
+	failing.assert();
+	skipped.assert();
+
+	assert_eq!(responses.len(), 2);
+	assert_eq!(responses[0].status, ResponseStatus::Success);
+	assert_eq!(responses[1].status, ResponseStatus::Skipped);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn rejects_large_batch() -> Result<()> {
+	let mut client = get_client();
+	client.batch.max_size = 2;
+	let unsent = mock("GET", "/api/v6/batch/large").expect(0).create();
+
+	let batch = BatchRequest {
+		requests: vec![
+			SerializableHttpRequest {
+				method: "GET".into(),
+				path: "/batch/large".into(),
+				..Default::default()
+			};
+			3
+		],
+		stop_on_error: false,
+		cancel_id: None,
+	};
+	let responses = client.request_batch(&batch, None).await;
+	unsent.assert();
+
+	assert_eq!(responses.len(), 3);
+	for response in responses {
+		assert_eq!(response.status, ResponseStatus::BatchTooLarge);
+	}
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn cancels_batch() -> Result<()> {
+	use tokio::net::TcpListener;
+
+	// Discord never replies, so only echoed requests finish before the batch is cancelled
+	let listener = TcpListener::bind("127.0.0.1:0").await?;
+	let mut client = get_client();
+	client.api_base = listener.local_addr()?.to_string();
+	client.echo = true;
+	spawn(async move {
+		let mut conns = Vec::new();
+		while let Ok((conn, _)) = listener.accept().await {
+			conns.push(conn);
+		}
+	});
+
+	let request = |path: &str, echo| SerializableHttpRequest {
+		method: "GET".into(),
+		path: path.into(),
+		echo,
+		..Default::default()
+	};
+	let batch = BatchRequest {
+		requests: vec![
+			request("/first", true),
+			request("/second", false),
+			request("/third", false),
+		],
+		cancel_id: Some("slow".into()),
+		..Default::default()
+	};
+
+	let cancel = client.cancellations.register("slow");
+	let cancelling = async {
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(client.cancellations.cancel("slow"));
+	};
+	let (responses, _) = timeout(
+		Duration::from_secs(5),
+		futures::future::join(client.request_batch(&batch, Some(&cancel)), cancelling),
+	)
+	.await?;
+
+	let statuses = responses
+		.iter()
+		.map(|response| response.status)
+		.collect::<Vec<_>>();
+	assert_eq!(
+		statuses,
+		[
+			ResponseStatus::Success,
+			ResponseStatus::Cancelled,
+			ResponseStatus::Cancelled
+		]
+	);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn cancels_request() -> Result<()> {
+	use tokio::{net::TcpListener, sync::oneshot};
+
+	let event = "CANCEL_REQUEST_TEST";
+	let cancel_event = "CANCEL_REQUEST_TEST_CANCEL";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+
+	// Discord never replies, so the request is still being sent when it's cancelled
+	let listener = TcpListener::bind("127.0.0.1:0").await?;
+	let mut client = get_client();
+	client.api_base = listener.local_addr()?.to_string();
+	let client = Arc::new(client);
+	let (connected, sending) = oneshot::channel();
+	spawn(async move {
+		let mut connected = Some(connected);
+		let mut conns = Vec::new();
+		while let Ok((conn, _)) = listener.accept().await {
+			conns.push(conn);
+			if let Some(connected) = connected.take() {
+				let _ = connected.send(());
+			}
+		}
+	});
+
+	let events = vec![Bytes::from(event)];
+	let cancel_events = vec![Bytes::from(cancel_event)];
+	broker.ensure_events(events.iter()).await?;
+	broker.ensure_events(cancel_events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/slow".into(),
+		cancel_id: Some("slow".into()),
+		..Default::default()
+	};
+	let rpc = broker.call(event, &payload, None).await?;
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	let handler = spawn({
+		let client = Arc::clone(&client);
+		async move { client.handle_message(message).await }
+	});
+	timeout(Duration::from_secs(5), sending).await??;
+
+	let (shut_down, shutdown) = oneshot::channel();
+	let cancellations = spawn({
+		let client = Arc::clone(&client);
+		let stream = broker.consume::<String>(cancel_events);
+		async move {
+			client
+				.consume_cancellations_until(stream, async {
+					let _ = shutdown.await;
+				})
+				.await
+		}
+	});
+	broker.publish(cancel_event, &"slow".to_string()).await?;
+
+	timeout(Duration::from_secs(5), handler).await???;
+	shut_down.send(()).unwrap();
+	timeout(Duration::from_secs(5), cancellations).await???;
+
+	let response = timeout(
+		Duration::from_secs(1),
+		rpc.response::<RequestResponse<SerializableHttpResponse>>(),
+	)
+	.await;
+	assert!(response.is_err());
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn cancels_on_every_instance() -> Result<()> {
+	use tokio::sync::oneshot;
+
+	let event = "CANCEL_EVERY_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let events = vec![Bytes::from(event)];
+
+	// only one of the proxies is handling the message, but the cancellation reaches both
+	let mut consumers = Vec::new();
+	for instance in ["cancel_a", "cancel_b"] {
+		let mut config = config.clone();
+		config.broker.instance = Some(instance.into());
+		let control = config.new_control_broker(None);
+		control.ensure_events(events.iter()).await?;
+
+		let client = Arc::new(get_client());
+		let cancellable = client.cancellations.register("every");
+		let (shut_down, shutdown) = oneshot::channel::<()>();
+		let consumer = spawn({
+			let client = Arc::clone(&client);
+			let stream = control.consume::<String>(events.clone());
+			async move {
+				client
+					.consume_cancellations_until(stream, async {
+						let _ = shutdown.await;
+					})
+					.await
+			}
+		});
+		consumers.push((cancellable, shut_down, consumer));
+	}
+
+	broker.publish(event, &"every".to_string()).await?;
+
+	for (cancellable, shut_down, consumer) in consumers {
+		timeout(Duration::from_secs(5), cancellable.cancelled()).await?;
+		shut_down.send(()).unwrap();
+		timeout(Duration::from_secs(5), consumer).await???;
+	}
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn resets_bucket() -> Result<()> {
+	use tokio::sync::oneshot;
+
+	let event = "RESET_BUCKET_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let client = Arc::new(get_client());
+
+	client.ratelimiter.claim("reset_test".into()).await?;
+	assert!(timeout(
+		Duration::from_millis(100),
+		client.ratelimiter.claim("reset_test".into())
+	)
+	.await
+	.is_err());
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let (shut_down, shutdown) = oneshot::channel();
+	let resets = spawn({
+		let client = Arc::clone(&client);
+		let stream = broker.consume::<String>(events);
+		async move {
+			client
+				.consume_resets_until(stream, async {
+					let _ = shutdown.await;
+				})
+				.await
+		}
+	});
+	broker.publish(event, &"reset_test".to_string()).await?;
+
+	timeout(
+		Duration::from_secs(5),
+		client.ratelimiter.claim("reset_test".into()),
+	)
+	.await??;
+	shut_down.send(()).unwrap();
+	timeout(Duration::from_secs(5), resets).await???;
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn pauses_every_instance() -> Result<()> {
+	use tokio::{sync::oneshot, time::sleep};
+
+	let event = "PAUSE_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let events = vec![Bytes::from(event)];
+
+	// each proxy consumes in its own control group, rather than sharing one
+	let mut consumers = Vec::new();
+	for instance in ["pause_a", "pause_b"] {
+		let mut config = config.clone();
+		config.broker.instance = Some(instance.into());
+		let control = config.new_control_broker(None);
+		control.ensure_events(events.iter()).await?;
+
+		let client = Arc::new(get_client());
+		let (shut_down, shutdown) = oneshot::channel::<()>();
+		let consumer = spawn({
+			let client = Arc::clone(&client);
+			let stream = control.consume::<PauseControl>(events.clone());
+			async move {
+				client
+					.consume_pauses_until(stream, async {
+						let _ = shutdown.await;
+					})
+					.await
+			}
+		});
+		consumers.push((client, shut_down, consumer));
+	}
+
+	broker
+		.publish(event, &PauseControl::Pause("/channels/*/messages".into()))
+		.await?;
+
+	for (client, shut_down, consumer) in consumers {
+		timeout(Duration::from_secs(5), async {
+			while !client.pause.is_paused("/channels/:id/messages?post") {
+				sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await?;
+		shut_down.send(()).unwrap();
+		timeout(Duration::from_secs(5), consumer).await???;
+	}
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn resets_on_every_instance() -> Result<()> {
+	use tokio::sync::oneshot;
+
+	let event = "RESET_EVERY_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let events = vec![Bytes::from(event)];
+
+	// each proxy has its own local ratelimiter, so each has to reset its own bucket
+	let mut consumers = Vec::new();
+	for instance in ["reset_a", "reset_b"] {
+		let mut config = config.clone();
+		config.broker.instance = Some(instance.into());
+		let control = config.new_control_broker(None);
+		control.ensure_events(events.iter()).await?;
+
+		let client = Arc::new(get_client());
+		client.ratelimiter.claim("reset_every".into()).await?;
+		let (shut_down, shutdown) = oneshot::channel::<()>();
+		let consumer = spawn({
+			let client = Arc::clone(&client);
+			let stream = control.consume::<String>(events.clone());
+			async move {
+				client
+					.consume_resets_until(stream, async {
+						let _ = shutdown.await;
+					})
+					.await
+			}
+		});
+		consumers.push((client, shut_down, consumer));
+	}
+
+	broker.publish(event, &"reset_every".to_string()).await?;
+
+	for (client, shut_down, consumer) in consumers {
+		timeout(
+			Duration::from_secs(5),
+			client.ratelimiter.claim("reset_every".into()),
+		)
+		.await??;
+		shut_down.send(()).unwrap();
+		timeout(Duration::from_secs(5), consumer).await???;
+	}
+
+	Ok(())
+}
+
+#[cfg(feature = "metrics")]
+#[test(tokio::test)]
+async fn records_ack_delay() -> Result<()> {
+	use spectacles_proxy::metrics::ACK_DELAY;
+	use tokio::time::{sleep, Instant};
+
+	let event = "ACK_DELAY_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+	let mut client = get_client();
+	client.profile = "ack_delay_test".into();
+	client.echo = true;
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/users/@me".into(),
+		echo: true,
+		no_reply: true,
+		..Default::default()
+	};
+	broker.publish(event, &payload).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("message");
+	let received = Instant::now();
+	sleep(Duration::from_millis(200)).await;
+	client.handle_message_received(message, received).await?;
+
+	let delay = ACK_DELAY.with_label_values(&["ack_delay_test"]);
+	assert_eq!(delay.get_sample_count(), 1);
+	let sum = delay.get_sample_sum();
+	assert!(sum >= 0.2, "ack delay is {}s", sum);
+	assert!(
+		sum <= received.elapsed().as_secs_f64(),
+		"ack delay is {}s",
+		sum
+	);
+
+	Ok(())
+}
+
+#[cfg(feature = "metrics")]
+#[test(tokio::test)]
+async fn runs_self_test() -> Result<()> {
+	use spectacles_proxy::{
+		metrics::{SELF_TEST_LAST_SUCCESS, SELF_TEST_SUCCESS},
+		runtime::self_test::SelfTest,
+	};
+
+	let mut client = get_client();
+	client.profile = "self_test_test".into();
+	let me = mock("GET", "/api/v6/users/@me")
+		.with_body(r#"{"id":"1234"}"#)
+		.create();
+	let revoked = mock("GET", "/api/v6/self-test/revoked")
+		.with_status(401)
+		.create();
+
+	let self_test = SelfTest::new(Duration::from_secs(60), "/users/@me".into());
+	assert!(self_test.check(&client).await);
+	me.assert();
+	assert_eq!(
+		SELF_TEST_SUCCESS
+			.with_label_values(&["self_test_test"])
+			.get(),
+		1
+	);
+	let last_success = SELF_TEST_LAST_SUCCESS
+		.with_label_values(&["self_test_test"])
+		.get();
+	assert!(last_success > 0.);
+
+	let self_test = SelfTest::new(Duration::from_secs(60), "/self-test/revoked".into());
+	assert!(!self_test.check(&client).await);
+	revoked.assert();
+	assert_eq!(
+		SELF_TEST_SUCCESS
+			.with_label_values(&["self_test_test"])
+			.get(),
+		0
+	);
+	assert_eq!(
+		SELF_TEST_LAST_SUCCESS
+			.with_label_values(&["self_test_test"])
+			.get(),
+		last_success
+	);
+
+	Ok(())
+}
+
+#[test(tokio::test)]
+async fn retries_http_timeout_within_deadline() -> Result<()> {
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::TcpListener,
+		sync::oneshot,
+	};
+
+	let event = "HTTP_TIMEOUT_TEST";
+	let config = Config::default().with_env();
+	let broker = get_broker(&config);
+
+	// Discord doesn't respond to the first attempt in time, but does to the second
+	let listener = TcpListener::bind("127.0.0.1:0").await?;
+	let (served, responded) = oneshot::channel();
+	let mut client = get_client();
+	client.api_base = listener.local_addr()?.to_string();
+	client.requeue = Some(Requeue {
+		broker: get_broker(&config),
+		event: event.to_string(),
+		delay: Duration::from_millis(100),
+		max_redeliveries: 3,
+		poison_event: None,
+	});
+	spawn(async move {
+		let (hung, _) = listener.accept().await.unwrap();
+		let (mut conn, _) = listener.accept().await.unwrap();
+		let mut buf = [0; 1024];
+		let _ = conn.read(&mut buf).await.unwrap();
+		conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}")
+			.await
+			.unwrap();
+		served.send(()).unwrap();
+		drop(hung);
+	});
+
+	let events = vec![Bytes::from(event)];
+	broker.ensure_events(events.iter()).await?;
+	let mut consumer = broker.consume::<SerializableHttpRequest>(events);
+
+	let payload = SerializableHttpRequest {
+		method: "GET".into(),
+		path: "/slow".into(),
+		http_timeout: Some(Duration::from_millis(200)),
+		deadline: Some(SystemTime::now() + Duration::from_secs(10)),
+		..Default::default()
+	};
+	broker.publish(event, &payload).await?;
+
+	let message = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("original message");
+	client.handle_message(message).await?;
+
+	let requeued = timeout(Duration::from_secs(5), consumer.try_next())
+		.await??
+		.expect("requeued message");
+	assert_eq!(
+		requeued.data.as_ref().map(|data| data.redeliveries),
+		Some(1)
+	);
+	client.handle_message(requeued).await?;
+	timeout(Duration::from_secs(5), responded).await??;
+
+	Ok(())
+}
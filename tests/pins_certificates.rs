@@ -0,0 +1,49 @@
+use spectacles_proxy::runtime::config::HttpClientConfig;
+use std::net::SocketAddr;
+use tokio::spawn;
+use warp::Filter;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+fn serve_tls() -> SocketAddr {
+	let (addr, server) = warp::serve(warp::any().map(|| "ok"))
+		.tls()
+		.cert_path(format!("{}/discord.pem", FIXTURES))
+		.key_path(format!("{}/discord.key", FIXTURES))
+		.bind_ephemeral(([127, 0, 0, 1], 0));
+	spawn(server);
+	addr
+}
+
+fn pinned_client(cert: &str) -> reqwest::Client {
+	HttpClientConfig {
+		pinned_certs: vec![format!("{}/{}", FIXTURES, cert)],
+		..Default::default()
+	}
+	.new_client()
+	.expect("pinned client")
+}
+
+#[tokio::test]
+async fn accepts_pinned_cert() {
+	let addr = serve_tls();
+	let res = pinned_client("discord.pem")
+		.get(format!("https://localhost:{}/", addr.port()))
+		.send()
+		.await
+		.expect("request with pinned cert");
+
+	assert_eq!(res.text().await.unwrap(), "ok");
+}
+
+#[tokio::test]
+async fn rejects_unpinned_cert() {
+	let addr = serve_tls();
+	let err = pinned_client("other.pem")
+		.get(format!("https://localhost:{}/", addr.port()))
+		.send()
+		.await
+		.expect_err("request with a different pinned cert");
+
+	assert!(err.is_connect(), "{:?}", err);
+}